@@ -1,4 +1,6 @@
 /// Description of the CLI interface to floki
+use crate::config::RuntimeOverrides;
+use crate::summary::OutputFormat;
 use std::path;
 use structopt::StructOpt;
 
@@ -13,6 +15,10 @@ pub(crate) enum Subcommand {
     #[structopt(name = "pull")]
     Pull {},
 
+    /// Print the image and environment as shell exports, for use with eval
+    #[structopt(name = "env")]
+    Env {},
+
     /// Generate shell completions to stdout.
     #[structopt(name = "completion")]
     Completion {
@@ -27,14 +33,123 @@ pub(crate) enum Subcommand {
 #[structopt(name = "floki", about = "The interactive container launcher.")]
 pub(crate) struct Cli {
     /// Use the specified config instead of searching the tree for a
-    /// "floki.yaml" file.
-    #[structopt(long = "config", short = "c")]
-    pub(crate) config_file: Option<path::PathBuf>,
+    /// "floki.yaml" file. Given more than once, the configs are merged,
+    /// with later configs taking precedence.
+    #[structopt(long = "config", short = "c", number_of_values = 1)]
+    pub(crate) config_files: Vec<path::PathBuf>,
+
+    /// Run this image without a configuration file, rather than searching
+    /// the tree for one
+    #[structopt(long = "image", conflicts_with_all = &["config-files", "profile"])]
+    pub(crate) image: Option<String>,
+
+    /// The shell to use in the image given with --image
+    #[structopt(long = "shell", requires = "image")]
+    pub(crate) shell: Option<String>,
+
+    /// Merge this profile from the configuration's "profiles" over the
+    /// rest of the configuration. The FLOKI_PROFILE environment variable
+    /// selects one for configurations which define it.
+    #[structopt(long = "profile")]
+    pub(crate) profile: Option<String>,
+
+    /// Don't forward the ssh agent, even if the configuration does
+    #[structopt(long = "no-forward-ssh-agent")]
+    pub(crate) no_forward_ssh_agent: bool,
+
+    /// Don't start docker-in-docker, even if the configuration does
+    #[structopt(long = "no-dind")]
+    pub(crate) no_dind: bool,
+
+    /// Don't run as the host user, even if the configuration does
+    #[structopt(long = "no-forward-user")]
+    pub(crate) no_forward_user: bool,
+
+    /// Mount the project at this path in the container, instead of the
+    /// configured mount
+    #[structopt(long = "mount")]
+    pub(crate) mount: Option<path::PathBuf>,
+
+    /// Mount the current directory at this path in the container, and work
+    /// there, instead of mounting the project
+    #[structopt(long = "bind-current-dir-as", conflicts_with = "mount")]
+    pub(crate) bind_current_dir_as: Option<path::PathBuf>,
+
+    /// Kill the container if the session runs for longer than this many
+    /// seconds, instead of the configured session_timeout
+    #[structopt(long = "timeout")]
+    pub(crate) session_timeout: Option<u64>,
+
+    /// Only pass host variables listed by name in forward_env_prefixes into
+    /// the container, and leave out floki's own FLOKI_ variables
+    #[structopt(long = "keep-env-clean")]
+    pub(crate) keep_env_clean: bool,
 
     /// Deprecated, and no longer has any effect.
     #[structopt(long = "local", short = "l", hidden = true)]
     pub(crate) local: bool,
 
+    /// Build or pull the image, then exit without running a container
+    #[structopt(long = "pull-only")]
+    pub(crate) pull_only: bool,
+
+    /// Print where each configuration field was set - which file, host
+    /// override, profile, or command line option - then exit
+    #[structopt(long = "explain")]
+    pub(crate) explain: bool,
+
+    /// Print the fields which differ between the resolved configuration
+    /// and this other configuration file, then exit
+    #[structopt(long = "compare-config")]
+    pub(crate) compare_config: Option<path::PathBuf>,
+
+    /// Print the absolute path of the configuration file floki would use,
+    /// then exit
+    #[structopt(long = "print-config-path")]
+    pub(crate) print_config_path: bool,
+
+    /// Open a shell in the running container with this name, rather than
+    /// starting a new one
+    #[structopt(long = "attach")]
+    pub(crate) attach: Option<String>,
+
+    /// List the volumes floki has created, with their sizes
+    #[structopt(long = "list-volumes")]
+    pub(crate) list_volumes: bool,
+
+    /// Remove the volumes floki has created which aren't shared between all
+    /// configurations
+    #[structopt(long = "prune-volumes")]
+    pub(crate) prune_volumes: bool,
+
+    /// Don't ask for confirmation before removing anything
+    #[structopt(long = "yes", short = "y")]
+    pub(crate) yes: bool,
+
+    /// With pull or --pull-only, print the commands floki would run to get
+    /// the image, rather than running them
+    #[structopt(long = "dry-run")]
+    pub(crate) dry_run: bool,
+
+    /// Report what floki did. With "json", a summary of the image, volumes
+    /// and container exit code is written to stderr at the end of the run.
+    #[structopt(long = "format", default_value = "text", possible_values = &["text", "json"])]
+    pub(crate) format: OutputFormat,
+
+    /// Write progress events - pulls, builds and the container starting
+    /// and exiting - to stderr as JSON lines while floki works
+    #[structopt(long = "events")]
+    pub(crate) events: bool,
+
+    /// Write the JSON summary to this file instead of stderr
+    #[structopt(long = "summary-file")]
+    pub(crate) summary_file: Option<path::PathBuf>,
+
+    /// Only allow host environment variables listed in this file (one
+    /// name per line) to be forwarded into the container
+    #[structopt(long = "env-passthrough-from-file")]
+    pub(crate) env_allowlist: Option<path::PathBuf>,
+
     /// Logging verbosity level
     #[structopt(short = "v", parse(from_occurrences))]
     pub(crate) verbosity: u8,
@@ -42,3 +157,18 @@ pub(crate) struct Cli {
     #[structopt(subcommand)]
     pub(crate) subcommand: Option<Subcommand>,
 }
+
+impl Cli {
+    /// The configuration changes requested for this run
+    pub(crate) fn runtime_overrides(&self) -> RuntimeOverrides {
+        RuntimeOverrides {
+            no_forward_ssh_agent: self.no_forward_ssh_agent,
+            no_dind: self.no_dind,
+            no_forward_user: self.no_forward_user,
+            mount: self.mount.clone(),
+            bind_current_dir_as: self.bind_current_dir_as.clone(),
+            session_timeout: self.session_timeout,
+            keep_env_clean: self.keep_env_clean,
+        }
+    }
+}
@@ -1,16 +1,139 @@
 use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
 use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::path;
 use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    /// The docker context every docker command targets, if one is
+    /// configured. Docker is only ever run from the main thread.
+    static DOCKER_CONTEXT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Target a docker context with every docker command from now on
+pub(crate) fn set_docker_context(context: Option<&str>) -> Result<(), Error> {
+    if context.is_some_and(|context| context.trim().is_empty()) {
+        return Err(FlokiError::InvalidDockerContext {}.into());
+    }
+    DOCKER_CONTEXT.with(|current| *current.borrow_mut() = context.map(str::to_string));
+    Ok(())
+}
+
+/// Start a docker command, targeting the configured context
+pub(crate) fn docker() -> Command {
+    let mut command = Command::new("docker");
+    command.args(context_args());
+    command
+}
+
+/// The arguments which make a docker command target the configured
+/// context, for docker commands which aren't started with docker()
+pub(crate) fn context_args() -> Vec<String> {
+    DOCKER_CONTEXT.with(|context| match context.borrow().as_ref() {
+        Some(context) => vec!["--context".to_string(), context.clone()],
+        None => Vec::new(),
+    })
+}
+
+/// A command to run on the host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandSpec {
+    /// The program to run
+    pub command: String,
+    /// Arguments to pass to the program
+    #[serde(default = "Vec::new")]
+    pub args: Vec<String>,
+    /// Extra environment variables to set for the program
+    #[serde(default = "BTreeMap::new")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl CommandSpec {
+    /// Build a process which runs the command
+    pub fn build(&self) -> Command {
+        let mut command = Command::new(&self.command);
+        command.args(&self.args).envs(&self.env);
+        command
+    }
+
+    /// Run the command to completion. If the output is captured, it is
+    /// only shown if the command fails, as part of the error.
+    pub fn run(&self, capture_output: bool) -> Result<(), FlokiError> {
+        self.run_in(capture_output, None)
+    }
+
+    /// Run the command to completion from the given directory, or from
+    /// floki's working directory if there isn't one
+    pub fn run_in(
+        &self,
+        capture_output: bool,
+        directory: Option<&path::Path>,
+    ) -> Result<(), FlokiError> {
+        debug!("Running command: {:?} in {:?}", self, directory);
+        let mut command = self.build();
+        if let Some(directory) = directory {
+            command.current_dir(directory);
+        }
+        let launch_error = |error| FlokiError::FailedToRunCommand {
+            command: self.command.clone(),
+            error,
+        };
+
+        let (exit_status, output) = if capture_output {
+            let output = command
+                .stdin(Stdio::null())
+                .output()
+                .map_err(launch_error)?;
+            let mut captured = String::from_utf8_lossy(&output.stdout).to_string();
+            captured.push_str(&String::from_utf8_lossy(&output.stderr));
+            (output.status, Some(captured))
+        } else {
+            (command.status().map_err(launch_error)?, None)
+        };
+
+        if exit_status.success() {
+            Ok(())
+        } else {
+            Err(FlokiError::CommandFailed {
+                exit_status: FlokiSubprocessExitStatus {
+                    process_description: self.command.clone(),
+                    exit_status,
+                },
+                output,
+            })
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DockerCommandBuilder {
     name: String,
     volumes: Vec<OsString>,
     environment: Vec<OsString>,
+    /// Values of variables passed to the container by name, set in
+    /// docker's own environment
+    passed_environment: Vec<(OsString, OsString)>,
     switches: Vec<OsString>,
     image: String,
+    keep_on_failure: bool,
+    tty: bool,
+    timeout: Option<Duration>,
+}
+
+/// What should happen to a container once it has exited
+#[derive(Debug, PartialEq)]
+enum Cleanup {
+    /// Docker removes the container itself (it was run with --rm)
+    Automatic,
+    /// The container must be removed explicitly
+    Remove,
+    /// The container is left for debugging
+    Keep,
 }
 
 #[derive(Debug)]
@@ -22,13 +145,120 @@ impl DaemonHandle {
     fn from_builder(builder: DockerCommandBuilder) -> Self {
         DaemonHandle { name: builder.name }
     }
+
+    /// Wait until the container is running - and if it has a healthcheck,
+    /// until it reports healthy
+    pub fn wait_until_ready(&self, timeout: Duration) -> Result<(), Error> {
+        wait_until_ready(&self.name, timeout, READY_POLL_INTERVAL, || {
+            container_state(&self.name)
+        })
+    }
+}
+
+/// How often containers being waited for are checked
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a session with a timeout is checked for having exited
+const SESSION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Decides when a session has run for too long
+#[derive(Debug)]
+struct Watchdog {
+    deadline: Instant,
+}
+
+impl Watchdog {
+    fn new(started: Instant, timeout: Duration) -> Self {
+        Watchdog {
+            deadline: started + timeout,
+        }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+}
+
+/// How far a container has got towards being usable
+#[derive(Debug, PartialEq)]
+enum Readiness {
+    Ready,
+    Waiting,
+    Failed,
+}
+
+/// Decide how ready a container is from its status, followed by its
+/// health status if it has a healthcheck (e.g. "running healthy")
+fn readiness(state: &str) -> Readiness {
+    let mut parts = state.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("running"), None) | (Some("running"), Some("healthy")) => Readiness::Ready,
+        (Some("running"), Some("starting")) | (Some("created"), _) | (Some("restarting"), _) => {
+            Readiness::Waiting
+        }
+        _ => Readiness::Failed,
+    }
+}
+
+/// Poll the state of a container until it is ready, it fails, or the
+/// timeout passes
+fn wait_until_ready(
+    name: &str,
+    timeout: Duration,
+    interval: Duration,
+    mut state: impl FnMut() -> Result<String, Error>,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    loop {
+        let current = state()?;
+        match readiness(&current) {
+            Readiness::Ready => return Ok(()),
+            Readiness::Waiting if start.elapsed() < timeout => {
+                debug!("Waiting for container '{}' ({})", name, current.trim());
+                thread::sleep(interval);
+            }
+            _ => {
+                return Err(FlokiError::ContainerNotReady {
+                    name: name.into(),
+                    state: current.trim().into(),
+                }
+                .into())
+            }
+        }
+    }
+}
+
+/// The status of a container, followed by its health if it has a
+/// healthcheck
+fn container_state(name: &str) -> Result<String, Error> {
+    let output = docker()
+        .args(["inspect", "--format"])
+        .arg("{{.State.Status}}{{if .State.Health}} {{.State.Health.Status}}{{end}}")
+        .arg(name)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Kill a running container
+fn kill_container(name: &str) -> Result<(), Error> {
+    docker()
+        .args(["kill", name])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+    Ok(())
 }
 
 impl Drop for DaemonHandle {
     fn drop(&mut self) {
         info!("Stopping daemon docker container '{}'", self.name);
-        Command::new("docker")
-            .args(&["kill", &self.name])
+        docker()
+            .args(["kill", &self.name])
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
@@ -46,22 +276,27 @@ impl DockerCommandBuilder {
             self, command
         );
 
-        let mut command = Command::new("docker")
-            .args(&["run", "--rm", "-it"])
-            .args(&self.build_volume_switches())
-            .args(self.build_environment_switches())
-            .args(self.build_docker_switches())
-            .arg(&self.image)
-            .args(command)
+        let mut command = self
+            .run_command(command)
             .stdout(Stdio::inherit())
             .stderr(Stdio::inherit())
             .stdin(Stdio::inherit())
             .spawn()
             .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
 
-        let exit_status = command
-            .wait()
-            .map_err(|e| FlokiError::FailedToCompleteDockerCommand { error: e })?;
+        let exit_status = match self.timeout {
+            Some(timeout) => self.wait_with_timeout(&mut command, timeout)?,
+            None => command
+                .wait()
+                .map_err(|e| FlokiError::FailedToCompleteDockerCommand { error: e })?,
+        };
+
+        match self.cleanup(exit_status.success()) {
+            Cleanup::Automatic => (),
+            Cleanup::Remove => self.remove()?,
+            Cleanup::Keep => warn!("{}", self.kept_message()),
+        }
+
         if exit_status.success() {
             Ok(())
         } else {
@@ -75,12 +310,113 @@ impl DockerCommandBuilder {
         }
     }
 
+    /// Wait for the container to exit, killing it if it runs past the
+    /// timeout
+    fn wait_with_timeout(
+        &self,
+        child: &mut std::process::Child,
+        timeout: Duration,
+    ) -> Result<std::process::ExitStatus, Error> {
+        let watchdog = Watchdog::new(Instant::now(), timeout);
+        loop {
+            let status = child
+                .try_wait()
+                .map_err(|e| FlokiError::FailedToCompleteDockerCommand { error: e })?;
+            if let Some(status) = status {
+                return Ok(status);
+            }
+            if watchdog.expired(Instant::now()) {
+                warn!(
+                    "Session ran for longer than {:?}, killing container '{}'",
+                    timeout, self.name
+                );
+                kill_container(&self.name)?;
+                child
+                    .wait()
+                    .map_err(|e| FlokiError::FailedToCompleteDockerCommand { error: e })?;
+                return Err(FlokiError::SessionTimedOut {
+                    seconds: timeout.as_secs(),
+                }
+                .into());
+            }
+            thread::sleep(SESSION_POLL_INTERVAL);
+        }
+    }
+
+    /// Assemble the docker command to run the container
+    fn run_command(&self, command: &[&str]) -> Command {
+        let mut cmd = docker();
+        cmd.arg("run");
+        if !self.keep_on_failure {
+            cmd.arg("--rm");
+        }
+        if self.keep_on_failure || self.timeout.is_some() {
+            // Name the container so it can be found if it's kept, or killed
+            // when the session times out
+            cmd.args(["--name", &self.name]);
+        }
+        cmd.envs(self.build_passed_environment().iter().cloned())
+            .args(["--label", FLOKI_LABEL]);
+        if self.keep_on_failure {
+            // Kept on purpose, so not an orphan
+            cmd.args(["--label", &format!("{}=true", KEPT_LABEL)]);
+        }
+        cmd.arg(if self.tty { "-it" } else { "-i" })
+            .args(self.build_volume_switches())
+            .args(self.build_environment_switches())
+            .args(self.build_docker_switches())
+            .arg(&self.image)
+            .args(command);
+        cmd
+    }
+
+    fn cleanup(&self, success: bool) -> Cleanup {
+        match (self.keep_on_failure, success) {
+            (false, _) => Cleanup::Automatic,
+            (true, true) => Cleanup::Remove,
+            (true, false) => Cleanup::Keep,
+        }
+    }
+
+    fn kept_message(&self) -> String {
+        format!(
+            "Keeping failed container '{}' for debugging - remove it with 'docker rm {}'",
+            self.name, self.name
+        )
+    }
+
+    /// Remove the (stopped) container
+    fn remove(&self) -> Result<(), Error> {
+        debug!("Removing container '{}'", self.name);
+        let exit_status = docker()
+            .args(["rm", &self.name])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+        if exit_status.success() {
+            Ok(())
+        } else {
+            Err(FlokiError::RunContainerFailed {
+                exit_status: FlokiSubprocessExitStatus {
+                    process_description: "docker rm".into(),
+                    exit_status,
+                },
+            }
+            .into())
+        }
+    }
+
     pub fn start_as_daemon(self, command: &[&str]) -> Result<DaemonHandle, Error> {
         debug!("Starting daemon container '{}'", self.name);
-        let exit_status = Command::new("docker")
-            .args(&["run", "--rm"])
-            .args(&["--name", &self.name])
-            .args(&self.build_volume_switches())
+        let exit_status = docker()
+            .args(["run", "--rm"])
+            .args(["--name", &self.name])
+            .args(["--label", FLOKI_LABEL])
+            .envs(self.build_passed_environment().iter().cloned())
+            .args(self.build_volume_switches())
             .args(self.build_environment_switches())
             .args(self.build_docker_switches())
             .arg("-d")
@@ -112,18 +448,48 @@ impl DockerCommandBuilder {
             name: uuid::Uuid::new_v4().to_string(),
             volumes: Vec::new(),
             environment: Vec::new(),
+            passed_environment: Vec::new(),
             switches: Vec::new(),
             image: image.into(),
+            keep_on_failure: false,
+            tty: true,
+            timeout: None,
         }
     }
 
+    /// Kill the container if the session runs for longer than the timeout
+    pub fn set_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether to allocate a terminal for the container
+    pub fn set_tty(mut self, tty: bool) -> Self {
+        self.tty = tty;
+        self
+    }
+
+    pub fn set_keep_on_failure(mut self, keep_on_failure: bool) -> Self {
+        self.keep_on_failure = keep_on_failure;
+        self
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
-    pub fn add_volume(mut self, spec: (&path::PathBuf, &path::PathBuf)) -> Self {
+    pub fn add_volume(self, spec: (&path::PathBuf, &path::PathBuf)) -> Self {
+        self.add_volume_with_options(spec, &[])
+    }
+
+    /// Add a volume, with options such as `z` or `ro` after the mapping
+    pub fn add_volume_with_options(
+        mut self,
+        spec: (&path::PathBuf, &path::PathBuf),
+        options: &[String],
+    ) -> Self {
         let (src, dst) = spec;
-        self.volumes.push(Self::volume_mapping(src, dst));
+        self.volumes.push(Self::volume_mapping(src, dst, options));
         self
     }
 
@@ -133,6 +499,22 @@ impl DockerCommandBuilder {
         self
     }
 
+    /// Forward a variable from the environment floki runs in
+    pub fn forward_environment<V: AsRef<OsStr>>(mut self, var: V) -> Self {
+        self.environment.push("-e".into());
+        self.environment.push(var.as_ref().into());
+        self
+    }
+
+    /// Set a variable in the container without its value appearing on the
+    /// command line - docker is given the value in its own environment, and
+    /// forwards it by name
+    pub fn pass_environment<V: AsRef<OsStr>, B: AsRef<OsStr>>(mut self, var: V, value: B) -> Self {
+        self.passed_environment
+            .push((var.as_ref().into(), value.as_ref().into()));
+        self.forward_environment(var)
+    }
+
     pub fn add_docker_switch<S: AsRef<OsStr>>(mut self, switch: S) -> Self {
         self.switches.push(switch.as_ref().into());
         self
@@ -145,7 +527,7 @@ impl DockerCommandBuilder {
         cmd
     }
 
-    fn build_volume_switches(&self) -> Vec<&OsStr> {
+    pub(crate) fn build_volume_switches(&self) -> Vec<&OsStr> {
         let mut switches = Vec::new();
         for mapping in self.volumes.iter() {
             switches.push("-v".as_ref());
@@ -154,10 +536,14 @@ impl DockerCommandBuilder {
         switches
     }
 
-    fn volume_mapping(src: &path::Path, dst: &path::Path) -> OsString {
+    fn volume_mapping(src: &path::Path, dst: &path::Path, options: &[String]) -> OsString {
         let mut mapping = src.to_path_buf().into_os_string();
         mapping.push(":");
         mapping.push(dst);
+        if !options.is_empty() {
+            mapping.push(":");
+            mapping.push(options.join(","));
+        }
         mapping
     }
 
@@ -168,11 +554,15 @@ impl DockerCommandBuilder {
         binding
     }
 
-    fn build_environment_switches(&self) -> &Vec<OsString> {
+    pub(crate) fn build_environment_switches(&self) -> &Vec<OsString> {
         &self.environment
     }
 
-    fn build_docker_switches(&self) -> &Vec<OsString> {
+    pub(crate) fn build_passed_environment(&self) -> &Vec<(OsString, OsString)> {
+        &self.passed_environment
+    }
+
+    pub(crate) fn build_docker_switches(&self) -> &Vec<OsString> {
         &self.switches
     }
 }
@@ -194,6 +584,376 @@ pub fn enable_docker_in_docker(
 ) -> Result<DockerCommandBuilder, Error> {
     Ok(command
         .add_docker_switch("--link")
-        .add_docker_switch(&format!("{}:floki-docker", dind.name()))
+        .add_docker_switch(format!("{}:floki-docker", dind.name()))
         .add_environment("DOCKER_HOST", "tcp://floki-docker:2375"))
 }
+
+/// Containers started by floki are labelled, so they can be found later
+const FLOKI_LABEL: &str = "floki=true";
+
+/// The label of containers run with keep_on_failure
+const KEPT_LABEL: &str = "com.floki.kept";
+
+/// Remove floki containers which have stopped but were never removed, e.g.
+/// because floki crashed. Running containers are left alone, as they may
+/// belong to other floki sessions, as are containers which have been
+/// created but not started yet (another session may be starting them) and
+/// containers kept by keep_on_failure.
+pub fn cleanup_orphans() -> Result<(), Error> {
+    let format = format!("{{{{.ID}}}} {{{{.Label \"{}\"}}}}", KEPT_LABEL);
+    let output = docker()
+        .args(["ps", "-a", "--no-trunc", "--format", &format])
+        .args(["--filter", &format!("label={}", FLOKI_LABEL)])
+        .args(["--filter", "status=exited"])
+        .args(["--filter", "status=dead"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+    let ids = orphan_ids(&String::from_utf8_lossy(&output.stdout));
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    info!("Removing {} orphaned floki container(s)", ids.len());
+    let exit_status = docker()
+        .arg("rm")
+        .args(&ids)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(FlokiError::FailedToRemoveOrphans {
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "docker rm".into(),
+                exit_status,
+            },
+        }
+        .into())
+    }
+}
+
+/// Open an interactive shell in a running container. Whether the container
+/// is running is checked first, so a missing container gets a clear error
+/// rather than docker's.
+pub fn attach(
+    name: &str,
+    shell: &str,
+    is_running: impl FnOnce() -> Result<bool, Error>,
+) -> Result<(), Error> {
+    if !is_running()? {
+        return Err(FlokiError::ContainerNotRunning { name: name.into() }.into());
+    }
+
+    let exit_status = attach_command(name, shell)
+        .status()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(FlokiError::RunContainerFailed {
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "docker exec".into(),
+                exit_status,
+            },
+        }
+        .into())
+    }
+}
+
+/// Assemble the docker command to open a shell in a running container
+fn attach_command(name: &str, shell: &str) -> Command {
+    let mut cmd = docker();
+    cmd.args(["exec", "-it", name, shell]);
+    cmd
+}
+
+/// Check whether there is a running container with the given name
+pub fn container_running(name: &str) -> Result<bool, Error> {
+    let output = docker()
+        .args(["inspect", "--format", "{{.State.Running}}", name])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+    // docker inspect fails if there is no such container
+    Ok(output.status.success() && String::from_utf8_lossy(&output.stdout).trim() == "true")
+}
+
+/// Get the ids of containers listed with their kept label, leaving out
+/// those which were kept on purpose
+fn orphan_ids(output: &str) -> Vec<String> {
+    container_ids(output)
+        .into_iter()
+        .filter_map(|line| match line.split_once(' ') {
+            Some((_, kept)) if kept.trim() == "true" => None,
+            Some((id, _)) => Some(id.to_string()),
+            None => Some(line),
+        })
+        .collect()
+}
+
+/// Get the container ids listed by `docker ps --format {{.ID}}`
+fn container_ids(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_docker_context() -> Result<(), Error> {
+        set_docker_context(Some("remote"))?;
+        let cmd = DockerCommandBuilder::new("foo").set_tty(false);
+        assert_eq!(
+            cmd.run_command(&["sh"]).get_args().collect::<Vec<_>>(),
+            vec![
+                "--context",
+                "remote",
+                "run",
+                "--rm",
+                "--label",
+                "floki=true",
+                "-i",
+                "foo",
+                "sh"
+            ]
+        );
+        assert_eq!(
+            attach_command("floki-dev", "bash")
+                .get_args()
+                .collect::<Vec<_>>(),
+            vec!["--context", "remote", "exec", "-it", "floki-dev", "bash"]
+        );
+
+        assert_eq!(context_args(), vec!["--context", "remote"]);
+
+        set_docker_context(None)?;
+        assert_eq!(docker().get_args().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_docker_context() {
+        assert!(set_docker_context(Some("")).is_err());
+        assert!(set_docker_context(Some(" ")).is_err());
+    }
+
+    #[test]
+    fn test_container_ids() {
+        let output = "4b1d6e0f3c2a\n9f8e7d6c5b4a\n\n";
+        assert_eq!(container_ids(output), vec!["4b1d6e0f3c2a", "9f8e7d6c5b4a"]);
+        assert!(container_ids("").is_empty());
+    }
+
+    #[test]
+    fn test_passed_environment_is_kept_off_the_command_line() {
+        let cmd = DockerCommandBuilder::new("foo").pass_environment("HOST_TOKEN", "secret");
+        let command = cmd.run_command(&["sh"]);
+        assert!(command.get_args().all(|arg| arg != "HOST_TOKEN=secret"));
+        assert!(command.get_args().any(|arg| arg == "HOST_TOKEN"));
+        assert!(command
+            .get_envs()
+            .any(|(k, v)| k == "HOST_TOKEN" && v == Some("secret".as_ref())));
+    }
+
+    #[test]
+    fn test_orphan_ids_leave_out_kept_containers() {
+        let output = "4b1d6e0f3c2a \n9f8e7d6c5b4a true\n1a2b3c4d5e6f\n";
+        assert_eq!(orphan_ids(output), vec!["4b1d6e0f3c2a", "1a2b3c4d5e6f"]);
+    }
+
+    #[test]
+    fn test_readiness() {
+        assert_eq!(readiness("running\n"), Readiness::Ready);
+        assert_eq!(readiness("running healthy\n"), Readiness::Ready);
+        assert_eq!(readiness("running starting\n"), Readiness::Waiting);
+        assert_eq!(readiness("created\n"), Readiness::Waiting);
+        assert_eq!(readiness("running unhealthy\n"), Readiness::Failed);
+        assert_eq!(readiness("exited\n"), Readiness::Failed);
+        // docker inspect printed nothing, e.g. as the container is gone
+        assert_eq!(readiness(""), Readiness::Failed);
+    }
+
+    #[test]
+    fn test_wait_until_ready_polls_until_healthy() {
+        let mut states = vec!["running healthy", "running starting", "created"];
+        let result = wait_until_ready("dind", Duration::from_secs(5), Duration::ZERO, || {
+            Ok(states.pop().unwrap().to_string())
+        });
+        assert!(result.is_ok());
+        assert!(states.is_empty());
+    }
+
+    #[test]
+    fn test_wait_until_ready_times_out() {
+        let error = wait_until_ready("dind", Duration::ZERO, Duration::ZERO, || {
+            Ok("running starting".to_string())
+        })
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::ContainerNotReady { state, .. }) if state == "running starting"
+        ));
+    }
+
+    #[test]
+    fn test_run_without_tty() {
+        let cmd = DockerCommandBuilder::new("foo").set_tty(false);
+        assert_eq!(
+            cmd.run_command(&["sh"]).get_args().collect::<Vec<_>>(),
+            vec!["run", "--rm", "--label", "floki=true", "-i", "foo", "sh"]
+        );
+    }
+
+    #[test]
+    fn test_attach_command() {
+        let cmd = attach_command("floki-dev", "bash");
+        assert_eq!(cmd.get_program(), "docker");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec!["exec", "-it", "floki-dev", "bash"]
+        );
+    }
+
+    #[test]
+    fn test_attach_missing_container() {
+        let error = attach("floki-dev", "bash", || Ok(false)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::ContainerNotRunning { name }) if name == "floki-dev"
+        ));
+    }
+
+    #[test]
+    fn test_command_spec_build() {
+        let spec = CommandSpec {
+            command: "make".into(),
+            args: vec!["-j4".into(), "image".into()],
+            env: maplit::btreemap!("TAG".into() => "1.0".into()),
+        };
+        let command = spec.build();
+
+        assert_eq!(command.get_program(), "make");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), vec!["-j4", "image"]);
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            vec![(OsStr::new("TAG"), Some(OsStr::new("1.0")))]
+        );
+    }
+
+    #[test]
+    fn test_command_spec_reports_captured_output_on_failure() {
+        let spec = CommandSpec {
+            command: "sh".into(),
+            args: vec!["-c".into(), "echo \"it went wrong\" >&2; exit 3".into()],
+            env: BTreeMap::new(),
+        };
+
+        let error = spec.run(true).unwrap_err();
+        match &error {
+            FlokiError::CommandFailed {
+                exit_status,
+                output,
+            } => {
+                assert_eq!(exit_status.exit_status.code(), Some(3));
+                assert_eq!(output.as_deref(), Some("it went wrong\n"));
+            }
+            _ => panic!("unexpected error {:?}", error),
+        }
+        assert!(error.to_string().contains("it went wrong"));
+    }
+
+    #[test]
+    fn test_command_spec_success() {
+        let spec = CommandSpec {
+            command: "true".into(),
+            args: vec![],
+            env: BTreeMap::new(),
+        };
+        assert!(spec.run(true).is_ok());
+    }
+
+    #[test]
+    fn test_run_removes_container() {
+        let cmd = DockerCommandBuilder::new("foo");
+        let command = cmd.run_command(&["sh"]);
+        let args: Vec<_> = command.get_args().collect();
+
+        assert_eq!(
+            args,
+            vec!["run", "--rm", "--label", "floki=true", "-it", "foo", "sh"]
+        );
+        assert_eq!(cmd.cleanup(false), Cleanup::Automatic);
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let started = Instant::now();
+        let watchdog = Watchdog::new(started, Duration::from_secs(60));
+        assert!(!watchdog.expired(started));
+        assert!(!watchdog.expired(started + Duration::from_secs(59)));
+        assert!(watchdog.expired(started + Duration::from_secs(60)));
+        assert!(watchdog.expired(started + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_timed_session_names_container() {
+        let cmd = DockerCommandBuilder::new("foo").set_timeout(Some(Duration::from_secs(60)));
+        let command = cmd.run_command(&["sh"]);
+        let args: Vec<_> = command.get_args().collect();
+
+        assert_eq!(
+            args,
+            vec![
+                "run",
+                "--rm",
+                "--name",
+                cmd.name(),
+                "--label",
+                "floki=true",
+                "-it",
+                "foo",
+                "sh"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_container_on_failure() {
+        let cmd = DockerCommandBuilder::new("foo").set_keep_on_failure(true);
+        let command = cmd.run_command(&["sh"]);
+        let args: Vec<_> = command.get_args().collect();
+
+        assert_eq!(
+            args,
+            vec![
+                "run",
+                "--name",
+                cmd.name(),
+                "--label",
+                "floki=true",
+                "--label",
+                "com.floki.kept=true",
+                "-it",
+                "foo",
+                "sh"
+            ]
+        );
+        assert_eq!(cmd.cleanup(false), Cleanup::Keep);
+        assert!(cmd.kept_message().contains(cmd.name()));
+        assert_eq!(cmd.cleanup(true), Cleanup::Remove);
+    }
+}
@@ -1,14 +1,17 @@
 /// Configuration file format for floki
+use crate::command::CommandSpec;
 use crate::errors;
 use crate::image;
+use crate::template;
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
 
 use std::collections::BTreeMap;
-use std::fs::File;
+use std::env::consts;
+use std::fs;
 use std::path;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub(crate) enum Shell {
     Shell(String),
@@ -35,7 +38,12 @@ impl Shell {
 #[serde(untagged)]
 pub(crate) enum DindConfig {
     Toggle(bool),
-    Image { image: String },
+    Image {
+        image: String,
+        /// A command to check the docker daemon is up, which floki waits
+        /// to succeed before starting the main container
+        healthcheck: Option<String>,
+    },
 }
 
 impl DindConfig {
@@ -44,7 +52,41 @@ impl DindConfig {
     }
 }
 
+/// How the host user is forwarded into the container - either on or off,
+/// or with options
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ForwardUser {
+    Toggle(bool),
+    Options {
+        /// Also make the user and group names resolvable in the container,
+        /// for tools which read /etc/passwd
+        #[serde(default = "default_to_false")]
+        map_names: bool,
+    },
+}
+
+impl ForwardUser {
+    pub fn deactivated() -> Self {
+        ForwardUser::Toggle(false)
+    }
+
+    pub fn forward(&self) -> bool {
+        match self {
+            ForwardUser::Toggle(forward) => *forward,
+            ForwardUser::Options { .. } => true,
+        }
+    }
+
+    pub fn map_names(&self) -> bool {
+        match self {
+            ForwardUser::Toggle(_) => false,
+            ForwardUser::Options { map_names } => *map_names,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 /// The Volume structure captures configuration for floki volumes
 pub(crate) struct Volume {
     #[serde(default = "default_to_false")]
@@ -52,15 +94,152 @@ pub(crate) struct Volume {
     /// shared volume by the same name. Volumes which are not
     /// shared are localised to a particular floki configuration file.
     pub(crate) shared: bool,
+    #[serde(default)]
+    /// The scope namespaces a shared volume, so that it is only
+    /// shared with volumes of the same name in the same scope.
+    pub(crate) share_scope: Option<String>,
+    #[serde(default = "default_to_false")]
+    /// A tmpfs volume is backed by memory rather than a host directory,
+    /// so its contents don't persist beyond the container.
+    pub(crate) tmpfs: bool,
+    #[serde(default)]
+    /// A host directory copied into the volume when it is first created.
+    /// Changes in the container aren't written back to it.
+    pub(crate) init_from: Option<path::PathBuf>,
+    #[serde(default = "Vec::new")]
+    /// Options appended to the bind mount, such as `z` for SELinux
+    /// relabeling or `rshared` for bind propagation.
+    pub(crate) options: Vec<String>,
+    #[serde(default = "Vec::new")]
+    /// Commands run in the container, after init, only when the volume has
+    /// just been created.
+    pub(crate) setup_once: Vec<String>,
     /// The mount path is the path at which the volume is mounted
     /// inside the floki container.
     pub(crate) mount: path::PathBuf,
 }
 
+/// Credentials used to log in to a registry before pulling the image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RegistryAuth {
+    /// The registry to log in to, or Docker Hub if not set
+    pub(crate) registry: Option<String>,
+    pub(crate) username: String,
+    /// The host environment variable holding the password
+    pub(crate) password_env: Option<String>,
+    /// The OS keychain entry holding the password
+    pub(crate) password_keychain: Option<KeychainEntry>,
+}
+
+/// An entry in the OS keychain, found by its service and account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct KeychainEntry {
+    pub(crate) service: String,
+    pub(crate) account: String,
+}
+
+/// Tools which can be used to drop from root to the host user
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PrivilegeDrop {
+    Gosu,
+    SuExec,
+}
+
+impl PrivilegeDrop {
+    pub(crate) fn command(&self) -> &str {
+        match self {
+            PrivilegeDrop::Gosu => "gosu",
+            PrivilegeDrop::SuExec => "su-exec",
+        }
+    }
+}
+
+/// When docker should pull the image before running the container
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PullPolicy {
+    Always,
+    Missing,
+    Never,
+}
+
+impl PullPolicy {
+    pub(crate) fn value(&self) -> &str {
+        match self {
+            PullPolicy::Always => "always",
+            PullPolicy::Missing => "missing",
+            PullPolicy::Never => "never",
+        }
+    }
+}
+
+/// Which cgroup namespace the container runs in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CgroupNamespace {
+    Host,
+    Private,
+}
+
+impl CgroupNamespace {
+    pub(crate) fn value(&self) -> &str {
+        match self {
+            CgroupNamespace::Host => "host",
+            CgroupNamespace::Private => "private",
+        }
+    }
+}
+
+/// The isolation technology of a Windows container
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Isolation {
+    Default,
+    Process,
+    Hyperv,
+}
+
+impl Isolation {
+    pub(crate) fn value(&self) -> &str {
+        match self {
+            Isolation::Default => "default",
+            Isolation::Process => "process",
+            Isolation::Hyperv => "hyperv",
+        }
+    }
+}
+
+/// The container runtime behind the docker CLI - podman provides a docker
+/// compatible CLI, but differs in places
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Runtime {
+    Docker,
+    Podman,
+}
+
+impl Runtime {
+    /// The switches which set the container's entrypoint. Podman only
+    /// clears the entrypoint when the empty value is attached to the flag.
+    pub(crate) fn entrypoint_switches(&self, entrypoint: &str) -> Vec<String> {
+        match self {
+            Runtime::Docker => vec!["--entrypoint".into(), entrypoint.into()],
+            Runtime::Podman => vec![format!("--entrypoint={}", entrypoint)],
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub(crate) enum Entrypoint {
-    Suppress { suppress: bool },
+    Suppress {
+        suppress: bool,
+    },
+    /// Use a script which runs the init commands, then execs the shell
+    Script {
+        script: bool,
+    },
 }
 
 impl Entrypoint {
@@ -70,6 +249,10 @@ impl Entrypoint {
             _ => None,
         }
     }
+
+    pub fn script(&self) -> bool {
+        matches!(self, Entrypoint::Script { script: true })
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -78,8 +261,8 @@ pub(crate) struct FlokiConfig {
     pub(crate) image: image::Image,
     #[serde(default = "Vec::new")]
     pub(crate) init: Vec<String>,
-    #[serde(default = "default_shell")]
-    pub(crate) shell: Shell,
+    pub(crate) shell: Option<Shell>,
+    pub(crate) shell_rc: Option<path::PathBuf>,
     #[serde(default = "default_mount")]
     pub(crate) mount: path::PathBuf,
     #[serde(default = "Vec::new")]
@@ -88,63 +271,600 @@ pub(crate) struct FlokiConfig {
     pub(crate) forward_ssh_agent: bool,
     #[serde(default = "DindConfig::deactivated")]
     pub(crate) dind: DindConfig,
-    #[serde(default = "default_to_false")]
-    pub(crate) forward_user: bool,
+    #[serde(default = "ForwardUser::deactivated")]
+    pub(crate) forward_user: ForwardUser,
     #[serde(default = "BTreeMap::new")]
     pub(crate) volumes: BTreeMap<String, Volume>,
     #[serde(default = "default_entrypoint")]
     pub(crate) entrypoint: Entrypoint,
+    #[serde(default)]
+    pub(crate) registry_mirror: Option<String>,
+    pub(crate) registry_auth: Option<RegistryAuth>,
+    pub(crate) umask: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) label_files: Vec<path::PathBuf>,
+    pub(crate) memory_swap: Option<String>,
+    #[serde(default = "default_to_false")]
+    pub(crate) oom_kill_disable: bool,
+    pub(crate) oom_score_adj: Option<i64>,
+    pub(crate) pids_limit: Option<i64>,
+    pub(crate) drop_privileges: Option<PrivilegeDrop>,
+    #[serde(default = "default_to_false")]
+    pub(crate) keep_on_failure: bool,
+    pub(crate) shm_size: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) forward_env_prefixes: Vec<String>,
+    pub(crate) forward_env_prefix: Option<String>,
+    pub(crate) locale: Option<String>,
+    #[serde(default = "default_to_false")]
+    pub(crate) keep_env_clean: bool,
+    pub(crate) stop_signal: Option<String>,
+    pub(crate) mac_address: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) mounts: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) devices: Vec<String>,
+    pub(crate) host_gateway_alias: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) on_exit: Vec<CommandSpec>,
+    pub(crate) outer_workdir: Option<path::PathBuf>,
+    pub(crate) pull_policy: Option<PullPolicy>,
+    #[serde(default = "default_runtime")]
+    pub(crate) runtime: Runtime,
+    #[serde(default = "default_to_false")]
+    pub(crate) cleanup_orphans: bool,
+    #[serde(default = "default_to_false")]
+    pub(crate) dedupe_docker_switches: bool,
+    #[serde(default = "default_to_false")]
+    pub(crate) read_only_rootfs: bool,
+    pub(crate) tty: Option<bool>,
+    /// Mount the directory floki is run from, rather than the project root
+    /// (only set from the command line)
+    #[serde(skip)]
+    pub(crate) bind_current_directory: bool,
+    pub(crate) cgroup_parent: Option<String>,
+    pub(crate) cgroupns: Option<CgroupNamespace>,
+    pub(crate) isolation: Option<Isolation>,
+    pub(crate) userns: Option<String>,
+    #[serde(default = "default_to_false")]
+    pub(crate) label_start_time: bool,
+    pub(crate) session_timeout: Option<u64>,
+    pub(crate) docker_context: Option<String>,
+    pub(crate) volume_key: Option<String>,
 }
 
 impl FlokiConfig {
-    pub fn from_file(file: &path::Path) -> Result<FlokiConfig, Error> {
-        debug!("Reading configuration file: {:?}", file);
-
-        let f = File::open(file).map_err(|e| errors::FlokiError::ProblemOpeningConfigYaml {
-            name: file.display().to_string(),
-            error: e,
-        })?;
+    /// Load several configuration files, merged left to right so that
+    /// later files take precedence over earlier ones. The selected profile
+    /// is merged over each file which defines it.
+    pub fn from_files(
+        files: &[path::PathBuf],
+        profile: Option<&str>,
+    ) -> Result<FlokiConfig, Error> {
+        let contents = read_files(files)?
+            .iter()
+            .zip(files)
+            .map(|(contents, file)| {
+                let directory = file
+                    .parent()
+                    .filter(|directory| !directory.as_os_str().is_empty())
+                    .unwrap_or_else(|| path::Path::new("."));
+                template::expand_document(contents, directory)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let names = file_names(files);
+        if let Some(profile) = profile {
+            if !contents
+                .iter()
+                .any(|contents| defines_profile_in(contents, profile))
+            {
+                return Err(errors::FlokiError::ProfileNotDefined {
+                    profile: profile.into(),
+                    files: names,
+                }
+                .into());
+            }
+        }
 
-        let mut config: FlokiConfig = serde_yaml::from_reader(f).map_err(|e| {
-            errors::FlokiError::ProblemParsingConfigYaml {
-                name: file.display().to_string(),
+        let mut config = Self::from_strs_for_host(&contents, consts::OS, consts::ARCH, profile)
+            .map_err(|e| errors::FlokiError::ProblemParsingConfigYaml {
+                name: names.clone(),
                 error: e,
-            }
-        })?;
+            })?;
 
         // Ensure the path to an external yaml file is correct.
         // If the image.yaml.path file is relative, then it should
         // be relative to the floki config file. At this point we
         // already have the path to the floki config file, so we
         // just prepend that to image.yaml.path.
-        if let image::Image::Yaml { ref mut yaml } = config.image {
-            if let image::YamlSpec::File { file, .. } = yaml {
-                if file.is_relative() {
-                    *file = file
-                        .parent()
-                        .ok_or_else(|| errors::FlokiInternalError::InternalAssertionFailed {
-                            description: format!(
-                                "could not construct path to external yaml file '{:?}'",
-                                &file
-                            ),
-                        })?
-                        .join(file.clone());
-                }
+        if let image::Image::Yaml {
+            yaml: image::YamlSpec::File { ref mut file, .. },
+        } = config.image
+        {
+            if file.is_relative() {
+                *file = file
+                    .parent()
+                    .ok_or_else(|| errors::FlokiInternalError::InternalAssertionFailed {
+                        description: format!(
+                            "could not construct path to external yaml file '{:?}'",
+                            &file
+                        ),
+                    })?
+                    .join(file.clone());
             }
         }
 
-        debug!(
-            "Parsed '{}' into configuration: {:?}",
-            file.display(),
-            &config
-        );
+        // Images referenced from other configurations are relative to the
+        // configuration file doing the referencing - the one the image was
+        // last set in
+        let image_source = provenance(&contents, consts::OS, consts::ARCH, profile)
+            .map_err(|e| errors::FlokiError::ProblemParsingConfigYaml {
+                name: names.clone(),
+                error: e,
+            })?
+            .remove("image");
+        let image_directory = image_source
+            .as_ref()
+            .and_then(Source::file)
+            .and_then(|file| files.get(file))
+            .and_then(|file| file.parent());
+        if let image::Image::FromConfig { ref mut config } = config.image {
+            if let Some(directory) = image_directory {
+                *config = directory.join(&config);
+            }
+        }
+
+        // As are compose files
+        if let image::Image::Compose { ref mut compose } = config.image {
+            if let Some(directory) = image_directory {
+                compose.relative_to(directory);
+            }
+        }
+
+        debug!("Parsed '{}' into configuration: {:?}", names, &config);
 
         Ok(config)
     }
+
+    /// A configuration made only from command line options, for one-off
+    /// runs without a configuration file. Everything else has its default.
+    pub(crate) fn scratch(image: &str, shell: Option<&str>) -> Result<Self, Error> {
+        let mut fields = serde_yaml::Mapping::new();
+        fields.insert("image".into(), image.into());
+        let mut config: Self = serde_yaml::from_value(fields.into())?;
+        if let Some(shell) = shell {
+            config.shell = Some(Shell::Shell(shell.into()));
+        }
+        Ok(config)
+    }
+
+    /// Describe where each field of the configuration loaded from these
+    /// files, with these overrides, was set. Fields which weren't set
+    /// anywhere have their default value.
+    pub(crate) fn explain(
+        files: &[path::PathBuf],
+        profile: Option<&str>,
+        overrides: &RuntimeOverrides,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let mut config = Self::from_files(files, profile)?;
+        overrides.apply(&mut config);
+
+        let contents = read_files(files)?;
+        let mut sources =
+            provenance(&contents, consts::OS, consts::ARCH, profile).map_err(|e| {
+                errors::FlokiError::ProblemParsingConfigYaml {
+                    name: file_names(files),
+                    error: e,
+                }
+            })?;
+        for key in overrides.keys() {
+            sources.insert(key.to_string(), Source::CommandLine);
+        }
+
+        let fields = match serde_yaml::to_value(&config)? {
+            serde_yaml::Value::Mapping(fields) => fields,
+            _ => serde_yaml::Mapping::new(),
+        };
+        Ok(fields
+            .into_iter()
+            .filter_map(|(key, _)| key.as_str().map(str::to_string))
+            .map(|key| {
+                let source = sources
+                    .get(&key)
+                    .map_or_else(|| "default".to_string(), |source| source.describe(files));
+                (key, source)
+            })
+            .collect())
+    }
 }
 
-fn default_shell() -> Shell {
-    Shell::Shell("sh".into())
+/// A field which differs between two configurations, as its left and
+/// right values
+pub(crate) type Difference = (serde_yaml::Value, serde_yaml::Value);
+
+/// Compare two resolved configurations field by field, returning the
+/// fields which differ by their dotted path. Fields only set on one side
+/// are null on the other.
+pub(crate) fn diff(
+    left: &FlokiConfig,
+    right: &FlokiConfig,
+) -> Result<BTreeMap<String, Difference>, Error> {
+    let mut differences = BTreeMap::new();
+    diff_values(
+        None,
+        &serde_yaml::to_value(left)?,
+        &serde_yaml::to_value(right)?,
+        &mut differences,
+    );
+    Ok(differences)
+}
+
+/// Record where two values differ, descending into mappings so only the
+/// fields which changed are reported
+fn diff_values(
+    path: Option<&str>,
+    left: &serde_yaml::Value,
+    right: &serde_yaml::Value,
+    differences: &mut BTreeMap<String, Difference>,
+) {
+    use serde_yaml::Value;
+
+    match (left, right) {
+        (Value::Mapping(left), Value::Mapping(right)) => {
+            let keys: std::collections::BTreeSet<&str> = left
+                .iter()
+                .chain(right.iter())
+                .filter_map(|(key, _)| key.as_str())
+                .collect();
+            for key in keys {
+                let field = Value::String(key.into());
+                let name = match path {
+                    Some(path) => format!("{}.{}", path, key),
+                    None => key.to_string(),
+                };
+                diff_values(
+                    Some(&name),
+                    left.get(&field).unwrap_or(&Value::Null),
+                    right.get(&field).unwrap_or(&Value::Null),
+                    differences,
+                );
+            }
+        }
+        (left, right) if left != right => {
+            differences.insert(
+                path.unwrap_or_default().to_string(),
+                (left.clone(), right.clone()),
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Read the configuration files, in order
+fn read_files(files: &[path::PathBuf]) -> Result<Vec<String>, Error> {
+    debug!("Reading configuration files: {:?}", files);
+
+    Ok(files
+        .iter()
+        .map(|file| {
+            fs::read_to_string(file).map_err(|e| errors::FlokiError::ProblemOpeningConfigYaml {
+                name: file.display().to_string(),
+                error: e,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+/// The configuration files, for error messages
+/// Whether any of the configuration files defines the profile
+pub(crate) fn defines_profile(files: &[path::PathBuf], profile: &str) -> Result<bool, Error> {
+    Ok(read_files(files)?
+        .iter()
+        .any(|contents| defines_profile_in(contents, profile)))
+}
+
+/// Whether a configuration defines the profile. Configurations which don't
+/// parse don't define any.
+fn defines_profile_in(contents: &str, profile: &str) -> bool {
+    serde_yaml::from_str::<serde_yaml::Value>(contents).is_ok_and(|raw| {
+        raw.get("profiles")
+            .and_then(|profiles| profiles.get(profile))
+            .is_some()
+    })
+}
+
+fn file_names(files: &[path::PathBuf]) -> String {
+    files
+        .iter()
+        .map(|file| file.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Where a piece of configuration was set. Files are given by their
+/// position in the list of configuration files.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Source {
+    File(usize),
+    HostOverride { file: usize, host: String },
+    Profile { file: usize, name: String },
+    CommandLine,
+}
+
+impl Source {
+    /// The position of the file this was set in, if it was set in one
+    fn file(&self) -> Option<usize> {
+        match self {
+            Source::File(file)
+            | Source::HostOverride { file, .. }
+            | Source::Profile { file, .. } => Some(*file),
+            Source::CommandLine => None,
+        }
+    }
+
+    fn describe(&self, files: &[path::PathBuf]) -> String {
+        let name = |file: &usize| {
+            files
+                .get(*file)
+                .map_or_else(|| format!("file {}", file), |f| f.display().to_string())
+        };
+        match self {
+            Source::File(file) => name(file),
+            Source::HostOverride { file, host } => {
+                format!("{} (overrides for {})", name(file), host)
+            }
+            Source::Profile {
+                file,
+                name: profile,
+            } => {
+                format!("{} (profile {})", name(file), profile)
+            }
+            Source::CommandLine => "command line".into(),
+        }
+    }
+}
+
+/// The layers of configuration to merge, in order: each file, followed by
+/// its overrides for this host, then the selected profile
+fn layers(
+    contents: &[String],
+    os: &str,
+    arch: &str,
+    profile: Option<&str>,
+) -> Result<Vec<(Source, serde_yaml::Value)>, serde_yaml::Error> {
+    let mut layers = Vec::new();
+
+    for (file, content) in contents.iter().enumerate() {
+        let mut raw: serde_yaml::Value = serde_yaml::from_str(content)?;
+        let overrides = raw
+            .as_mapping_mut()
+            .and_then(|m| m.remove(&"overrides".into()));
+        let profiles = raw
+            .as_mapping_mut()
+            .and_then(|m| m.remove(&"profiles".into()));
+        layers.push((Source::File(file), raw));
+
+        if let Some(serde_yaml::Value::Mapping(overrides)) = overrides {
+            for (host, entry) in overrides {
+                if host.as_str() == Some(os) || host.as_str() == Some(arch) {
+                    debug!("Applying configuration overrides for {:?}", host);
+                    let host = host.as_str().unwrap_or_default().to_string();
+                    layers.push((Source::HostOverride { file, host }, entry));
+                }
+            }
+        }
+
+        if let (Some(profile), Some(serde_yaml::Value::Mapping(mut profiles))) = (profile, profiles)
+        {
+            if let Some(entry) = profiles.remove(&profile.into()) {
+                let name = profile.to_string();
+                layers.push((Source::Profile { file, name }, entry));
+            }
+        }
+    }
+
+    Ok(layers)
+}
+
+/// Which layer set each top level field of the merged configuration. Fields
+/// holding mappings are merged, so earlier layers may also have contributed
+/// to a mapping.
+fn provenance(
+    contents: &[String],
+    os: &str,
+    arch: &str,
+    profile: Option<&str>,
+) -> Result<BTreeMap<String, Source>, serde_yaml::Error> {
+    let mut sources = BTreeMap::new();
+    for (source, layer) in layers(contents, os, arch, profile)? {
+        if let serde_yaml::Value::Mapping(layer) = layer {
+            for (key, _) in layer {
+                if let Some(key) = key.as_str() {
+                    sources.insert(key.to_string(), source.clone());
+                }
+            }
+        }
+    }
+    Ok(sources)
+}
+
+impl FlokiConfig {
+    /// Parse and merge configurations, applying any overrides for the
+    /// given host operating system and architecture, then the selected
+    /// profile
+    fn from_strs_for_host(
+        contents: &[String],
+        os: &str,
+        arch: &str,
+        profile: Option<&str>,
+    ) -> Result<Self, serde_yaml::Error> {
+        let layers = layers(contents, os, arch, profile)?;
+        match layers.as_slice() {
+            // Parse directly where possible (a single file with no overrides
+            // or profiles), to keep locations in error messages
+            [(_, layer)] if *layer == serde_yaml::from_str::<serde_yaml::Value>(&contents[0])? => {
+                serde_yaml::from_str(&contents[0])
+            }
+            _ => {
+                serde_yaml::from_value(merge(layers.into_iter().map(|(_, layer)| layer).collect()))
+            }
+        }
+    }
+}
+
+/// Changes to the configuration requested for a single run, e.g. from the
+/// command line. These apply after the configuration has been loaded, so
+/// win over anything configured.
+#[derive(Debug, Default)]
+pub(crate) struct RuntimeOverrides {
+    pub(crate) no_forward_ssh_agent: bool,
+    pub(crate) no_dind: bool,
+    pub(crate) no_forward_user: bool,
+    pub(crate) mount: Option<path::PathBuf>,
+    pub(crate) bind_current_dir_as: Option<path::PathBuf>,
+    pub(crate) session_timeout: Option<u64>,
+    pub(crate) keep_env_clean: bool,
+}
+
+impl RuntimeOverrides {
+    /// The configuration fields these overrides change
+    pub(crate) fn keys(&self) -> Vec<&'static str> {
+        let mut keys = Vec::new();
+        if self.no_forward_ssh_agent {
+            keys.push("forward_ssh_agent");
+        }
+        if self.no_dind {
+            keys.push("dind");
+        }
+        if self.no_forward_user {
+            keys.push("forward_user");
+        }
+        if self.mount.is_some() || self.bind_current_dir_as.is_some() {
+            keys.push("mount");
+        }
+        if self.session_timeout.is_some() {
+            keys.push("session_timeout");
+        }
+        if self.keep_env_clean {
+            keys.push("keep_env_clean");
+        }
+        keys
+    }
+
+    pub(crate) fn apply(&self, config: &mut FlokiConfig) {
+        if self.no_forward_ssh_agent {
+            config.forward_ssh_agent = false;
+        }
+        if self.no_dind {
+            config.dind = DindConfig::deactivated();
+        }
+        if self.no_forward_user {
+            config.forward_user = ForwardUser::deactivated();
+        }
+        if let Some(mount) = &self.mount {
+            config.mount = mount.clone();
+        }
+        if let Some(mount) = &self.bind_current_dir_as {
+            config.mount = mount.clone();
+            config.bind_current_directory = true;
+        }
+        if let Some(timeout) = self.session_timeout {
+            config.session_timeout = Some(timeout);
+        }
+        if self.keep_env_clean {
+            config.keep_env_clean = true;
+        }
+    }
+}
+
+/// Merge configuration documents left to right, so that later documents
+/// take precedence over earlier ones
+pub(crate) fn merge(documents: Vec<serde_yaml::Value>) -> serde_yaml::Value {
+    documents
+        .into_iter()
+        .reduce(merge_document)
+        .unwrap_or(serde_yaml::Value::Null)
+}
+
+/// Top level fields which are replaced wholesale rather than merged,
+/// since mixing the fields of two variants makes no sense
+const UNMERGED_FIELDS: &[&str] = &["image"];
+
+/// Merge one configuration document over another
+fn merge_document(base: serde_yaml::Value, overlay: serde_yaml::Value) -> serde_yaml::Value {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(mut base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                let merged = match base.remove(&key) {
+                    Some(existing)
+                        if !key.as_str().is_some_and(|k| UNMERGED_FIELDS.contains(&k)) =>
+                    {
+                        merge_values(existing, value)
+                    }
+                    _ => value,
+                };
+                base.insert(key, merged);
+            }
+            serde_yaml::Value::Mapping(base)
+        }
+        (base, overlay) => merge_values(base, overlay),
+    }
+}
+
+/// Merge one yaml value over another. Mappings are merged key by key,
+/// while anything else is replaced.
+fn merge_values(base: serde_yaml::Value, overlay: serde_yaml::Value) -> serde_yaml::Value {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(mut base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                let merged = match base.remove(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            serde_yaml::Value::Mapping(base)
+        }
+        (_, overlay) => overlay,
+    }
+}
+
+/// The image used for docker-in-docker when none is configured
+pub(crate) const DEFAULT_DIND_IMAGE: &str = "docker:stable-dind";
+
+/// Host wide defaults, read from defaults.yaml in the floki workspace
+/// (~/.floki). These apply to every floki configuration on the host.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub(crate) struct FlokiDefaults {
+    dind_image: Option<String>,
+}
+
+impl FlokiDefaults {
+    /// Load the defaults file, if there is one
+    pub(crate) fn from_file(file: &path::Path) -> Result<Self, Error> {
+        if !file.exists() {
+            return Ok(Self::default());
+        }
+
+        debug!("Reading defaults file: {}", file.display());
+        let contents =
+            fs::read_to_string(file).map_err(|e| errors::FlokiError::ProblemOpeningConfigYaml {
+                name: file.display().to_string(),
+                error: e,
+            })?;
+        let defaults = serde_yaml::from_str(&contents).map_err(|e| {
+            errors::FlokiError::ProblemParsingConfigYaml {
+                name: file.display().to_string(),
+                error: e,
+            }
+        })?;
+        Ok(defaults)
+    }
+
+    /// The image to use for `dind: true`
+    pub(crate) fn dind_image(&self) -> &str {
+        self.dind_image.as_deref().unwrap_or(DEFAULT_DIND_IMAGE)
+    }
 }
 
 fn default_mount() -> path::PathBuf {
@@ -159,9 +879,14 @@ fn default_entrypoint() -> Entrypoint {
     Entrypoint::Suppress { suppress: true }
 }
 
+fn default_runtime() -> Runtime {
+    Runtime::Docker
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use serde_json::json;
 
     #[derive(Debug, PartialEq, Serialize, Deserialize)]
     struct TestShellConfig {
@@ -213,6 +938,20 @@ mod test {
         let expected = TestDindConfig {
             dind: DindConfig::Image {
                 image: "dind:custom".into(),
+                healthcheck: None,
+            },
+        };
+        let actual: TestDindConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_dind_healthcheck_config() {
+        let yaml = "dind:\n  image: dind:custom\n  healthcheck: docker info";
+        let expected = TestDindConfig {
+            dind: DindConfig::Image {
+                image: "dind:custom".into(),
+                healthcheck: Some("docker info".into()),
             },
         };
         let actual: TestDindConfig = serde_yaml::from_str(yaml).unwrap();
@@ -245,4 +984,672 @@ mod test {
         assert_eq!(actual, expected);
         assert_eq!(actual.entrypoint.value(), None);
     }
+
+    #[test]
+    fn test_entrypoint_script() {
+        let yaml = "entrypoint:\n  script: true";
+        let expected = TestEntrypointConfig {
+            entrypoint: Entrypoint::Script { script: true },
+        };
+        let actual: TestEntrypointConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual, expected);
+        assert!(actual.entrypoint.script());
+        assert_eq!(actual.entrypoint.value(), None);
+    }
+
+    #[test]
+    fn test_label_files() {
+        let yaml = "image: foo\nlabel_files:\n  - labels.env\n  - /etc/labels.env";
+        let actual: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            actual.label_files,
+            vec![
+                path::PathBuf::from("labels.env"),
+                path::PathBuf::from("/etc/labels.env")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_label_files_default_empty() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert!(actual.label_files.is_empty());
+    }
+
+    #[test]
+    fn test_memory_controls() {
+        let yaml = "image: foo\nmemory_swap: 2g\noom_kill_disable: true";
+        let actual: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual.memory_swap, Some("2g".into()));
+        assert!(actual.oom_kill_disable);
+    }
+
+    #[test]
+    fn test_memory_controls_default() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.memory_swap, None);
+        assert!(!actual.oom_kill_disable);
+    }
+
+    #[test]
+    fn test_process_controls() {
+        let actual: FlokiConfig =
+            serde_yaml::from_str("image: foo\noom_score_adj: -500\npids_limit: 256").unwrap();
+        assert_eq!(actual.oom_score_adj, Some(-500));
+        assert_eq!(actual.pids_limit, Some(256));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.oom_score_adj, None);
+        assert_eq!(actual.pids_limit, None);
+    }
+
+    #[test]
+    fn test_drop_privileges() {
+        let yaml = "image: foo\ndrop_privileges: su-exec";
+        let actual: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual.drop_privileges, Some(PrivilegeDrop::SuExec));
+        assert_eq!(actual.drop_privileges.unwrap().command(), "su-exec");
+
+        let yaml = "image: foo\ndrop_privileges: sudo";
+        assert!(serde_yaml::from_str::<FlokiConfig>(yaml).is_err());
+    }
+
+    #[test]
+    fn test_overrides_apply_on_matching_host() {
+        let yaml = r#"
+image: foo
+docker_switches:
+  - --rm
+overrides:
+  linux:
+    docker_switches:
+      - --network=host
+  macos:
+    forward_user: true
+"#;
+        let actual =
+            FlokiConfig::from_strs_for_host(&[yaml.into()], "linux", "x86_64", None).unwrap();
+        assert_eq!(actual.docker_switches, vec!["--network=host".to_string()]);
+        assert!(!actual.forward_user.forward());
+        assert_eq!(actual.image, image::Image::Name("foo".into()));
+    }
+
+    #[test]
+    fn test_runtime_overrides_disable_features() {
+        let yaml = "image: foo\nforward_ssh_agent: true\ndind: true\nforward_user: true";
+        let mut config: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        RuntimeOverrides {
+            no_forward_ssh_agent: true,
+            no_dind: true,
+            no_forward_user: true,
+            ..Default::default()
+        }
+        .apply(&mut config);
+        assert!(!config.forward_ssh_agent);
+        assert_eq!(config.dind, DindConfig::deactivated());
+        assert!(!config.forward_user.forward());
+    }
+
+    #[test]
+    fn test_no_runtime_overrides() {
+        let yaml = "image: foo\nforward_ssh_agent: true\ndind: true\nforward_user: true";
+        let mut config: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        RuntimeOverrides::default().apply(&mut config);
+        assert!(config.forward_ssh_agent);
+        assert_eq!(config.dind, DindConfig::Toggle(true));
+        assert!(config.forward_user.forward());
+    }
+
+    const PROFILES: &str = r#"
+image: debian:sid
+shell: bash
+docker_switches:
+  - --rm
+profiles:
+  ci:
+    image: registry.example.com/debian:sid
+  dev:
+    forward_user: true
+"#;
+
+    #[test]
+    fn test_profile_overrides_image() {
+        let actual =
+            FlokiConfig::from_strs_for_host(&[PROFILES.into()], "linux", "x86_64", Some("ci"))
+                .unwrap();
+        assert_eq!(
+            actual.image,
+            image::Image::Name("registry.example.com/debian:sid".into())
+        );
+        // Fields the profile doesn't set are left alone
+        assert_eq!(actual.shell, Some(Shell::Shell("bash".into())));
+        assert_eq!(actual.docker_switches, vec!["--rm".to_string()]);
+        assert!(!actual.forward_user.forward());
+    }
+
+    #[test]
+    fn test_profiles_ignored_unless_selected() {
+        let actual =
+            FlokiConfig::from_strs_for_host(&[PROFILES.into()], "linux", "x86_64", None).unwrap();
+        assert_eq!(actual.image, image::Image::Name("debian:sid".into()));
+        assert!(!actual.forward_user.forward());
+    }
+
+    #[test]
+    fn test_profile_provenance() {
+        let sources = provenance(&[PROFILES.into()], "linux", "x86_64", Some("ci")).unwrap();
+        assert_eq!(
+            sources["image"],
+            Source::Profile {
+                file: 0,
+                name: "ci".into()
+            }
+        );
+        assert_eq!(sources["shell"], Source::File(0));
+        assert!(!sources.contains_key("forward_user"));
+    }
+
+    #[test]
+    fn test_provenance_across_files_and_overrides() {
+        let base = "image: foo\nshell: bash\noverrides:\n  linux:\n    mount: /linux";
+        let local = "shell: zsh";
+        let sources = provenance(&[base.into(), local.into()], "linux", "x86_64", None).unwrap();
+        assert_eq!(sources["image"], Source::File(0));
+        assert_eq!(sources["shell"], Source::File(1));
+        assert_eq!(
+            sources["mount"],
+            Source::HostOverride {
+                file: 0,
+                host: "linux".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff_configs() -> Result<(), Error> {
+        let left: FlokiConfig = serde_yaml::from_str("image: foo\nshell: bash")?;
+        let right: FlokiConfig = serde_yaml::from_str("image: foo\nshell: zsh")?;
+
+        let differences = diff(&left, &right)?;
+        assert_eq!(
+            differences,
+            BTreeMap::from([("shell".to_string(), ("bash".into(), "zsh".into()))])
+        );
+        assert!(diff(&left, &left)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_nested_fields() -> Result<(), Error> {
+        let left: FlokiConfig = serde_yaml::from_str("image: foo")?;
+        let right: FlokiConfig =
+            serde_yaml::from_str("image: foo\nvolumes:\n  cache:\n    mount: /cache")?;
+
+        let differences = diff(&left, &right)?;
+        assert_eq!(
+            differences.get("volumes.cache"),
+            Some(&(
+                serde_yaml::Value::Null,
+                serde_yaml::to_value(&right.volumes["cache"])?
+            ))
+        );
+        assert_eq!(differences.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scratch_config() -> Result<(), Error> {
+        let config = FlokiConfig::scratch("debian:sid", Some("bash"))?;
+        assert_eq!(config.image, image::Image::Name("debian:sid".into()));
+        assert_eq!(config.shell, Some(Shell::Shell("bash".into())));
+        assert_eq!(config.mount, default_mount());
+        assert!(config.volumes.is_empty());
+
+        let config = FlokiConfig::scratch("debian:sid", None)?;
+        assert_eq!(config.shell, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_runtime_override_keys() {
+        let overrides = RuntimeOverrides {
+            no_dind: true,
+            mount: Some("/work".into()),
+            ..Default::default()
+        };
+        assert_eq!(overrides.keys(), vec!["dind", "mount"]);
+        assert!(RuntimeOverrides::default().keys().is_empty());
+    }
+
+    #[test]
+    fn test_describe_source() {
+        let files = vec![path::PathBuf::from("/src/floki.yaml")];
+        assert_eq!(
+            Source::Profile {
+                file: 0,
+                name: "ci".into()
+            }
+            .describe(&files),
+            "/src/floki.yaml (profile ci)"
+        );
+        assert_eq!(Source::CommandLine.describe(&files), "command line");
+    }
+
+    #[test]
+    fn test_unknown_profile() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("floki.yaml");
+        fs::write(&file, PROFILES)?;
+        let files = [file];
+
+        assert!(defines_profile(&files, "ci")?);
+        assert!(!defines_profile(&files, "prod")?);
+        let error = FlokiConfig::from_files(&files, Some("prod")).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<errors::FlokiError>(),
+            Some(errors::FlokiError::ProfileNotDefined { profile, .. }) if profile == "prod"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_overrides_ignored_on_other_hosts() {
+        let yaml = r#"
+image: foo
+docker_switches:
+  - --rm
+overrides:
+  linux:
+    docker_switches:
+      - --network=host
+  aarch64:
+    shell: bash
+"#;
+        let actual =
+            FlokiConfig::from_strs_for_host(&[yaml.into()], "macos", "x86_64", None).unwrap();
+        assert_eq!(actual.docker_switches, vec!["--rm".to_string()]);
+        assert_eq!(actual.shell, None);
+
+        let actual =
+            FlokiConfig::from_strs_for_host(&[yaml.into()], "macos", "aarch64", None).unwrap();
+        assert_eq!(actual.shell, Some(Shell::Shell("bash".into())));
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestVolumeConfig {
+        volumes: BTreeMap<String, Volume>,
+    }
+
+    #[test]
+    fn test_tmpfs_volume() {
+        let yaml = "volumes:\n  scratch:\n    tmpfs: true\n    mount: /scratch\n  cache:\n    mount: /cache";
+        let actual: TestVolumeConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual.volumes["scratch"].tmpfs);
+        assert!(!actual.volumes["cache"].tmpfs);
+    }
+
+    #[test]
+    fn test_volume_options() {
+        let yaml =
+            "volumes:\n  cache:\n    mount: /cache\n    options: [z]\n  other:\n    mount: /other";
+        let actual: TestVolumeConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual.volumes["cache"].options, vec!["z".to_string()]);
+        assert!(actual.volumes["other"].options.is_empty());
+    }
+
+    #[test]
+    fn test_volume_setup_once() {
+        let yaml = "volumes:\n  cache:\n    mount: /cache\n    setup_once:\n      - cargo fetch";
+        let actual: TestVolumeConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual.volumes["cache"].setup_once, vec!["cargo fetch"]);
+    }
+
+    #[test]
+    fn test_default_dind_image() {
+        assert_eq!(FlokiDefaults::default().dind_image(), "docker:stable-dind");
+
+        let defaults: FlokiDefaults =
+            serde_yaml::from_str("dind_image: mirror.internal/docker:dind").unwrap();
+        assert_eq!(defaults.dind_image(), "mirror.internal/docker:dind");
+    }
+
+    #[test]
+    fn test_missing_defaults_file() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(
+            FlokiDefaults::from_file(&dir.path().join("defaults.yaml"))?,
+            FlokiDefaults::default()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tty() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\ntty: false").unwrap();
+        assert_eq!(actual.tty, Some(false));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.tty, None);
+    }
+
+    #[test]
+    fn test_read_only_rootfs() {
+        let actual: FlokiConfig =
+            serde_yaml::from_str("image: foo\nread_only_rootfs: true").unwrap();
+        assert!(actual.read_only_rootfs);
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert!(!actual.read_only_rootfs);
+    }
+
+    #[test]
+    fn test_dedupe_docker_switches() {
+        let actual: FlokiConfig =
+            serde_yaml::from_str("image: foo\ndedupe_docker_switches: true").unwrap();
+        assert!(actual.dedupe_docker_switches);
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert!(!actual.dedupe_docker_switches);
+    }
+
+    #[test]
+    fn test_cleanup_orphans() {
+        let actual: FlokiConfig =
+            serde_yaml::from_str("image: foo\ncleanup_orphans: true").unwrap();
+        assert!(actual.cleanup_orphans);
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert!(!actual.cleanup_orphans);
+    }
+
+    #[test]
+    fn test_runtime() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\nruntime: podman").unwrap();
+        assert_eq!(actual.runtime, Runtime::Podman);
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.runtime, Runtime::Docker);
+    }
+
+    #[test]
+    fn test_suppressed_entrypoint_per_runtime() {
+        let entrypoint = default_entrypoint();
+        let value = entrypoint.value().unwrap();
+        assert_eq!(
+            Runtime::Docker.entrypoint_switches(value),
+            vec!["--entrypoint", ""]
+        );
+        assert_eq!(
+            Runtime::Podman.entrypoint_switches(value),
+            vec!["--entrypoint="]
+        );
+    }
+
+    #[test]
+    fn test_pull_policy() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\npull_policy: never").unwrap();
+        assert_eq!(actual.pull_policy, Some(PullPolicy::Never));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.pull_policy, None);
+    }
+
+    #[test]
+    fn test_registry_auth() {
+        let yaml = "image: foo\nregistry_auth:\n  registry: quay.io\n  username: me\n  password_keychain:\n    service: quay.io\n    account: me";
+        let actual: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            actual.registry_auth,
+            Some(RegistryAuth {
+                registry: Some("quay.io".into()),
+                username: "me".into(),
+                password_env: None,
+                password_keychain: Some(KeychainEntry {
+                    service: "quay.io".into(),
+                    account: "me".into(),
+                }),
+            })
+        );
+
+        let yaml = "image: foo\nregistry_auth:\n  username: me\n  password_env: REGISTRY_PASSWORD";
+        let actual: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        let auth = actual.registry_auth.unwrap();
+        assert_eq!(auth.registry, None);
+        assert_eq!(auth.password_env, Some("REGISTRY_PASSWORD".into()));
+    }
+
+    #[test]
+    fn test_shell_rc() {
+        let actual: FlokiConfig =
+            serde_yaml::from_str("image: foo\nshell_rc: scripts/floki.rc").unwrap();
+        assert_eq!(actual.shell_rc, Some("scripts/floki.rc".into()));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.shell_rc, None);
+    }
+
+    #[test]
+    fn test_optional_settings() -> Result<(), Error> {
+        let set: FlokiConfig = serde_yaml::from_str(
+            "image: foo
+mac_address: 02:42:ac:11:00:02
+stop_signal: SIGINT
+userns: host
+cgroup_parent: /ci/jobs
+cgroupns: host
+volume_key: project
+docker_context: remote
+session_timeout: 3600
+label_start_time: true",
+        )?;
+        let unset: FlokiConfig = serde_yaml::from_str("image: foo")?;
+        let (set, unset) = (serde_json::to_value(set)?, serde_json::to_value(unset)?);
+
+        for (field, value, default) in [
+            ("mac_address", json!("02:42:ac:11:00:02"), json!(null)),
+            ("stop_signal", json!("SIGINT"), json!(null)),
+            ("userns", json!("host"), json!(null)),
+            ("cgroup_parent", json!("/ci/jobs"), json!(null)),
+            ("cgroupns", json!("host"), json!(null)),
+            ("volume_key", json!("project"), json!(null)),
+            ("docker_context", json!("remote"), json!(null)),
+            ("session_timeout", json!(3600), json!(null)),
+            ("label_start_time", json!(true), json!(false)),
+        ] {
+            assert_eq!(set[field], value, "{} when set", field);
+            assert_eq!(unset[field], default, "{} by default", field);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_isolation() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\nisolation: hyperv").unwrap();
+        assert_eq!(actual.isolation, Some(Isolation::Hyperv));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.isolation, None);
+
+        let actual: Result<FlokiConfig, _> = serde_yaml::from_str("image: foo\nisolation: vm");
+        assert!(actual.is_err());
+    }
+
+    #[test]
+    fn test_invalid_cgroupns() {
+        let actual: Result<FlokiConfig, _> = serde_yaml::from_str("image: foo\ncgroupns: shared");
+        assert!(actual.is_err());
+    }
+
+    #[test]
+    fn test_outer_workdir() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\nouter_workdir: out").unwrap();
+        assert_eq!(actual.outer_workdir, Some("out".into()));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.outer_workdir, None);
+    }
+
+    #[test]
+    fn test_on_exit() {
+        let actual: FlokiConfig = serde_yaml::from_str(
+            "image: foo\non_exit:\n  - command: chown\n    args: [-R, me, target]",
+        )
+        .unwrap();
+        assert_eq!(
+            actual.on_exit,
+            vec![CommandSpec {
+                command: "chown".into(),
+                args: vec!["-R".into(), "me".into(), "target".into()],
+                env: BTreeMap::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_host_gateway_alias() {
+        let actual: FlokiConfig =
+            serde_yaml::from_str("image: foo\nhost_gateway_alias: host.docker.internal").unwrap();
+        assert_eq!(
+            actual.host_gateway_alias,
+            Some("host.docker.internal".into())
+        );
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.host_gateway_alias, None);
+    }
+
+    #[test]
+    fn test_devices() {
+        let actual: FlokiConfig =
+            serde_yaml::from_str("image: foo\ndevices:\n  - /dev/ttyUSB0:/dev/ttyUSB0:rwm")
+                .unwrap();
+        assert_eq!(actual.devices, vec!["/dev/ttyUSB0:/dev/ttyUSB0:rwm"]);
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert!(actual.devices.is_empty());
+    }
+
+    #[test]
+    fn test_mounts() {
+        let actual: FlokiConfig =
+            serde_yaml::from_str("image: foo\nmounts:\n  - type=volume,source=cache,target=/cache")
+                .unwrap();
+        assert_eq!(
+            actual.mounts,
+            vec!["type=volume,source=cache,target=/cache"]
+        );
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert!(actual.mounts.is_empty());
+    }
+
+    #[test]
+    fn test_forward_user_toggle() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\nforward_user: true").unwrap();
+        assert_eq!(actual.forward_user, ForwardUser::Toggle(true));
+        assert!(actual.forward_user.forward());
+        assert!(!actual.forward_user.map_names());
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert!(!actual.forward_user.forward());
+    }
+
+    #[test]
+    fn test_forward_user_options() {
+        let yaml = "image: foo\nforward_user:\n  map_names: true";
+        let actual: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            actual.forward_user,
+            ForwardUser::Options { map_names: true }
+        );
+        assert!(actual.forward_user.forward());
+        assert!(actual.forward_user.map_names());
+    }
+
+    #[test]
+    fn test_shm_size() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\nshm_size: 512m").unwrap();
+        assert_eq!(actual.shm_size, Some("512m".into()));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.shm_size, None);
+    }
+
+    #[test]
+    fn test_merge_overrides_scalars() {
+        let base = "image: foo\nshell: bash\nforward_user: true";
+        let local = "image: bar\nshell: zsh";
+        let actual =
+            FlokiConfig::from_strs_for_host(&[base.into(), local.into()], "linux", "x86_64", None)
+                .unwrap();
+        assert_eq!(actual.image, image::Image::Name("bar".into()));
+        assert_eq!(actual.shell, Some(Shell::Shell("zsh".into())));
+        assert!(actual.forward_user.forward());
+    }
+
+    #[test]
+    fn test_merge_replaces_image() {
+        let base = "image:\n  build:\n    name: foo\n    dockerfile: Dockerfile";
+        let local = "image:\n  exec:\n    command: ./build.sh\n    image: foo:latest";
+        let actual =
+            FlokiConfig::from_strs_for_host(&[base.into(), local.into()], "linux", "x86_64", None)
+                .unwrap();
+        assert!(matches!(actual.image, image::Image::Exec { .. }));
+        assert_eq!(actual.image.name().unwrap(), "foo:latest");
+    }
+
+    #[test]
+    fn test_merge_replaces_lists() {
+        let base = "image: foo\ninit:\n  - echo base\nvolumes:\n  cache:\n    mount: /cache";
+        let local = "init:\n  - echo local\nvolumes:\n  cache:\n    shared: true";
+        let actual =
+            FlokiConfig::from_strs_for_host(&[base.into(), local.into()], "linux", "x86_64", None)
+                .unwrap();
+        assert_eq!(actual.init, vec!["echo local".to_string()]);
+        assert!(actual.volumes["cache"].shared);
+        assert_eq!(actual.volumes["cache"].mount, path::PathBuf::from("/cache"));
+    }
+
+    #[test]
+    fn test_forward_env_prefix() {
+        let yaml = "image: foo\nforward_env_prefixes:\n  - AWS_\nforward_env_prefix: HOST_";
+        let actual: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual.forward_env_prefix, Some("HOST_".into()));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.forward_env_prefix, None);
+    }
+
+    #[test]
+    fn test_keep_env_clean() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\nkeep_env_clean: true").unwrap();
+        assert!(actual.keep_env_clean);
+
+        let mut actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert!(!actual.keep_env_clean);
+
+        let overrides = RuntimeOverrides {
+            keep_env_clean: true,
+            ..Default::default()
+        };
+        overrides.apply(&mut actual);
+        assert!(actual.keep_env_clean);
+        assert_eq!(overrides.keys(), vec!["keep_env_clean"]);
+    }
+
+    #[test]
+    fn test_locale() {
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo\nlocale: C.UTF-8").unwrap();
+        assert_eq!(actual.locale, Some("C.UTF-8".into()));
+
+        let actual: FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        assert_eq!(actual.locale, None);
+    }
+
+    #[test]
+    fn test_forward_env_prefixes() {
+        let yaml = "image: foo\nforward_env_prefixes:\n  - AWS_\n  - CARGO_";
+        let actual: FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual.forward_env_prefixes, vec!["AWS_", "CARGO_"]);
+    }
 }
@@ -1,22 +1,36 @@
 /// Docker-in-docker structures
 use anyhow::Error;
 use std::path;
+use std::time::Duration;
 
 use crate::command::{DaemonHandle, DockerCommandBuilder};
 use crate::image::{image_exists_locally, pull_image};
 
+/// How long to wait for the dind container to become ready
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct Dind {
     command: DockerCommandBuilder,
 }
 
 impl Dind {
-    pub fn new(image: &str, mount: (&path::PathBuf, &path::PathBuf)) -> Self {
-        Dind {
-            command: DockerCommandBuilder::new(image)
-                .add_docker_switch("--privileged")
-                .add_volume(mount),
+    pub fn new(
+        image: &str,
+        mount: (&path::PathBuf, &path::PathBuf),
+        healthcheck: Option<&str>,
+    ) -> Self {
+        let mut command = DockerCommandBuilder::new(image)
+            .add_docker_switch("--privileged")
+            .add_volume(mount);
+        if let Some(healthcheck) = healthcheck {
+            command = command
+                .add_docker_switch("--health-cmd")
+                .add_docker_switch(healthcheck)
+                .add_docker_switch("--health-interval")
+                .add_docker_switch("1s");
         }
+        Dind { command }
     }
 
     pub fn name(&self) -> &str {
@@ -31,6 +45,7 @@ impl Dind {
         let handle = self
             .command
             .start_as_daemon(&["dockerd", "--host=tcp://0.0.0.0:2375"])?;
+        handle.wait_until_ready(READY_TIMEOUT)?;
         info!("docker:dind launched");
         Ok(handle)
     }
@@ -41,6 +56,6 @@ pub fn dind_preflight(image: &str) -> Result<(), Error> {
     if image_exists_locally(image)? {
         Ok(())
     } else {
-        pull_image(image)
+        pull_image(image, false)
     }
 }
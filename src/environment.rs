@@ -1,4 +1,5 @@
 /// Query the current user environment
+use crate::config::FlokiDefaults;
 use crate::errors;
 use anyhow::Error;
 use std::env;
@@ -39,27 +40,69 @@ pub struct Environment {
     /// The host folder that floki uses to e.g. create directories
     /// to back volumes
     pub floki_workspace: path::PathBuf,
+    /// Names of the environment variables set on the host
+    pub host_variables: Vec<String>,
+    /// Host wide defaults
+    pub defaults: FlokiDefaults,
+    /// Whether floki's stdin is a terminal
+    pub interactive: bool,
 }
 
 impl Environment {
     /// Gather information on the environment floki is running in
     pub fn gather(config_file: &Option<path::PathBuf>) -> Result<Self, Error> {
         let (floki_root, config_path) = resolve_floki_root_and_config(config_file)?;
+        Self::gather_at(floki_root, normalize_path(config_path)?)
+    }
+
+    /// Gather information on the environment for a run without a
+    /// configuration file - the current directory stands in for it
+    pub fn gather_without_config() -> Result<Self, Error> {
+        let current_directory = get_current_working_directory()?;
+        Self::gather_at(current_directory.clone(), current_directory)
+    }
+
+    fn gather_at(floki_root: path::PathBuf, config_file: path::PathBuf) -> Result<Self, Error> {
         let user = User::current();
+        let floki_workspace = get_floki_work_path(user.uid);
+        let defaults = FlokiDefaults::from_file(&floki_workspace.join("defaults.yaml"))?;
 
         let env = Environment {
             user_details: user,
             current_directory: get_current_working_directory()?,
             floki_root,
-            config_file: normalize_path(config_path)?,
+            config_file,
             ssh_agent_socket: get_ssh_agent_socket_path(),
-            floki_workspace: get_floki_work_path(user.uid),
+            floki_workspace,
+            host_variables: get_host_variables(),
+            defaults,
+            interactive: nix::unistd::isatty(0).unwrap_or(false),
         };
 
         debug!("Got environment {:?}", &env);
 
         Ok(env)
     }
+
+    /// A fixed environment for tests: floki run by uid 1000 in /host/src,
+    /// with the configuration at /host/src/floki.yaml
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Environment {
+            user_details: User {
+                uid: nix::unistd::Uid::from_raw(1000),
+                gid: nix::unistd::Gid::from_raw(100),
+            },
+            current_directory: "/host/src".into(),
+            floki_root: "/host/src".into(),
+            config_file: "/host/src/floki.yaml".into(),
+            ssh_agent_socket: None,
+            floki_workspace: "/host/.floki".into(),
+            host_variables: vec![],
+            defaults: Default::default(),
+            interactive: false,
+        }
+    }
 }
 
 /// Get the current working directory as a String
@@ -73,24 +116,102 @@ fn get_ssh_agent_socket_path() -> Option<OsString> {
     env::var_os("SSH_AUTH_SOCK")
 }
 
-/// Search all ancestors of the current directory for a floki.yaml file name.
-fn find_floki_yaml(current_directory: &path::Path) -> Result<path::PathBuf, Error> {
+/// Find the floki configuration files in a directory - a floki.yaml
+/// and any yaml files in a .floki directory. The floki workspace (e.g.
+/// ~/.floki) isn't a .floki directory of configurations, whatever yaml
+/// files are kept in it.
+fn config_candidates_in(
+    directory: &path::Path,
+    floki_workspace: &path::Path,
+) -> Vec<path::PathBuf> {
+    let floki_directory = directory.join(".floki");
+    let mut candidates: Vec<path::PathBuf> = if same_directory(&floki_directory, floki_workspace) {
+        Vec::new()
+    } else {
+        floki_directory
+            .read_dir()
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|f| f.is_file() && f.extension() == Some("yaml".as_ref()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    candidates.sort();
+
+    let floki_yaml = directory.join("floki.yaml");
+    if floki_yaml.is_file() {
+        candidates.insert(0, floki_yaml);
+    }
+    candidates
+}
+
+/// Whether two paths are the same directory, however they're written
+fn same_directory(a: &path::Path, b: &path::Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Get the names of the environment variables that are set. Variables
+/// with names which aren't unicode are skipped.
+fn get_host_variables() -> Vec<String> {
+    env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .collect()
+}
+
+/// Search all ancestors of the current directory for floki configuration
+/// files, returning the nearest directory containing any, and the files.
+fn find_config_candidates(
+    current_directory: &path::Path,
+    floki_workspace: &path::Path,
+) -> Result<(path::PathBuf, Vec<path::PathBuf>), Error> {
     current_directory
         .ancestors()
-        .map(|a| a.join("floki.yaml"))
-        .find(|f| f.is_file())
+        .map(|a| (a.to_path_buf(), config_candidates_in(a, floki_workspace)))
+        .find(|(_, candidates)| !candidates.is_empty())
         .ok_or_else(|| errors::FlokiError::ProblemFindingConfigYaml {}.into())
 }
 
-/// Take a file path, and return a tuple consisting of its parent directory and the file path
-fn locate_file_in_parents(path: path::PathBuf) -> Result<(path::PathBuf, path::PathBuf), Error> {
-    let dir = path
-        .parent()
-        .ok_or_else(|| errors::FlokiInternalError::InternalAssertionFailed {
-            description: format!("config_file '{:?}' does not have a parent", &path),
-        })?
-        .to_path_buf();
-    Ok((dir, path))
+/// Choose a configuration file from the candidates. A single candidate
+/// is always chosen, otherwise a selection is needed (e.g. from a
+/// prompt), and without one it is an error listing the candidates.
+pub fn select_config(
+    mut candidates: Vec<path::PathBuf>,
+    selection: Option<usize>,
+) -> Result<path::PathBuf, Error> {
+    match selection {
+        _ if candidates.len() == 1 => Ok(candidates.remove(0)),
+        Some(index) if index < candidates.len() => Ok(candidates.remove(index)),
+        _ => Err(errors::FlokiError::MultipleConfigsFound {
+            candidates: candidates
+                .iter()
+                .map(|c| format!("'{}'", c.display()))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+        .into()),
+    }
+}
+
+/// Search all ancestors of the current directory for a floki configuration
+/// file, returning the directory it was found in and the file. When there
+/// are several candidates, the user is asked which to use if they can be.
+fn find_floki_yaml(
+    current_directory: &path::Path,
+) -> Result<(path::PathBuf, path::PathBuf), Error> {
+    let (directory, candidates) =
+        find_config_candidates(current_directory, &current_floki_workspace())?;
+    let selection = if candidates.len() > 1 {
+        let names: Vec<_> = candidates.iter().map(|c| c.display().to_string()).collect();
+        crate::prompt::choose("Several configuration files were found:", &names)
+    } else {
+        None
+    };
+    Ok((directory, select_config(candidates, selection)?))
 }
 
 /// Resolve floki root directory and path to configuration file. The floki root directory
@@ -101,12 +222,28 @@ fn resolve_floki_root_and_config(
 ) -> Result<(path::PathBuf, path::PathBuf), Error> {
     match config_file {
         Some(path) => Ok((get_current_working_directory()?, path.clone())),
-        None => Ok(locate_file_in_parents(find_floki_yaml(
-            &get_current_working_directory()?,
-        )?)?),
+        None => find_floki_yaml(&get_current_working_directory()?),
     }
 }
 
+/// The absolute path of the configuration file floki would use
+pub fn resolve_config_path(config_file: &Option<path::PathBuf>) -> Result<path::PathBuf, Error> {
+    match config_file {
+        Some(path) => normalize_path(path.clone()),
+        None => nearest_config_path(&get_current_working_directory()?),
+    }
+}
+
+/// The absolute path of the nearest configuration file to a directory
+fn nearest_config_path(current_directory: &path::Path) -> Result<path::PathBuf, Error> {
+    normalize_path(find_floki_yaml(current_directory)?.1)
+}
+
+/// The floki workspace of the current user
+pub fn current_floki_workspace() -> path::PathBuf {
+    get_floki_work_path(nix::unistd::getuid())
+}
+
 /// Resolve a directory for floki to use for user-global file (caches etc)
 fn get_floki_work_path(uid: nix::unistd::Uid) -> path::PathBuf {
     let root: path::PathBuf = env::var("HOME").unwrap_or(format!("/tmp/{}/", uid)).into();
@@ -137,7 +274,11 @@ mod test {
             path.parent()
                 .ok_or_else(|| anyhow!("Unable to take parent of path"))?,
         )?;
-        fs::OpenOptions::new().create(true).write(true).open(path)?;
+        fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path)?;
         Ok(())
     }
 
@@ -146,7 +287,10 @@ mod test {
         let tmp_dir = tempfile::TempDir::new()?;
         let floki_yaml_path = tmp_dir.path().join("floki.yaml");
         touch_file(&floki_yaml_path)?;
-        assert_eq!(find_floki_yaml(tmp_dir.path())?, floki_yaml_path);
+        assert_eq!(
+            find_floki_yaml(tmp_dir.path())?,
+            (tmp_dir.path().to_path_buf(), floki_yaml_path)
+        );
         Ok(())
     }
 
@@ -157,11 +301,24 @@ mod test {
         touch_file(&floki_yaml_path)?;
         assert_eq!(
             find_floki_yaml(&tmp_dir.path().join("dir/subdir"))?,
-            floki_yaml_path
+            (tmp_dir.path().to_path_buf(), floki_yaml_path)
         );
         Ok(())
     }
 
+    #[test]
+    fn test_nearest_config_path() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        touch_file(&tmp_dir.path().join("floki.yaml"))?;
+        let nearest = tmp_dir.path().join("project/floki.yaml");
+        touch_file(&nearest)?;
+
+        let path = nearest_config_path(&tmp_dir.path().join("project/src/module"))?;
+        assert!(path.is_absolute());
+        assert_eq!(path, fs::canonicalize(&nearest)?);
+        Ok(())
+    }
+
     #[test]
     fn test_find_floki_yaml_sibling() -> Result<(), Error> {
         let tmp_dir = tempfile::TempDir::new()?;
@@ -170,4 +327,62 @@ mod test {
         assert!(find_floki_yaml(&tmp_dir.path().join("include")).is_err());
         Ok(())
     }
+
+    #[test]
+    fn test_find_floki_yaml_in_floki_directory() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let floki_yaml_path = tmp_dir.path().join(".floki/dev.yaml");
+        touch_file(&floki_yaml_path)?;
+        assert_eq!(
+            find_floki_yaml(&tmp_dir.path().join("dir"))?,
+            (tmp_dir.path().to_path_buf(), floki_yaml_path)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_floki_yaml_multiple_candidates() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let candidates = vec![
+            tmp_dir.path().join("floki.yaml"),
+            tmp_dir.path().join(".floki/ci.yaml"),
+            tmp_dir.path().join(".floki/dev.yaml"),
+        ];
+        for candidate in &candidates {
+            touch_file(candidate)?;
+        }
+
+        let message = find_floki_yaml(tmp_dir.path()).unwrap_err().to_string();
+        for candidate in &candidates {
+            assert!(message.contains(&candidate.display().to_string()));
+        }
+
+        let (_, found) = find_config_candidates(tmp_dir.path(), &tmp_dir.path().join("home"))?;
+        assert_eq!(found, candidates);
+        assert_eq!(select_config(found, Some(2))?, candidates[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_floki_workspace_isnt_searched() -> Result<(), Error> {
+        // The host defaults in ~/.floki aren't a configuration for
+        // everything under the home directory
+        let home = tempfile::TempDir::new()?;
+        touch_file(&home.path().join(".floki/defaults.yaml"))?;
+        fs::create_dir_all(home.path().join("work"))?;
+
+        let workspace = home.path().join(".floki");
+        assert!(matches!(
+            find_config_candidates(&home.path().join("work"), &workspace)
+                .unwrap_err()
+                .downcast_ref::<errors::FlokiError>(),
+            Some(errors::FlokiError::ProblemFindingConfigYaml {})
+        ));
+
+        // Configurations in the home directory itself are still found
+        touch_file(&home.path().join("floki.yaml"))?;
+        let (_, found) = find_config_candidates(&home.path().join("work"), &workspace)?;
+        assert_eq!(found, vec![home.path().join("floki.yaml")]);
+        Ok(())
+    }
 }
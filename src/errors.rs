@@ -16,6 +16,9 @@ pub enum FlokiError {
     #[error("No floki.yaml found in tree")]
     ProblemFindingConfigYaml {},
 
+    #[error("Found multiple configuration files - choose one with --config: {candidates}")]
+    MultipleConfigsFound { candidates: String },
+
     #[error("Could not normalize the file path '{name}': {error:?}")]
     ProblemNormalizingFilePath { name: String, error: io::Error },
 
@@ -40,25 +43,195 @@ pub enum FlokiError {
         exit_status: FlokiSubprocessExitStatus,
     },
 
-    #[error("Failed to build docker image '{image}': {exit_status}")]
+    #[error(
+        "The session ran for longer than its timeout of {seconds}s, so the container was killed"
+    )]
+    SessionTimedOut { seconds: u64 },
+
+    #[error("Failed to load OCI layout '{layout}' as image '{image}': {exit_status}")]
+    FailedToLoadImage {
+        layout: String,
+        image: String,
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
+    #[error(
+        "Failed to build docker image '{image}': {exit_status}{}",
+        captured_output(output)
+    )]
     FailedToBuildImage {
         image: String,
         exit_status: FlokiSubprocessExitStatus,
+        output: Option<String>,
+    },
+
+    #[error("The downloaded build context from {url} doesn't match context_sha256 - expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error(
+        "The build of '{image}' has a context_sha256, but only remote tarball contexts can be checked"
+    )]
+    ChecksumNeedsTarballContext { image: String },
+
+    #[error("The build of '{image}' has annotations, which need buildx - set buildx: true")]
+    AnnotationsNeedBuildx { image: String },
+
+    #[error("The build of '{image}' sets quiet_build and {option}, which can't be used together")]
+    QuietBuildConflict { image: String, option: String },
+
+    #[error("Running command '{command}' failed with error: {error:?}")]
+    FailedToRunCommand { command: String, error: io::Error },
+
+    #[error("Command failed: {exit_status}{}", captured_output(output))]
+    CommandFailed {
+        exit_status: FlokiSubprocessExitStatus,
+        output: Option<String>,
     },
 
     #[error("Failed to check existence of image '{image}': {error:?}")]
     FailedToCheckForImage { image: String, error: io::Error },
 
+    #[error("No profile named '{profile}' is defined in {files}")]
+    ProfileNotDefined { profile: String, files: String },
+
     #[error("Running container failed: {exit_status:?}")]
     RunContainerFailed {
         exit_status: FlokiSubprocessExitStatus,
     },
 
+    #[error("Failed to remove orphaned floki containers: {exit_status}")]
+    FailedToRemoveOrphans {
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
     #[error("Unable to forward ssh socket - cannot find SSH_AUTH_SOCK in environment - do you have an ssh agent running?")]
     NoSshAuthSock {},
 
     #[error("Malformed item in docker_switches: {item}")]
     MalformedDockerSwitch { item: String },
+
+    #[error("Invalid umask '{value}' - expected an octal mode such as 022")]
+    InvalidUmask { value: String },
+
+    #[error("Label file '{name}' does not exist")]
+    LabelFileNotFound { name: String },
+
+    #[error("Shell rc file '{name}' does not exist")]
+    ShellRcNotFound { name: String },
+
+    #[error("Invalid {field} '{value}' - expected a size such as 512m or 2g")]
+    InvalidSize { field: String, value: String },
+
+    #[error("Invalid stop_signal '{value}' - expected a signal name such as SIGTERM, or a number")]
+    InvalidStopSignal { value: String },
+
+    #[error("Invalid oom_score_adj {value} - expected a value from -1000 to 1000")]
+    InvalidOomScoreAdj { value: i64 },
+
+    #[error("Invalid userns '{value}' - expected host, or \"\" for the daemon's default")]
+    InvalidUserns { value: String },
+
+    #[error("Invalid mac_address '{value}' - expected six pairs of hex digits separated by colons, such as 02:42:ac:11:00:02")]
+    InvalidMacAddress { value: String },
+
+    #[error("Refusing to forward environment variable '{name}' - it isn't in the allowlist")]
+    EnvVariableNotAllowed { name: String },
+
+    #[error("Invalid mount '{spec}' - mounts must start with type=, e.g. type=volume,source=cache,target=/cache")]
+    InvalidMount { spec: String },
+
+    #[error("Invalid device '{spec}' - devices must start with the absolute path of the host device, e.g. /dev/ttyUSB0:/dev/ttyUSB0:rwm")]
+    InvalidDevice { spec: String },
+
+    #[error("Volume '{volume}' is a tmpfs volume, so it can't use {option} - tmpfs volumes belong to a single container")]
+    TmpfsVolumeConflict { volume: String, option: String },
+
+    #[error("Invalid option '{option}' for volume '{volume}' - options can't be empty or contain ':' or ','")]
+    InvalidVolumeOption { volume: String, option: String },
+
+    #[error("{first} and {second} are both mounted at {mount} - give them different mount paths")]
+    MountPathCollision {
+        mount: String,
+        first: String,
+        second: String,
+    },
+
+    #[error("Image '{image}' isn't available locally, and pull_policy is never. Pull it first with `docker pull`, or change the pull_policy")]
+    ImageNotPresentLocally { image: String },
+
+    #[error("There is no service '{service}' in the compose file {file}")]
+    ComposeServiceNotFound { file: String, service: String },
+
+    #[error("The service '{service}' in the compose file {file} has neither an image nor a build")]
+    ComposeServiceHasNoImage { file: String, service: String },
+
+    #[error("The image of {config} refers back to itself through other configurations")]
+    ImageReferenceCycle { config: String },
+
+    #[error("There is no running container named '{name}' - find running floki containers with `docker ps --filter label=floki=true`")]
+    ContainerNotRunning { name: String },
+
+    #[error("Container '{name}' didn't become ready - its state is '{state}'")]
+    ContainerNotReady { name: String, state: String },
+
+    #[error("Nothing was removed, as it wasn't confirmed - pass --yes to skip confirmation")]
+    NotConfirmed {},
+
+    #[error("The response from {url} has no {header} header")]
+    MissingResponseHeader { url: String, header: String },
+
+    #[error("The response from {url} is larger than the maximum of {max_size} bytes - check the url, or raise max_size")]
+    ResponseTooLarge { url: String, max_size: u64 },
+
+    #[error("Unknown function '{function}' in configuration - use env, file or cmd")]
+    UnknownTemplateFunction { function: String },
+
+    #[error("Unterminated function call in configuration value '{value}' - expected a closing }}")]
+    UnterminatedTemplate { value: String },
+
+    #[error("Environment variable '{name}' used in configuration isn't set")]
+    TemplateVariableNotSet { name: String },
+
+    #[error("Couldn't read file '{file}' used in configuration: {error:?}")]
+    TemplateFileUnreadable { file: String, error: io::Error },
+
+    #[error("Command used in configuration failed: {exit_status}")]
+    TemplateCommandFailed {
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
+    #[error("docker_context can't be empty - remove it to use the current docker context")]
+    InvalidDockerContext {},
+
+    #[error("Failed to log in to {registry}: {exit_status}")]
+    FailedToLogIn {
+        registry: String,
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
+    #[error("The password for {registry} should be in environment variable {variable}, but it isn't set")]
+    RegistryPasswordNotSet { registry: String, variable: String },
+
+    #[error("The password for {registry} should be in keychain entry {service}/{account}, but there's no such entry")]
+    RegistryPasswordNotInKeychain {
+        registry: String,
+        service: String,
+        account: String,
+    },
+
+    #[error(
+        "registry_auth for {registry} has no password - set password_env or password_keychain"
+    )]
+    RegistryPasswordMissing { registry: String },
+
+    #[error(
+        "registry_auth for {registry} sets both password_env and password_keychain - use only one"
+    )]
+    RegistryPasswordConflict { registry: String },
 }
 
 /// Generate a summary string for a process exiting
@@ -69,6 +242,14 @@ fn exit_code_diagnosis(exit_status: &ExitStatus) -> String {
     }
 }
 
+/// Format the captured output of a failed process, if there is any
+fn captured_output(output: &Option<String>) -> String {
+    match output {
+        Some(output) if !output.trim().is_empty() => format!(":\n{}", output.trim_end()),
+        _ => "".into(),
+    }
+}
+
 /// Custom debug formatter for FlokiSubprocessExitStatus
 impl fmt::Debug for FlokiSubprocessExitStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -106,4 +287,9 @@ pub enum FlokiInternalError {
 pub enum FlokiUserError {
     #[error("Invalid verbosity setting of {setting:?}. Use a setting between 0 and 3 (-vvv)")]
     InvalidVerbositySetting { setting: u8 },
+
+    #[error(
+        "--dry-run only works with `floki pull` and --pull-only, which don't start a container"
+    )]
+    DryRunNeedsPull {},
 }
@@ -0,0 +1,93 @@
+/// Progress events, emitted as floki works for tooling to follow
+use serde::Serialize;
+use std::io::Write;
+
+/// Something floki has started or finished doing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    PullStarted { image: String },
+    PullFinished { image: String },
+    BuildStarted { image: String },
+    ContainerStarted { image: String },
+    ContainerExited { code: i32 },
+}
+
+/// Somewhere to send events
+pub(crate) trait Emitter {
+    fn emit(&mut self, event: Event);
+
+    /// Whether anything is done with the events - work done only so it can
+    /// be reported is skipped when not
+    fn listening(&self) -> bool {
+        true
+    }
+}
+
+/// Drops every event, for when nobody is listening
+pub(crate) struct Discard;
+
+impl Emitter for Discard {
+    fn emit(&mut self, _event: Event) {}
+
+    fn listening(&self) -> bool {
+        false
+    }
+}
+
+/// Keeps every event
+impl Emitter for Vec<Event> {
+    fn emit(&mut self, event: Event) {
+        self.push(event);
+    }
+}
+
+/// Writes each event as a line of JSON
+pub(crate) struct JsonLines<W: Write>(pub(crate) W);
+
+impl<W: Write> Emitter for JsonLines<W> {
+    fn emit(&mut self, event: Event) {
+        // Failing to report progress shouldn't stop the run
+        let written = serde_json::to_string(&event)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(self.0, "{}", line));
+        if let Err(e) = written {
+            warn!("Failed to write event {:?}: {}", event, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json_lines() -> Result<(), anyhow::Error> {
+        let mut emitter = JsonLines(Vec::new());
+        emitter.emit(Event::PullStarted {
+            image: "debian:sid".into(),
+        });
+        emitter.emit(Event::ContainerExited { code: 3 });
+
+        let lines = String::from_utf8(emitter.0)?;
+        let events: Vec<serde_json::Value> = lines
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(
+            events,
+            vec![
+                serde_json::json!({"event": "pull_started", "image": "debian:sid"}),
+                serde_json::json!({"event": "container_exited", "code": 3}),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_listening() {
+        assert!(!Discard.listening());
+        assert!(Vec::<Event>::new().listening());
+        assert!(JsonLines(Vec::new()).listening());
+    }
+}
@@ -1,15 +1,35 @@
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
-    env, fs,
+    collections::{BTreeMap, HashMap},
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, Read, Write},
+    os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
     process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
 };
 use url::Url;
-use yaml_rust::YamlLoader;
+use yaml_rust::{Yaml, YamlLoader};
 
+use crate::command::CommandSpec;
 use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
+use crate::events::{Emitter, Event};
+use crate::warning::Warning;
+
+static DEFAULT_REGISTRY: &str = "docker.io";
+const FETCH_BACKOFF: Duration = Duration::from_millis(500);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_FETCH_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Image names already resolved in this run, keyed by the spec they were
+/// resolved from, so that repeated lookups don't fetch again
+static RESOLVED_NAMES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct BuildSpec {
@@ -19,6 +39,631 @@ pub struct BuildSpec {
     #[serde(default = "default_context")]
     context: PathBuf,
     target: Option<String>,
+    #[serde(default = "BTreeMap::new")]
+    targets: BTreeMap<String, String>,
+    output: Option<String>,
+    #[serde(default = "Vec::new")]
+    ssh: Vec<String>,
+    #[serde(default)]
+    cache_build: bool,
+    build_host: Option<String>,
+    #[serde(default = "BTreeMap::new")]
+    build_contexts: BTreeMap<String, String>,
+    progress: Option<BuildProgress>,
+    build_log: Option<PathBuf>,
+    dockerfile_headers: Option<HashMap<String, String>>,
+    context_sha256: Option<String>,
+    #[serde(default)]
+    buildx: bool,
+    #[serde(default = "BTreeMap::new")]
+    annotations: BTreeMap<String, String>,
+    #[serde(default)]
+    quiet_build: bool,
+    #[serde(default)]
+    auto_git_build_args: bool,
+}
+
+/// How BuildKit shows the progress of a build
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildProgress {
+    Auto,
+    Plain,
+    Tty,
+}
+
+impl BuildProgress {
+    fn value(&self) -> &str {
+        match self {
+            BuildProgress::Auto => "auto",
+            BuildProgress::Plain => "plain",
+            BuildProgress::Tty => "tty",
+        }
+    }
+}
+
+/// The progress mode for a build. Without a terminal, e.g. in CI, plain
+/// progress is used unless another mode is configured, as it is much
+/// easier to read in logs.
+fn progress_mode(configured: Option<BuildProgress>, terminal: bool) -> Option<BuildProgress> {
+    match configured {
+        None if !terminal => Some(BuildProgress::Plain),
+        configured => configured,
+    }
+}
+
+impl BuildSpec {
+    /// Assemble the docker command which performs this build, using the
+    /// downloaded dockerfile and context if there are any, and showing
+    /// progress on a terminal or not
+    fn command(
+        &self,
+        name: &str,
+        floki_root: &Path,
+        downloads: &Downloads,
+        build_args: &[(String, String)],
+        terminal: bool,
+    ) -> Command {
+        self.target_command(
+            self.target.as_deref(),
+            name,
+            floki_root,
+            downloads,
+            build_args,
+            terminal,
+        )
+    }
+
+    /// The commands which build each of the additional targets, with the
+    /// tags they're given
+    fn targets_commands(
+        &self,
+        floki_root: &Path,
+        downloads: &Downloads,
+        build_args: &[(String, String)],
+        terminal: bool,
+    ) -> Vec<(&str, Command)> {
+        self.targets
+            .iter()
+            .map(|(target, tag)| {
+                let command = self.target_command(
+                    Some(target),
+                    tag,
+                    floki_root,
+                    downloads,
+                    build_args,
+                    terminal,
+                );
+                (tag.as_str(), command)
+            })
+            .collect()
+    }
+
+    /// The images a build tags - those of its additional targets, and the
+    /// image itself
+    fn built_images<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.targets
+            .values()
+            .map(String::as_str)
+            .chain(std::iter::once(name))
+    }
+
+    /// Assemble the command which builds one target of the dockerfile
+    fn target_command(
+        &self,
+        target: Option<&str>,
+        name: &str,
+        floki_root: &Path,
+        downloads: &Downloads,
+        build_args: &[(String, String)],
+        terminal: bool,
+    ) -> Command {
+        // Only the build targets this daemon, whatever the docker context
+        let mut command = match &self.build_host {
+            Some(host) => {
+                let mut command = Command::new("docker");
+                command.env("DOCKER_HOST", host);
+                command
+            }
+            None => crate::command::docker(),
+        };
+        if self.buildx {
+            command.arg("buildx");
+        }
+        command.arg("build");
+
+        // A quiet build only prints the id of the image it built
+        let progress = if self.quiet_build {
+            None
+        } else {
+            progress_mode(self.progress, terminal)
+        };
+
+        // Builds with an output export artifacts instead of an image
+        // to tag. This, ssh forwarding, named contexts and progress modes
+        // need BuildKit.
+        if self.output.is_some()
+            || !self.ssh.is_empty()
+            || !self.build_contexts.is_empty()
+            || progress.is_some()
+        {
+            command.env("DOCKER_BUILDKIT", "1");
+        }
+
+        if let Some(output) = &self.output {
+            command.arg("--output").arg(output);
+        } else {
+            command.arg("-t").arg(name);
+            // buildx only leaves the image in docker when asked to
+            if self.buildx {
+                command.arg("--load");
+            }
+        }
+
+        // With a remote context, docker finds the dockerfile in the context.
+        // A remote dockerfile isn't downloaded for a dry run, so is piped in.
+        match (
+            downloads.dockerfile(),
+            self.remote_dockerfile(),
+            self.remote_context(),
+        ) {
+            (Some(dockerfile), _, _) => command.arg("-f").arg(dockerfile),
+            (None, Some(_), _) => command.arg("-f").arg("-"),
+            (None, None, Some(_)) => command.arg("-f").arg(&self.dockerfile),
+            (None, None, None) => command.arg("-f").arg(floki_root.join(&self.dockerfile)),
+        };
+
+        for ssh in &self.ssh {
+            command.arg("--ssh").arg(ssh);
+        }
+
+        for (name, context) in &self.build_contexts {
+            let mut value = OsString::from(format!("{}=", name));
+            value.push(build_context_value(context, floki_root));
+            command.arg("--build-context").arg(value);
+        }
+
+        for (key, value) in build_args {
+            command.arg("--build-arg").arg(format!("{}={}", key, value));
+        }
+
+        for (key, value) in &self.annotations {
+            command
+                .arg("--annotation")
+                .arg(format!("{}={}", key, value));
+        }
+
+        if let Some(target) = target {
+            command.arg("--target").arg(target);
+        }
+
+        if self.quiet_build {
+            command.arg("-q");
+        } else if let Some(progress) = progress {
+            command.arg("--progress").arg(progress.value());
+        }
+
+        // A downloaded context is passed on stdin when the build is run
+        match (downloads.context(), self.remote_context()) {
+            (Some(_), _) => command.arg("-"),
+            (None, Some(url)) => command.arg(url.as_str()),
+            (None, None) => command.arg(floki_root.join(&self.context)),
+        };
+        command
+    }
+
+    /// The build context, if it's a URL (such as a git repository or a
+    /// tarball) rather than a local directory
+    fn remote_context(&self) -> Option<Url> {
+        self.context
+            .to_str()
+            .and_then(|context| Url::parse(context).ok())
+            .filter(|url| url.has_host())
+    }
+
+    /// The build context, if it's a URL of a tarball rather than a path or
+    /// a git repository. Like docker, git URLs are those with a git scheme
+    /// or whose path ends .git.
+    fn remote_tarball_context(&self) -> Option<Url> {
+        self.remote_context()
+            .filter(|url| !matches!(url.scheme(), "git" | "ssh") && !url.path().ends_with(".git"))
+    }
+
+    /// The dockerfile, if it's a URL to download rather than a path
+    fn remote_dockerfile(&self) -> Option<Url> {
+        self.dockerfile
+            .to_str()
+            .and_then(|dockerfile| Url::parse(dockerfile).ok())
+            .filter(|url| url.has_host())
+    }
+
+    /// Describe a build command for a dry run, piping in a remote
+    /// dockerfile as it wasn't downloaded. Header values are left for the
+    /// shell to expand from their environment variables.
+    fn describe_dry_run(&self, command: &Command) -> String {
+        let url = match self.remote_dockerfile() {
+            Some(url) => url,
+            None => return describe_command(command),
+        };
+        let mut curl = String::from("curl -fsSL");
+        let mut headers: Vec<_> = self.dockerfile_headers.iter().flatten().collect();
+        headers.sort();
+        for (header, variable) in headers {
+            curl.push_str(&format!(
+                " -H {}\"${{{}}}\"",
+                shlex::quote(&format!("{}: ", header)),
+                variable
+            ));
+        }
+        format!(
+            "{} {} | {}",
+            curl,
+            shlex::quote(url.as_str()),
+            describe_command(command)
+        )
+    }
+
+    /// Hash the inputs to the build - the Dockerfile, the build contexts
+    /// and every setting which changes what's built - so unchanged builds
+    /// can be skipped
+    fn input_hash(
+        &self,
+        floki_root: &Path,
+        downloaded: Option<&Path>,
+        build_args: &[(String, String)],
+    ) -> Result<String, Error> {
+        let mut hasher = Sha256::new();
+        let settings = format!(
+            "{:?}",
+            (
+                &self.target,
+                &self.targets,
+                &self.output,
+                &self.ssh,
+                &self.build_host,
+                &self.build_contexts,
+                &self.annotations,
+                self.buildx,
+                build_args,
+            )
+        );
+        hasher.update(settings.as_bytes());
+        let dockerfile = match downloaded {
+            Some(dockerfile) => dockerfile.to_path_buf(),
+            None => floki_root.join(&self.dockerfile),
+        };
+        io::copy(&mut fs::File::open(dockerfile)?, &mut hasher)?;
+        let context = floki_root.join(&self.context);
+        hash_context(&mut hasher, &context)?;
+        for context in self.build_contexts.values() {
+            let local = PathBuf::from(build_context_value(context, floki_root));
+            if local.is_dir() {
+                hash_context(&mut hasher, &local)?;
+            }
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// The build args describing the git checkout the build is run from,
+    /// if they're wanted. Builds outside a git repository go ahead without
+    /// them (see git_warning).
+    fn git_build_args(
+        &self,
+        floki_root: &Path,
+        git: impl Fn(&Path, &[&str]) -> Option<String>,
+    ) -> Vec<(String, String)> {
+        if !self.auto_git_build_args {
+            return Vec::new();
+        }
+        let commit = match git(floki_root, &["rev-parse", "HEAD"]) {
+            Some(commit) => commit,
+            None => return Vec::new(),
+        };
+        let mut build_args = vec![("GIT_COMMIT".to_string(), commit)];
+        // There's no branch when HEAD is detached, e.g. in many CI checkouts
+        if let Some(branch) = git(floki_root, &["symbolic-ref", "--short", "HEAD"]) {
+            build_args.push(("GIT_BRANCH".to_string(), branch));
+        }
+        build_args
+    }
+
+    /// The warning for git build args being wanted for a build outside a
+    /// git repository, which then has none
+    fn git_warning(
+        &self,
+        floki_root: &Path,
+        git: impl Fn(&Path, &[&str]) -> Option<String>,
+    ) -> Option<Warning> {
+        if self.auto_git_build_args && git(floki_root, &["rev-parse", "HEAD"]).is_none() {
+            Some(Warning::NotAGitRepository {
+                directory: floki_root.display().to_string(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Run one build command, producing the image with the given name
+    fn run_build(
+        &self,
+        command: &mut Command,
+        name: &str,
+        floki_root: &Path,
+        downloads: &Downloads,
+    ) -> Result<(), Error> {
+        if let Some(context) = downloads.context() {
+            command.stdin(fs::File::open(context)?);
+        }
+        let exit_status = match &self.build_log {
+            Some(log) => spawn_logged(command, &floki_root.join(log))?,
+            None if self.quiet_build => {
+                let output = command.stderr(Stdio::inherit()).output()?;
+                if output.status.success() {
+                    let id = String::from_utf8_lossy(&output.stdout);
+                    info!("Built image {} ({})", name, id.trim());
+                }
+                output.status
+            }
+            None => command.spawn()?.wait()?,
+        };
+        if exit_status.success() {
+            Ok(())
+        } else {
+            Err(FlokiError::FailedToBuildImage {
+                image: name.into(),
+                exit_status: FlokiSubprocessExitStatus {
+                    process_description: "docker build".into(),
+                    exit_status,
+                },
+                output: None,
+            }
+            .into())
+        }
+    }
+}
+
+/// What a git command run in a directory printed, if it succeeded
+fn git_output(directory: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(args)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        None
+    }
+}
+
+/// Run a command, copying its output to a log file as well as to floki's
+/// own stdout and stderr
+fn spawn_logged(command: &mut Command, log: &Path) -> Result<std::process::ExitStatus, Error> {
+    if let Some(parent) = log.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = fs::File::create(log)
+        .with_context(|| format!("Couldn't create build log {}", log.display()))?;
+    let file = Arc::new(Mutex::new(file));
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().map(|output| {
+        let file = file.clone();
+        thread::spawn(move || tee(output, io::stdout(), &file))
+    });
+    let stderr = child.stderr.take().map(|output| {
+        let file = file.clone();
+        thread::spawn(move || tee(output, io::stderr(), &file))
+    });
+
+    let exit_status = child.wait()?;
+    for copy in stdout.into_iter().chain(stderr) {
+        copy.join()
+            .map_err(|_| anyhow!("Couldn't copy output to {}", log.display()))??;
+    }
+    Ok(exit_status)
+}
+
+/// Copy everything read to both the writer and the log, as it arrives
+fn tee(mut from: impl Read, mut to: impl Write, log: &Mutex<fs::File>) -> io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = from.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        to.write_all(&buffer[..read])?;
+        to.flush()?;
+        log.lock().unwrap().write_all(&buffer[..read])?;
+    }
+}
+
+/// Named build contexts with a scheme (e.g. docker-image:// or https://)
+/// are passed to docker as they are, while others are local directories
+fn build_context_value(context: &str, floki_root: &Path) -> OsString {
+    if context.contains("://") {
+        context.into()
+    } else {
+        floki_root.join(context).into_os_string()
+    }
+}
+
+/// Hash a build context, leaving out whatever its .dockerignore keeps out
+/// of the build
+fn hash_context(hasher: &mut Sha256, context: &Path) -> Result<(), Error> {
+    let ignore = match fs::read_to_string(context.join(".dockerignore")) {
+        Ok(contents) => DockerIgnore::parse(&contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => DockerIgnore::default(),
+        Err(e) => return Err(e.into()),
+    };
+    hash_tree(hasher, context, context, &ignore)
+}
+
+/// Hash the paths and contents of all files below a directory, in a
+/// stable order. Symlinks are hashed by their target rather than followed,
+/// as docker sends them to the build as links.
+fn hash_tree(
+    hasher: &mut Sha256,
+    root: &Path,
+    dir: &Path,
+    ignore: &DockerIgnore,
+) -> Result<(), Error> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        let relative = path.strip_prefix(root)?;
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_dir() {
+            if !ignore.skips_directory(relative) {
+                hash_tree(hasher, root, &path, ignore)?;
+            }
+            continue;
+        }
+        if ignore.excludes(relative) {
+            continue;
+        }
+        hasher.update(relative.as_os_str().as_bytes());
+        hasher.update(b"\0");
+        if file_type.is_symlink() {
+            hasher.update(b"->");
+            hasher.update(fs::read_link(&path)?.as_os_str().as_bytes());
+        } else if file_type.is_file() {
+            io::copy(&mut fs::File::open(&path)?, hasher)?;
+        }
+        hasher.update(b"\0");
+    }
+    Ok(())
+}
+
+/// The patterns of a .dockerignore file. Later patterns win, and patterns
+/// starting with `!` bring back paths an earlier pattern left out.
+#[derive(Debug, Default)]
+struct DockerIgnore {
+    patterns: Vec<(bool, Vec<String>)>,
+}
+
+impl DockerIgnore {
+    fn parse(contents: &str) -> Self {
+        let patterns = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (exception, pattern) = match line.strip_prefix('!') {
+                    Some(pattern) => (true, pattern.trim()),
+                    None => (false, line),
+                };
+                let segments = pattern
+                    .split('/')
+                    .filter(|s| !s.is_empty() && *s != ".")
+                    .map(String::from)
+                    .collect();
+                (exception, segments)
+            })
+            .collect();
+        DockerIgnore { patterns }
+    }
+
+    /// Whether a path, relative to the context, is left out of the build.
+    /// A pattern matching a directory leaves out everything below it.
+    fn excludes(&self, path: &Path) -> bool {
+        let segments: Vec<_> = path.iter().filter_map(|s| s.to_str()).collect();
+        let mut excluded = false;
+        for (exception, pattern) in &self.patterns {
+            let matched = (1..=segments.len()).any(|n| match_segments(pattern, &segments[..n]));
+            if matched {
+                excluded = !exception;
+            }
+        }
+        excluded
+    }
+
+    /// Whether a directory can be skipped entirely. Exceptions could bring
+    /// back something below an excluded directory, so then it can't.
+    fn skips_directory(&self, path: &Path) -> bool {
+        self.excludes(path) && self.patterns.iter().all(|(exception, _)| !exception)
+    }
+}
+
+/// Match path segments against pattern segments, where `**` matches any
+/// number of segments
+fn match_segments(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_segments(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, others)) => {
+                match_wildcards(first.as_bytes(), segment.as_bytes())
+                    && match_segments(rest, others)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Match one path segment against a pattern which may use `*` and `?`
+fn match_wildcards(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            (0..=name.len()).any(|skip| match_wildcards(rest, &name[skip..]))
+        }
+        (Some((b'?', rest)), Some((_, others))) => match_wildcards(rest, others),
+        (Some((b'\\', rest)), _) if !rest.is_empty() => match name.split_first() {
+            Some((c, others)) => *c == rest[0] && match_wildcards(&rest[1..], others),
+            None => false,
+        },
+        (Some((p, rest)), Some((c, others))) => p == c && match_wildcards(rest, others),
+        _ => false,
+    }
+}
+
+/// Where the input hash of the last successful build of an image is kept
+fn build_state_file(floki_workspace: &Path, image: &str) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(image.as_bytes());
+    floki_workspace
+        .join("builds")
+        .join(format!("{:x}", hasher.finalize()))
+}
+
+/// Check that every one of some images exists, stopping at the first
+/// which doesn't
+fn all_exist<'a>(
+    images: impl IntoIterator<Item = &'a str>,
+    mut exists: impl FnMut(&str) -> Result<bool, Error>,
+) -> Result<bool, Error> {
+    for image in images {
+        if !exists(image)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// A build can be skipped if its inputs match those of the last successful
+/// build, and the images that build produced are still around. Docker is
+/// only asked about the images if the inputs are unchanged.
+fn should_skip_build(
+    previous_hash: Option<&str>,
+    current_hash: &str,
+    image_exists: impl FnOnce() -> Result<bool, Error>,
+) -> Result<bool, Error> {
+    if previous_hash == Some(current_hash) {
+        image_exists()
+    } else {
+        Ok(false)
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -26,221 +671,1020 @@ pub struct BuildSpec {
 pub enum YamlSpec {
     File {
         file: PathBuf,
-        key: String,
+        #[serde(flatten)]
+        lookup: YamlLookup,
     },
     Url {
         url: Url,
-        key: String,
+        #[serde(flatten)]
+        lookup: YamlLookup,
         headers: Option<HashMap<String, String>>,
+        #[serde(default)]
+        retries: u32,
+        max_size: Option<u64>,
     },
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub struct ExecSpec {
-    command: String,
-    args: Vec<String>,
-    image: String,
-}
-
-fn default_dockerfile() -> PathBuf {
-    "Dockerfile".into()
-}
-
-fn default_context() -> PathBuf {
-    ".".into()
-}
-
+/// How to find the image name in a yaml document, or the response it was
+/// fetched in
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
-pub enum Image {
-    Name(String),
-    Build { build: BuildSpec },
-    Yaml { yaml: YamlSpec },
-    Exec { exec: ExecSpec },
+pub enum YamlLookup {
+    /// The image name is the value at a single key. If the value is a
+    /// list, its items are joined with the `join` separator.
+    Key { key: String, join: Option<String> },
+    /// The image name is a template, with each `{placeholder}` replaced by
+    /// the value at the corresponding key
+    Template {
+        template: String,
+        keys: BTreeMap<String, String>,
+    },
+    /// The image name is the value of a header in the response, rather than
+    /// anything in the document (only for yaml fetched from a url)
+    Header { from_header: String },
 }
 
-impl Image {
-    /// Name of the image
-    pub fn name(&self) -> Result<String, Error> {
-        match *self {
-            Image::Name(ref s) => Ok(s.clone()),
-            Image::Build { ref build } => Ok(build.name.clone() + ":floki"),
-            Image::Yaml { ref yaml } => {
-                let key = match yaml {
-                    YamlSpec::File { key, .. } => key,
-                    YamlSpec::Url { key, .. } => key,
-                };
-                let path = key.split('.').collect::<Vec<_>>();
-
-                let contents = match yaml {
-                    YamlSpec::File { file, .. } => fs::read_to_string(file)?,
-                    YamlSpec::Url { url, headers, .. } => {
-                        let mut builder = reqwest::blocking::Client::new().get(url.as_ref());
-
-                        if let Some(headers) = headers {
-                            for (key, value) in headers {
-                                builder = builder.header(
-                                    key,
-                                    env::var(value).context(format!(
-                                        "Couldn't fetch environment variable {}",
-                                        value
-                                    ))?,
-                                )
-                            }
-                        }
-
-                        builder
-                            .send()
-                            .context("Couldn't send request")?
-                            .error_for_status()
-                            .context("GET returned error")?
-                            .text()
-                            .context("Response is not text")?
-                    }
-                };
+impl YamlSpec {
+    /// Resolve the image name from the yaml document
+    fn image_name(&self) -> Result<String, Error> {
+        if let YamlLookup::Header { from_header } = self.lookup() {
+            return match self {
+                YamlSpec::Url {
+                    url,
+                    headers,
+                    retries,
+                    ..
+                } => fetch_header(url, headers, *retries, FETCH_BACKOFF, from_header),
+                YamlSpec::File { .. } => Err(anyhow!(
+                    "from_header can only be used with a url, not a file"
+                )),
+            };
+        }
 
-                let raw = YamlLoader::load_from_str(&contents)
-                    .context("Retrieved file doesn't seem to be YAML")?;
-                let mut val = &raw[0];
-
-                for key in &path {
-                    // Yaml arrays and maps with scalar keys can both be indexed by
-                    // usize, so heuristically prefer a usize index to a &str index.
-                    val = match key.parse::<usize>() {
-                        Ok(x) => &val[x],
-                        Err(_) => &val[*key],
-                    };
+        let contents = self.contents()?;
+        let raw = YamlLoader::load_from_str(&contents)
+            .context("Retrieved file doesn't seem to be YAML")?;
+        let document = &raw[0];
+
+        match self.lookup() {
+            YamlLookup::Key { key, join } => self.value_at(document, key, join.as_deref()),
+            YamlLookup::Header { .. } => unreachable!("header lookups don't read the document"),
+            YamlLookup::Template { template, keys } => {
+                let mut name = template.clone();
+                for (placeholder, key) in keys {
+                    name = name.replace(
+                        &format!("{{{}}}", placeholder),
+                        &self.value_at(document, key, None)?,
+                    );
                 }
-                val.as_str()
-                    .map(std::string::ToString::to_string)
-                    .context(format!(
-                        "Couldn't find key {} in file {}",
-                        key,
-                        match yaml {
-                            YamlSpec::File { file, .. } => file.to_string_lossy().to_string(),
-                            YamlSpec::Url { url, .. } => url.to_string(),
-                        }
+                if name.contains('{') {
+                    Err(anyhow!(
+                        "Template '{}' has placeholders with no key: {}",
+                        template,
+                        name
                     ))
+                } else {
+                    Ok(name)
+                }
             }
-            Image::Exec { ref exec } => Ok(exec.image.clone()),
         }
     }
 
-    /// Do the required work to get the image, and then return
-    /// it's name
-    pub fn obtain_image(&self, floki_root: &Path) -> Result<String, Error> {
-        match *self {
-            // Deal with the case where want to build an image
-            Image::Build { ref build } => {
-                let mut command = Command::new("docker");
-                command
-                    .arg("build")
-                    .arg("-t")
-                    .arg(self.name()?)
-                    .arg("-f")
-                    .arg(&floki_root.join(&build.dockerfile));
-
-                if let Some(target) = &build.target {
-                    command.arg("--target").arg(target);
-                }
+    fn lookup(&self) -> &YamlLookup {
+        match self {
+            YamlSpec::File { lookup, .. } => lookup,
+            YamlSpec::Url { lookup, .. } => lookup,
+        }
+    }
 
-                let exit_status = command
-                    .arg(&floki_root.join(&build.context))
-                    .spawn()?
-                    .wait()?;
-                if exit_status.success() {
-                    Ok(self.name()?)
-                } else {
-                    Err(FlokiError::FailedToBuildImage {
-                        image: self.name()?,
-                        exit_status: FlokiSubprocessExitStatus {
-                            process_description: "docker build".into(),
-                            exit_status,
-                        },
-                    }
-                    .into())
-                }
-            }
-            Image::Exec { ref exec } => {
-                let exit_status = Command::new(&exec.command)
-                    .args(&exec.args)
-                    .spawn()?
-                    .wait()?;
+    /// Where the yaml document comes from, for error messages
+    fn source(&self) -> String {
+        match self {
+            YamlSpec::File { file, .. } => file.to_string_lossy().to_string(),
+            YamlSpec::Url { url, .. } => url.to_string(),
+        }
+    }
 
-                if exit_status.success() {
-                    Ok(self.name()?)
-                } else {
-                    Err(FlokiError::FailedToBuildImage {
-                        image: self.name()?,
-                        exit_status: FlokiSubprocessExitStatus {
-                            process_description: exec.command.clone(),
-                            exit_status,
-                        },
-                    }
-                    .into())
-                }
-            }
-            // All other cases we just return the name
-            _ => Ok(self.name()?),
+    fn contents(&self) -> Result<String, Error> {
+        match self {
+            YamlSpec::File { file, .. } => Ok(fs::read_to_string(file)?),
+            YamlSpec::Url {
+                url,
+                headers,
+                retries,
+                max_size,
+                ..
+            } => fetch_url(
+                url,
+                headers,
+                *retries,
+                FETCH_BACKOFF,
+                max_size.unwrap_or(DEFAULT_MAX_FETCH_SIZE),
+            ),
+        }
+    }
+
+    /// Find the string at a dotted key path in the document. With a
+    /// separator, a list of scalars is joined into a single string.
+    fn value_at(&self, document: &Yaml, key: &str, join: Option<&str>) -> Result<String, Error> {
+        let mut val = document;
+
+        for key in key.split('.') {
+            // Yaml arrays and maps with scalar keys can both be indexed by
+            // usize, so heuristically prefer a usize index to a &str index.
+            val = match key.parse::<usize>() {
+                Ok(x) => &val[x],
+                Err(_) => &val[key],
+            };
         }
+        let value = match (val, join) {
+            (Yaml::Array(items), Some(separator)) => items
+                .iter()
+                .map(scalar_string)
+                .collect::<Option<Vec<_>>>()
+                .map(|items| items.join(separator)),
+            _ => val.as_str().map(std::string::ToString::to_string),
+        };
+        value.context(format!(
+            "Couldn't find key {} in file {}",
+            key,
+            self.source()
+        ))
     }
 }
 
-// Now we have some functions which are useful in general
+/// The text of a scalar yaml value
+fn scalar_string(value: &Yaml) -> Option<String> {
+    match value {
+        Yaml::String(s) | Yaml::Real(s) => Some(s.clone()),
+        Yaml::Integer(i) => Some(i.to_string()),
+        Yaml::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
 
-/// Wrapper to pull an image by it's name
-pub fn pull_image(name: &str) -> Result<(), Error> {
-    debug!("Pulling image: {}", name);
-    let exit_status = Command::new("docker")
-        .arg("pull")
-        .arg(name)
-        .spawn()?
-        .wait()?;
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExecSpec {
+    #[serde(flatten)]
+    command: CommandSpec,
+    image: String,
+    in_image: Option<String>,
+    #[serde(default)]
+    capture_output: bool,
+}
 
-    if exit_status.success() {
-        Ok(())
-    } else {
-        Err(FlokiError::FailedToPullImage {
-            image: name.into(),
-            exit_status: FlokiSubprocessExitStatus {
-                process_description: "docker pull".into(),
+impl ExecSpec {
+    /// Run the command to produce the image. Captured output is only
+    /// shown if the command fails.
+    fn run(&self, floki_root: &Path, config_dir: &Path) -> Result<(), FlokiError> {
+        match self
+            .command(floki_root, config_dir)
+            .run(self.capture_output)
+        {
+            Err(FlokiError::CommandFailed {
+                exit_status,
+                output,
+            }) => Err(FlokiError::FailedToBuildImage {
+                image: self.image.clone(),
                 exit_status,
+                output,
+            }),
+            result => result,
+        }
+    }
+
+    /// The command which produces the image, running it inside a
+    /// container if a runner image is given. It is told the image it
+    /// should produce, and the directory of the configuration.
+    ///
+    /// The runner sees the project, and the configuration's directory, at
+    /// the same paths as the host does, working from the project, and can
+    /// build images with the host's docker. Variables are named on the
+    /// command line and given their values through docker's environment,
+    /// so the values aren't in the process list.
+    fn command(&self, floki_root: &Path, config_dir: &Path) -> CommandSpec {
+        let mut env = BTreeMap::new();
+        env.insert("FLOKI_IMAGE".to_string(), self.image.clone());
+        env.insert(
+            "FLOKI_CONFIG_DIR".to_string(),
+            config_dir.display().to_string(),
+        );
+        // Configured variables take precedence
+        env.extend(self.command.env.clone());
+
+        match &self.in_image {
+            Some(runner) => {
+                // Run in the configured docker context, like any other
+                // docker command
+                let project = floki_root.display().to_string();
+                let mut args = crate::command::context_args();
+                args.extend(
+                    ["run", "--rm", "-v", &format!("{}:{}", project, project)].map(String::from),
+                );
+                if !config_dir.starts_with(floki_root) {
+                    let config_dir = config_dir.display();
+                    args.push("-v".into());
+                    args.push(format!("{}:{}", config_dir, config_dir));
+                }
+                args.extend(
+                    [
+                        "-v",
+                        "/var/run/docker.sock:/var/run/docker.sock",
+                        "-w",
+                        &project,
+                    ]
+                    .map(String::from),
+                );
+                for var in env.keys() {
+                    args.push("-e".into());
+                    args.push(var.clone());
+                }
+                args.push(runner.clone());
+                args.push(self.command.command.clone());
+                args.extend(self.command.args.iter().cloned());
+                CommandSpec {
+                    command: "docker".into(),
+                    args,
+                    env,
+                }
+            }
+            None => CommandSpec {
+                env,
+                ..self.command.clone()
             },
         }
-        .into())
     }
 }
 
-/// Determine whether an image exists locally
-pub fn image_exists_locally(name: &str) -> Result<bool, Error> {
-    let ret = Command::new("docker")
-        .args(&["history", "docker:stable-dind"])
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .map_err(|e| FlokiError::FailedToCheckForImage {
-            image: name.to_string(),
-            error: e,
-        })?;
+/// Look up a value in the cache, resolving and storing it if it isn't
+/// there. Failures aren't cached, so are retried on the next lookup.
+fn memoized(
+    cache: &Mutex<BTreeMap<String, String>>,
+    key: &str,
+    resolve: impl FnOnce() -> Result<String, Error>,
+) -> Result<String, Error> {
+    if let Some(value) = cache.lock().unwrap().get(key) {
+        return Ok(value.clone());
+    }
+    let value = resolve()?;
+    cache.lock().unwrap().insert(key.into(), value.clone());
+    Ok(value)
+}
 
-    Ok(ret.code() == Some(0))
+/// Fetch the text at a url. Responses larger than the maximum size (in
+/// bytes) are an error.
+fn fetch_url(
+    url: &Url,
+    headers: &Option<HashMap<String, String>>,
+    retries: u32,
+    backoff: Duration,
+    max_size: u64,
+) -> Result<String, Error> {
+    read_bounded(
+        url,
+        get(url, headers, retries, backoff, Some(FETCH_TIMEOUT))?,
+        max_size,
+    )
 }
 
-#[cfg(test)]
-mod test {
-    use maplit::hashmap;
-    use std::convert::TryInto;
+/// Fetch a url, returning the value of a header in the response
+fn fetch_header(
+    url: &Url,
+    headers: &Option<HashMap<String, String>>,
+    retries: u32,
+    backoff: Duration,
+    name: &str,
+) -> Result<String, Error> {
+    let response = get(url, headers, retries, backoff, Some(FETCH_TIMEOUT))?;
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| {
+            FlokiError::MissingResponseHeader {
+                url: url.to_string(),
+                header: name.into(),
+            }
+            .into()
+        })
+}
 
-    use super::*;
+/// GET a url, retrying transient failures (connection problems and server
+/// errors) with exponential backoff. The timeout covers reading the whole
+/// response; without one, only connecting is limited.
+fn get(
+    url: &Url,
+    headers: &Option<HashMap<String, String>>,
+    retries: u32,
+    backoff: Duration,
+    timeout: Option<Duration>,
+) -> Result<reqwest::blocking::Response, Error> {
+    let client = reqwest::blocking::Client::builder()
+        .connect_timeout(FETCH_TIMEOUT)
+        .timeout(timeout)
+        .build()?;
+    let mut attempt = 0;
 
-    #[derive(Debug, PartialEq, Serialize, Deserialize)]
-    struct TestImage {
-        image: Image,
+    loop {
+        let mut builder = client.get(url.as_ref());
+
+        if let Some(headers) = headers {
+            for (key, value) in headers {
+                builder = builder.header(
+                    key,
+                    env::var(value)
+                        .context(format!("Couldn't fetch environment variable {}", value))?,
+                )
+            }
+        }
+
+        match builder.send().and_then(|r| r.error_for_status()) {
+            Ok(response) => return Ok(response),
+            Err(e) if attempt < retries && is_retryable(&e) => {
+                let delay = backoff * 2u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "Fetching {} failed ({}), retrying in {:?} ({} of {})",
+                    url, e, delay, attempt, retries
+                );
+                thread::sleep(delay);
+            }
+            Err(e) => {
+                let context = match e.status() {
+                    Some(_) => "GET returned error",
+                    None => "Couldn't send request",
+                };
+                return Err(Error::new(e).context(context));
+            }
+        }
     }
+}
 
-    #[test]
-    fn test_image_spec_by_string() {
+/// Read a response body, giving up if it is larger than the maximum size
+fn read_bounded(url: &Url, response: impl Read, max_size: u64) -> Result<String, Error> {
+    let mut body = Vec::new();
+    response
+        .take(max_size + 1)
+        .read_to_end(&mut body)
+        .context("Couldn't read response")?;
+    if body.len() as u64 > max_size {
+        return Err(FlokiError::ResponseTooLarge {
+            url: url.to_string(),
+            max_size,
+        }
+        .into());
+    }
+    String::from_utf8(body).context("Response is not text")
+}
+
+/// Client errors won't go away on retrying, but server errors and
+/// failures to connect might
+fn is_retryable(error: &reqwest::Error) -> bool {
+    match error.status() {
+        Some(status) => status.is_server_error(),
+        None => error.is_connect() || error.is_timeout(),
+    }
+}
+
+/// The files downloaded for a build - its dockerfile and context, if
+/// they're remote and need to be
+#[derive(Default)]
+struct Downloads {
+    dockerfile: Option<DownloadedFile>,
+    context: Option<DownloadedFile>,
+}
+
+impl Downloads {
+    /// Fetch the downloads, except for a dry run, which mustn't touch the
+    /// network
+    fn fetch(build: &BuildSpec, workspace: &Path, dry_run: bool) -> Result<Self, Error> {
+        let dockerfile = match build.remote_dockerfile() {
+            Some(url) if !dry_run => Some(DownloadedFile::fetch_dockerfile(
+                &url,
+                &build.dockerfile_headers,
+                workspace,
+            )?),
+            _ => None,
+        };
+        // Contexts are only downloaded to check them, otherwise docker
+        // fetches them itself - as a dry run shows it doing
+        let context = match (build.remote_tarball_context(), &build.context_sha256) {
+            (Some(url), Some(sha256)) if !dry_run => {
+                Some(DownloadedFile::fetch_context(&url, sha256, workspace)?)
+            }
+            _ => None,
+        };
+        Ok(Downloads {
+            dockerfile,
+            context,
+        })
+    }
+
+    fn dockerfile(&self) -> Option<&Path> {
+        self.dockerfile.as_ref().map(DownloadedFile::path)
+    }
+
+    fn context(&self) -> Option<&Path> {
+        self.context.as_ref().map(DownloadedFile::path)
+    }
+}
+
+/// A file downloaded to the workspace, removed when dropped
+struct DownloadedFile {
+    path: PathBuf,
+}
+
+impl DownloadedFile {
+    fn fetch_dockerfile(
+        url: &Url,
+        headers: &Option<HashMap<String, String>>,
+        workspace: &Path,
+    ) -> Result<Self, Error> {
+        debug!("Downloading dockerfile from {}", url);
+        let contents = fetch_url(url, headers, 0, FETCH_BACKOFF, DEFAULT_MAX_FETCH_SIZE)
+            .with_context(|| format!("Couldn't download dockerfile from {}", url))?;
+        Self::write(
+            &workspace.join("dockerfiles"),
+            "Dockerfile",
+            contents.as_bytes(),
+        )
+    }
+
+    /// Download a build context, checking it has the expected digest.
+    /// Contexts can be large, so are streamed to disk as they're hashed,
+    /// with no time limit on the download.
+    fn fetch_context(url: &Url, sha256: &str, workspace: &Path) -> Result<Self, Error> {
+        debug!("Downloading build context from {}", url);
+        let mut response = get(url, &None, 0, FETCH_BACKOFF, None)?;
+        let (downloaded, mut file) = Self::create(&workspace.join("contexts"), "tar")?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = response
+                .read(&mut buffer)
+                .with_context(|| format!("Couldn't download build context from {}", url))?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            file.write_all(&buffer[..read])?;
+        }
+        verify_sha256(url, hasher, sha256)?;
+        Ok(downloaded)
+    }
+
+    fn write(directory: &Path, extension: &str, contents: &[u8]) -> Result<Self, Error> {
+        let (downloaded, mut file) = Self::create(directory, extension)?;
+        file.write_all(contents)?;
+        Ok(downloaded)
+    }
+
+    /// Create a new file to download into, which is removed when dropped
+    fn create(directory: &Path, extension: &str) -> Result<(Self, fs::File), Error> {
+        fs::create_dir_all(directory)?;
+        let path = directory.join(format!("{}.{}", uuid::Uuid::new_v4(), extension));
+        let file = fs::File::create(&path)?;
+        Ok((DownloadedFile { path }, file))
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DownloadedFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            debug!("Couldn't remove downloaded file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Check that downloaded contents, fed to the hasher, have the expected
+/// SHA-256 digest
+fn verify_sha256(url: &Url, hasher: Sha256, expected: &str) -> Result<(), Error> {
+    let actual = format!("{:x}", hasher.finalize());
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(FlokiError::ChecksumMismatch {
+            url: url.to_string(),
+            expected: expected.into(),
+            actual,
+        }
+        .into())
+    }
+}
+
+fn default_dockerfile() -> PathBuf {
+    "Dockerfile".into()
+}
+
+fn default_context() -> PathBuf {
+    ".".into()
+}
+
+/// A service of a docker compose file, whose image is used
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ComposeSpec {
+    file: PathBuf,
+    service: String,
+}
+
+impl ComposeSpec {
+    /// Make a relative compose file relative to the given directory
+    pub fn relative_to(&mut self, directory: &Path) {
+        self.file = directory.join(&self.file);
+    }
+
+    /// The image the service runs - its image if it names one, otherwise
+    /// the name compose gives the image it builds for the service
+    fn image_name(&self) -> Result<String, Error> {
+        let contents = fs::read_to_string(&self.file)
+            .with_context(|| format!("Couldn't read compose file {}", self.file.display()))?;
+        let raw = YamlLoader::load_from_str(&contents)
+            .with_context(|| format!("Compose file {} isn't YAML", self.file.display()))?;
+        let document = raw.first().unwrap_or(&Yaml::Null);
+
+        let service = &document["services"][self.service.as_str()];
+        if service.is_badvalue() {
+            return Err(FlokiError::ComposeServiceNotFound {
+                file: self.file.display().to_string(),
+                service: self.service.clone(),
+            }
+            .into());
+        }
+
+        match (service["image"].as_str(), service["build"].is_badvalue()) {
+            (Some(image), _) => Ok(image.to_string()),
+            (None, false) => Ok(format!(
+                "{}-{}",
+                compose_project_name(document, &self.file),
+                self.service
+            )),
+            (None, true) => Err(FlokiError::ComposeServiceHasNoImage {
+                file: self.file.display().to_string(),
+                service: self.service.clone(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// The project name compose uses - the name in the file, or the name of
+/// the directory containing it, reduced to the characters compose allows
+fn compose_project_name(document: &Yaml, file: &Path) -> String {
+    let name = match document["name"].as_str() {
+        Some(name) => name.to_string(),
+        None => file
+            .parent()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+    name.to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Image {
+    Name(String),
+    Build { build: BuildSpec },
+    Yaml { yaml: YamlSpec },
+    Exec { exec: ExecSpec },
+    FromConfig { config: PathBuf },
+    Compose { compose: ComposeSpec },
+}
+
+impl Image {
+    /// Name of the image
+    pub fn name(&self) -> Result<String, Error> {
+        match *self {
+            Image::Name(ref s) => Ok(match oci_layout(s) {
+                Some(layout) => oci_image_name(&layout),
+                None => s.clone(),
+            }),
+            Image::Build { ref build } => Ok(build.name.clone() + ":floki"),
+            Image::Yaml { ref yaml } => memoized(&RESOLVED_NAMES, &format!("{:?}", yaml), || {
+                yaml.image_name()
+            }),
+            Image::Exec { ref exec } => Ok(exec.image.clone()),
+            Image::FromConfig { ref config } => referenced_image(config)?.0.name(),
+            Image::Compose { ref compose } => compose.image_name(),
+        }
+    }
+
+    /// Name of the image, rewritten to pull via the given registry
+    /// mirror. Images which are produced locally are left as they are.
+    pub fn mirrored_name(&self, mirror: Option<&str>) -> Result<String, Error> {
+        match mirror {
+            Some(mirror) if self.is_remote() => Ok(apply_registry_mirror(&self.name()?, mirror)),
+            _ => self.name(),
+        }
+    }
+
+    /// Whether the image comes from a registry, rather than being
+    /// produced locally
+    pub fn is_remote(&self) -> bool {
+        match self {
+            Image::Name(name) => oci_layout(name).is_none(),
+            Image::Yaml { .. } | Image::Compose { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Check the image configuration for combinations which can't work
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            Image::Build { build } if !build.annotations.is_empty() && !build.buildx => {
+                Err(FlokiError::AnnotationsNeedBuildx {
+                    image: build.name.clone(),
+                }
+                .into())
+            }
+            Image::Build { build }
+                if build.context_sha256.is_some() && build.remote_tarball_context().is_none() =>
+            {
+                Err(FlokiError::ChecksumNeedsTarballContext {
+                    image: build.name.clone(),
+                }
+                .into())
+            }
+            Image::Build { build } if build.quiet_build => {
+                let conflict = if build.progress.is_some() {
+                    Some("progress")
+                } else if build.build_log.is_some() {
+                    Some("build_log")
+                } else {
+                    None
+                };
+                match conflict {
+                    Some(option) => Err(FlokiError::QuietBuildConflict {
+                        image: build.name.clone(),
+                        option: option.into(),
+                    }
+                    .into()),
+                    None => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Problems with the image configuration which don't stop it being
+    /// obtained from the given root
+    pub fn warnings(&self, floki_root: &Path) -> Vec<Warning> {
+        match self {
+            Image::Build { build } => {
+                let mut warnings = Vec::new();
+                if build.cache_build && build.remote_context().is_some() {
+                    warnings.push(Warning::CacheBuildWithRemoteContext);
+                }
+                warnings.extend(build.git_warning(floki_root, git_output));
+                warnings
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether obtaining the image leaves an image to run. Builds which
+    /// export their output elsewhere don't.
+    pub fn produces_image(&self) -> bool {
+        !matches!(
+            self,
+            Image::Build {
+                build: BuildSpec {
+                    output: Some(_),
+                    ..
+                }
+            }
+        )
+    }
+
+    /// Let a build use the forwarded ssh agent, unless it already says
+    /// which ssh sockets or keys to use
+    pub fn forward_ssh_agent_to_build(&mut self) {
+        if let Image::Build { build } = self {
+            if build.ssh.is_empty() {
+                build.ssh.push("default".into());
+            }
+        }
+    }
+
+    /// Do the required work to get the image, and then return
+    /// it's name. Builds which are actually run are reported to the events.
+    /// Exec commands are told the directory of the configuration.
+    pub fn obtain_image(
+        &self,
+        floki_root: &Path,
+        config_dir: &Path,
+        floki_workspace: &Path,
+        dry_run: bool,
+        events: &mut dyn Emitter,
+    ) -> Result<String, Error> {
+        match *self {
+            // Deal with the case where want to build an image
+            Image::Build { ref build } => {
+                let name = self.name()?;
+                // The downloads are removed once the build is done
+                let downloads = Downloads::fetch(build, floki_workspace, dry_run)?;
+                let build_args = build.git_build_args(floki_root, git_output);
+
+                // An undownloaded dockerfile can't be hashed, so dry runs
+                // with one always show the build
+                let hashable =
+                    downloads.dockerfile().is_some() || build.remote_dockerfile().is_none();
+                let cache = if build.cache_build && build.remote_context().is_none() && hashable {
+                    let hash = build.input_hash(floki_root, downloads.dockerfile(), &build_args)?;
+                    let state_file = build_state_file(floki_workspace, &name);
+                    let previous = fs::read_to_string(&state_file).ok();
+                    if should_skip_build(previous.as_deref(), &hash, || {
+                        all_exist(build.built_images(&name), image_exists_locally)
+                    })? {
+                        info!("Build inputs unchanged, reusing image {}", name);
+                        return Ok(name);
+                    }
+                    Some((state_file, hash))
+                } else {
+                    None
+                };
+                if !dry_run {
+                    events.emit(Event::BuildStarted {
+                        image: name.clone(),
+                    });
+                }
+
+                // Logged output doesn't go straight to the terminal
+                let terminal = build.build_log.is_none() && nix::unistd::isatty(2).unwrap_or(false);
+
+                // The additional targets are built first, leaving the
+                // image to run as the last thing built
+                let mut commands =
+                    build.targets_commands(floki_root, &downloads, &build_args, terminal);
+                commands.push((
+                    &name,
+                    build.command(&name, floki_root, &downloads, &build_args, terminal),
+                ));
+                for (tag, mut command) in commands {
+                    if dry_run {
+                        println!("{}", build.describe_dry_run(&command));
+                    } else {
+                        build.run_build(&mut command, tag, floki_root, &downloads)?;
+                    }
+                }
+
+                if let (Some((state_file, hash)), false) = (cache, dry_run) {
+                    fs::create_dir_all(floki_workspace.join("builds"))?;
+                    fs::write(state_file, hash)?;
+                }
+                Ok(name)
+            }
+            Image::Exec { ref exec } if dry_run => {
+                println!(
+                    "{}",
+                    describe_command(&exec.command(floki_root, config_dir).build())
+                );
+                Ok(self.name()?)
+            }
+            Image::Exec { ref exec } => {
+                events.emit(Event::BuildStarted {
+                    image: self.name()?,
+                });
+                exec.run(floki_root, config_dir)?;
+                Ok(self.name()?)
+            }
+            // Load images kept as OCI layouts into docker
+            Image::Name(ref name) => match oci_layout(name) {
+                Some(layout) if dry_run => {
+                    let name = self.name()?;
+                    println!("{}", describe_command(&oci_load_command(&layout, &name)));
+                    Ok(name)
+                }
+                Some(layout) => load_oci_layout(&layout, &self.name()?),
+                None => Ok(name.clone()),
+            },
+            // Get the image the way the other configuration would
+            Image::FromConfig { ref config } => {
+                let (image, root) = referenced_image(config)?;
+                image.obtain_image(&root, &root, floki_workspace, dry_run, events)
+            }
+            // All other cases we just return the name
+            _ => Ok(self.name()?),
+        }
+    }
+}
+
+/// Follow references to the images of other configurations, returning the
+/// image which is finally referenced, and the directory of the
+/// configuration defining it
+fn referenced_image(config: &Path) -> Result<(Image, PathBuf), Error> {
+    let mut visited: Vec<PathBuf> = Vec::new();
+    let mut config = config.to_path_buf();
+    loop {
+        let file = fs::canonicalize(&config).map_err(|e| FlokiError::ProblemOpeningConfigYaml {
+            name: config.display().to_string(),
+            error: e,
+        })?;
+        if visited.contains(&file) {
+            return Err(FlokiError::ImageReferenceCycle {
+                config: file.display().to_string(),
+            }
+            .into());
+        }
+        visited.push(file.clone());
+
+        match crate::config::FlokiConfig::from_files(std::slice::from_ref(&file), None)?.image {
+            Image::FromConfig { config: next } => config = next,
+            image => {
+                let root = file.parent().unwrap_or_else(|| Path::new("/"));
+                return Ok((image, root.to_path_buf()));
+            }
+        }
+    }
+}
+
+/// The directory of an OCI image layout, for image names which are
+/// `oci://` urls
+fn oci_layout(name: &str) -> Option<PathBuf> {
+    match Url::parse(name) {
+        Ok(url) if url.scheme() == "oci" => url.to_file_path().ok(),
+        _ => None,
+    }
+}
+
+/// The name an OCI layout is loaded into docker as, from its directory
+fn oci_image_name(layout: &Path) -> String {
+    let directory = layout
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let directory: String = directory
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+    format!("floki-oci/{}:latest", directory.trim_matches('-'))
+}
+
+/// Load the image in an OCI layout into docker, returning its name
+fn load_oci_layout(layout: &Path, image: &str) -> Result<String, Error> {
+    debug!("Loading OCI layout {:?} as {}", layout, image);
+    let exit_status = oci_load_command(layout, image).spawn()?.wait()?;
+    if exit_status.success() {
+        Ok(image.into())
+    } else {
+        Err(FlokiError::FailedToLoadImage {
+            layout: layout.display().to_string(),
+            image: image.into(),
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "skopeo copy".into(),
+                exit_status,
+            },
+        }
+        .into())
+    }
+}
+
+/// Copy the image in an OCI layout into the docker daemon
+fn oci_load_command(layout: &Path, name: &str) -> Command {
+    let mut command = Command::new("skopeo");
+    command
+        .arg("copy")
+        .arg(format!("oci:{}", layout.display()))
+        .arg(format!("docker-daemon:{}", name));
+    command
+}
+
+// Now we have some functions which are useful in general
+
+/// Expand an image reference to name its registry explicitly, in the
+/// same way docker resolves references with no registry component.
+fn normalize_image_name(name: &str) -> String {
+    match name.split_once('/') {
+        Some((first, _)) if first.contains('.') || first.contains(':') || first == "localhost" => {
+            name.to_string()
+        }
+        Some(_) => format!("{}/{}", DEFAULT_REGISTRY, name),
+        None => format!("{}/library/{}", DEFAULT_REGISTRY, name),
+    }
+}
+
+/// Rewrite an image reference on the default registry to refer to the
+/// same image on a mirror. References to other registries are unchanged.
+pub fn apply_registry_mirror(name: &str, mirror: &str) -> String {
+    let normalized = normalize_image_name(name);
+    match normalized.strip_prefix(&format!("{}/", DEFAULT_REGISTRY)) {
+        Some(rest) => format!("{}/{}", mirror.trim_end_matches('/'), rest),
+        None => name.to_string(),
+    }
+}
+
+/// Wrapper to pull an image by it's name
+pub fn pull_image(name: &str, dry_run: bool) -> Result<(), Error> {
+    let mut command = pull_command(name);
+    if dry_run {
+        println!("{}", describe_command(&command));
+        return Ok(());
+    }
+
+    debug!("Pulling image: {}", name);
+    let exit_status = command.spawn()?.wait()?;
+
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(FlokiError::FailedToPullImage {
+            image: name.into(),
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "docker pull".into(),
+                exit_status,
+            },
+        }
+        .into())
+    }
+}
+
+/// Assemble the command which pulls an image
+fn pull_command(name: &str) -> Command {
+    let mut command = crate::command::docker();
+    command.arg("pull").arg(name);
+    command
+}
+
+/// Render a command as it would be typed into a shell
+pub(crate) fn describe_command(command: &Command) -> String {
+    let words: Vec<String> = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|word| word.to_string_lossy().to_string())
+        .collect();
+    shlex::join(words.iter().map(String::as_str))
+}
+
+/// Determine whether an image exists locally
+pub fn image_exists_locally(name: &str) -> Result<bool, Error> {
+    Ok(inspect_image(name, "{{.Id}}")?.is_some())
+}
+
+/// Find the registry digest of a local image, if it exists locally and has
+/// been pushed to or pulled from a registry
+pub fn local_image_digest(name: &str) -> Result<Option<String>, Error> {
+    let output = inspect_image(name, "{{range .RepoDigests}}{{println .}}{{end}}")?;
+    Ok(output.as_deref().and_then(first_repo_digest))
+}
+
+/// Inspect a local image, returning the formatted output, or None if the
+/// image doesn't exist locally
+fn inspect_image(name: &str, format: &str) -> Result<Option<String>, Error> {
+    let output = crate::command::docker()
+        .args(["image", "inspect", "--format", format, name])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| FlokiError::FailedToCheckForImage {
+            image: name.to_string(),
+            error: e,
+        })?;
+
+    if output.status.success() {
+        Ok(Some(String::from_utf8_lossy(&output.stdout).to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Get the first digest from the repo digests listed by docker inspect
+fn first_repo_digest(output: &str) -> Option<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod test {
+    use maplit::hashmap;
+    use std::convert::TryInto;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestImage {
+        image: Image,
+    }
+
+    #[test]
+    fn test_image_spec_by_string() {
         let yaml = "image: foo";
         let expected = TestImage {
             image: Image::Name("foo".into()),
@@ -259,6 +1703,20 @@ mod test {
                     dockerfile: "Dockerfile.test".into(),
                     context: "./context".into(),
                     target: Some("builder".into()),
+                    targets: BTreeMap::new(),
+                    output: None,
+                    ssh: vec![],
+                    cache_build: false,
+                    build_host: None,
+                    build_contexts: BTreeMap::new(),
+                    progress: None,
+                    build_log: None,
+                    dockerfile_headers: None,
+                    context_sha256: None,
+                    buildx: false,
+                    annotations: BTreeMap::new(),
+                    quiet_build: false,
+                    auto_git_build_args: false,
                 },
             },
         };
@@ -266,27 +1724,328 @@ mod test {
         assert!(actual == expected);
     }
 
-    #[test]
-    fn test_image_spec_by_exec_spec() {
-        let yaml = r#"
-image:
-    exec:
-        command: foo
-        args:
-            - build
-        image: "foobuild:1.0.0"
+    const COMPOSE: &str = r#"
+services:
+  app:
+    image: registry.example.com/app:1.2
+    ports: ["8080:8080"]
+  tools:
+    build: ./tools
+  broken:
+    ports: ["9090:9090"]
 "#;
-        let expected = TestImage {
-            image: Image::Exec {
-                exec: ExecSpec {
-                    command: "foo".into(),
-                    args: vec!["build".into()],
-                    image: "foobuild:1.0.0".into(),
-                },
-            },
-        };
-        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+
+    fn compose_image(dir: &Path, service: &str) -> Result<String, Error> {
+        let image: Image = serde_yaml::from_str(&format!(
+            "compose:\n  file: {}\n  service: {}",
+            dir.join("docker-compose.yml").display(),
+            service
+        ))?;
+        image.name()
+    }
+
+    #[test]
+    fn test_image_from_compose_service() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let project = dir.path().join("My Project");
+        fs::create_dir(&project)?;
+        fs::write(project.join("docker-compose.yml"), COMPOSE)?;
+
+        assert_eq!(
+            compose_image(&project, "app")?,
+            "registry.example.com/app:1.2"
+        );
+        // Built images are named after the project and service
+        assert_eq!(compose_image(&project, "tools")?, "myproject-tools");
+        Ok(())
+    }
+
+    #[test]
+    fn test_compose_file_relative_to_its_config() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let base = write_config(dir.path(), "floki.yaml", "image: debian\nshell: bash")?;
+        let app = write_config(
+            dir.path(),
+            "app/floki.yaml",
+            "image:\n  compose:\n    file: docker-compose.yml\n    service: app",
+        )?;
+        fs::write(dir.path().join("app/docker-compose.yml"), COMPOSE)?;
+
+        // The compose file is beside the file setting the image, not the
+        // first file
+        let config = crate::config::FlokiConfig::from_files(&[base, app], None)?;
+        assert_eq!(config.image.name()?, "registry.example.com/app:1.2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_compose_service_not_found() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("docker-compose.yml"), COMPOSE)?;
+
+        let error = compose_image(dir.path(), "db").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::ComposeServiceNotFound { service, .. }) if service == "db"
+        ));
+
+        let error = compose_image(dir.path(), "broken").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::ComposeServiceHasNoImage { service, .. }) if service == "broken"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_spec_by_exec_spec() {
+        let yaml = r#"
+image:
+    exec:
+        command: foo
+        args:
+            - build
+        image: "foobuild:1.0.0"
+"#;
+        let expected = TestImage {
+            image: Image::Exec {
+                exec: ExecSpec {
+                    command: CommandSpec {
+                        command: "foo".into(),
+                        args: vec!["build".into()],
+                        env: BTreeMap::new(),
+                    },
+                    image: "foobuild:1.0.0".into(),
+                    in_image: None,
+                    capture_output: false,
+                },
+            },
+        };
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        assert!(actual == expected);
+    }
+
+    #[test]
+    fn test_exec_reports_captured_output_on_failure() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            r#"
+exec:
+    command: sh
+    args: ["-c", "echo building; echo no compiler found >&2; exit 2"]
+    image: foo:1.0
+    capture_output: true
+"#,
+        )?;
+        let exec = match &image {
+            Image::Exec { exec } => exec,
+            _ => panic!("expected an exec image"),
+        };
+
+        let error = exec.run(Path::new("/tmp"), Path::new("/tmp")).unwrap_err();
+        match &error {
+            FlokiError::FailedToBuildImage {
+                image,
+                exit_status,
+                output,
+            } => {
+                assert_eq!(image, "foo:1.0");
+                assert_eq!(exit_status.exit_status.code(), Some(2));
+                assert!(output.as_deref().unwrap().contains("no compiler found"));
+            }
+            _ => panic!("unexpected error {:?}", error),
+        }
+        assert!(error.to_string().contains("no compiler found"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_spec_by_exec_spec_in_image() {
+        let yaml = r#"
+image:
+    exec:
+        command: foo
+        args:
+            - build
+        image: "foobuild:1.0.0"
+        in_image: "foo-tools:2.0"
+"#;
+        let expected = TestImage {
+            image: Image::Exec {
+                exec: ExecSpec {
+                    command: CommandSpec {
+                        command: "foo".into(),
+                        args: vec!["build".into()],
+                        env: BTreeMap::new(),
+                    },
+                    image: "foobuild:1.0.0".into(),
+                    in_image: Some("foo-tools:2.0".into()),
+                    capture_output: false,
+                },
+            },
+        };
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
         assert!(actual == expected);
+
+        let command = match &actual.image {
+            Image::Exec { exec } => exec.command(Path::new("/host/src"), Path::new("/host/src")),
+            _ => panic!("expected an exec image"),
+        };
+        assert_eq!(command.command, "docker");
+        assert_eq!(
+            command.args,
+            vec![
+                "run",
+                "--rm",
+                "-v",
+                "/host/src:/host/src",
+                "-v",
+                "/var/run/docker.sock:/var/run/docker.sock",
+                "-w",
+                "/host/src",
+                "-e",
+                "FLOKI_CONFIG_DIR",
+                "-e",
+                "FLOKI_IMAGE",
+                "foo-tools:2.0",
+                "foo",
+                "build"
+            ]
+        );
+        // The values are only in docker's environment
+        assert_eq!(command.env["FLOKI_CONFIG_DIR"], "/host/src");
+        assert_eq!(command.env["FLOKI_IMAGE"], "foobuild:1.0.0");
+
+        // A configuration outside the project is mounted too
+        let command = match &actual.image {
+            Image::Exec { exec } => exec.command(Path::new("/host/src"), Path::new("/host/config")),
+            _ => panic!("expected an exec image"),
+        };
+        assert_eq!(
+            command.args[..6],
+            [
+                "run",
+                "--rm",
+                "-v",
+                "/host/src:/host/src",
+                "-v",
+                "/host/config:/host/config"
+            ]
+        );
+        assert_eq!(command.env["FLOKI_CONFIG_DIR"], "/host/config");
+    }
+
+    #[test]
+    fn test_exec_sees_image_and_config_dir() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let image: Image = serde_yaml::from_str(
+            r#"
+exec:
+    command: sh
+    args: ["-c", "echo \"$FLOKI_IMAGE $FLOKI_CONFIG_DIR $EXTRA\" > \"$FLOKI_CONFIG_DIR/seen\""]
+    env:
+        EXTRA: extra
+    image: foo:1.0
+"#,
+        )?;
+
+        // The configuration needn't be in the project root, e.g. with -c
+        let config_dir = dir.path().join("config");
+        fs::create_dir(&config_dir)?;
+        image.obtain_image(
+            dir.path(),
+            &config_dir,
+            dir.path(),
+            false,
+            &mut crate::events::Discard,
+        )?;
+
+        assert_eq!(
+            fs::read_to_string(config_dir.join("seen"))?,
+            format!("foo:1.0 {} extra\n", config_dir.display())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_exec_in_image_uses_docker_context() -> Result<(), Error> {
+        let exec: ExecSpec =
+            serde_yaml::from_str("command: foo\nimage: foo:1.0\nin_image: foo-tools:2.0")?;
+        crate::command::set_docker_context(Some("remote"))?;
+        let command = exec
+            .command(Path::new("/host/src"), Path::new("/host/src"))
+            .build();
+        crate::command::set_docker_context(None)?;
+
+        let args: Vec<_> = command.get_args().take(4).collect();
+        assert_eq!(args, vec!["--context", "remote", "run", "--rm"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_doesnt_exec() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let image: Image = serde_yaml::from_str(
+            "exec:\n    command: touch\n    args: [\"seen\"]\n    image: foo:1.0",
+        )?;
+
+        let mut events = Vec::new();
+        assert_eq!(
+            image.obtain_image(dir.path(), dir.path(), dir.path(), true, &mut events)?,
+            "foo:1.0"
+        );
+        assert!(!dir.path().join("seen").exists());
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_doesnt_download_dockerfile() -> Result<(), Error> {
+        let workspace = tempfile::tempdir()?;
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  dockerfile: https://example.invalid/Dockerfile\n  dockerfile_headers:\n    PRIVATE-TOKEN: TOKEN",
+        )?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let downloads = Downloads::fetch(build, workspace.path(), true)?;
+        assert!(downloads.dockerfile().is_none());
+        assert!(!workspace.path().join("dockerfiles").exists());
+
+        // The dockerfile is piped in rather than naming a removed download
+        let command = build.command("foo", Path::new("/root"), &downloads, &[], true);
+        assert_eq!(
+            build.describe_dry_run(&command),
+            concat!(
+                "curl -fsSL -H \"PRIVATE-TOKEN: \"\"${TOKEN}\" https://example.invalid/Dockerfile",
+                " | docker build -t foo -f - /root/."
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_doesnt_download_context() -> Result<(), Error> {
+        let workspace = tempfile::tempdir()?;
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  context: https://example.invalid/context.tar.gz\n  context_sha256: 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        )?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let downloads = Downloads::fetch(build, workspace.path(), true)?;
+        assert!(downloads.context().is_none());
+        assert!(!workspace.path().join("contexts").exists());
+
+        let command = build.command("foo", Path::new("/root"), &downloads, &[], true);
+        assert_eq!(
+            build.describe_dry_run(&command),
+            "docker build -t foo -f Dockerfile https://example.invalid/context.tar.gz"
+        );
+        Ok(())
     }
 
     #[test]
@@ -302,13 +2061,1315 @@ image:
             image: Image::Yaml {
                 yaml: YamlSpec::Url {
                     url: "https://example.com/example.yaml".try_into().unwrap(),
-                    key: "variables.RUST-IMAGE".into(),
+                    lookup: YamlLookup::Key {
+                        key: "variables.RUST-IMAGE".into(),
+                        join: None,
+                    },
                     headers: Some(hashmap!("PRIVATE-TOKEN".into() => "LOCAL_ENV_VARIABLE".into())),
+                    retries: 0,
+                    max_size: None,
                 },
             },
         };
 
-        let actual: TestImage = serde_yaml::from_str(&yaml).unwrap();
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
         assert!(actual == expected);
     }
+
+    #[test]
+    fn test_registry_mirror_explicit_registry() {
+        assert_eq!(
+            apply_registry_mirror("docker.io/rust/musl:1.0", "mirror.internal"),
+            "mirror.internal/rust/musl:1.0"
+        );
+        assert_eq!(
+            apply_registry_mirror("quay.io/foo/bar:1.0", "mirror.internal"),
+            "quay.io/foo/bar:1.0"
+        );
+    }
+
+    #[test]
+    fn test_registry_mirror_implicit_registry() {
+        assert_eq!(
+            apply_registry_mirror("debian:sid", "mirror.internal/"),
+            "mirror.internal/library/debian:sid"
+        );
+        assert_eq!(
+            apply_registry_mirror("ekidd/rust-musl-builder", "mirror.internal"),
+            "mirror.internal/ekidd/rust-musl-builder"
+        );
+        assert_eq!(
+            apply_registry_mirror("localhost:5000/foo", "mirror.internal"),
+            "localhost:5000/foo"
+        );
+    }
+
+    #[test]
+    fn test_oci_layout_scheme() {
+        assert_eq!(
+            oci_layout("oci:///srv/images/Tools"),
+            Some(PathBuf::from("/srv/images/Tools"))
+        );
+        assert_eq!(oci_layout("debian:sid"), None);
+        assert_eq!(oci_layout("localhost:5000/foo"), None);
+        assert_eq!(oci_layout("quay.io/foo/bar:1.0"), None);
+    }
+
+    #[test]
+    fn test_oci_layout_image() -> Result<(), Error> {
+        let image = Image::Name("oci:///srv/images/Tools".into());
+        assert_eq!(image.name()?, "floki-oci/tools:latest");
+        // The image is loaded, not pulled, so isn't mirrored
+        assert!(!image.is_remote());
+        assert_eq!(
+            image.mirrored_name(Some("mirror.internal"))?,
+            "floki-oci/tools:latest"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_oci_load_command() {
+        let command = oci_load_command(Path::new("/srv/images/tools"), "floki-oci/tools:latest");
+        assert_eq!(
+            describe_command(&command),
+            "skopeo copy oci:/srv/images/tools docker-daemon:floki-oci/tools:latest"
+        );
+    }
+
+    #[test]
+    fn test_registry_mirror_ignores_built_images() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str("build:\n  name: foo")?;
+        assert_eq!(image.mirrored_name(Some("mirror.internal"))?, "foo:floki");
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_with_output() -> Result<(), Error> {
+        let image: Image =
+            serde_yaml::from_str("build:\n  name: foo\n  output: type=local,dest=out")?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &[],
+            true,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "build",
+                "--output",
+                "type=local,dest=out",
+                "-f",
+                "/root/Dockerfile",
+                "/root/."
+            ]
+        );
+        assert!(command
+            .get_envs()
+            .any(|(k, v)| k == "DOCKER_BUILDKIT" && v == Some("1".as_ref())));
+        assert!(!image.produces_image());
+        Ok(())
+    }
+
+    fn write_config(dir: &Path, name: &str, contents: &str) -> Result<PathBuf, Error> {
+        let file = dir.join(name);
+        fs::create_dir_all(file.parent().unwrap())?;
+        fs::write(&file, contents)?;
+        Ok(file)
+    }
+
+    #[test]
+    fn test_image_from_config() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        write_config(
+            dir.path(),
+            "build/floki.yaml",
+            "image:\n  build:\n    name: tools",
+        )?;
+        let app = write_config(
+            dir.path(),
+            "app/floki.yaml",
+            "image:\n  config: ../build/floki.yaml",
+        )?;
+
+        let config = crate::config::FlokiConfig::from_files(&[app], None)?;
+        assert_eq!(config.image.name()?, "tools:floki");
+
+        let (image, root) = match &config.image {
+            Image::FromConfig { config } => referenced_image(config)?,
+            _ => panic!("expected an image from another config"),
+        };
+        assert!(matches!(image, Image::Build { .. }));
+        assert_eq!(root, fs::canonicalize(dir.path().join("build"))?);
+
+        // The reference is relative to the file it's in, even when an
+        // earlier file in another directory is merged first
+        let base = write_config(dir.path(), "floki.yaml", "image: debian\nshell: bash")?;
+        let app = dir.path().join("app/floki.yaml");
+        let config = crate::config::FlokiConfig::from_files(&[base, app], None)?;
+        assert_eq!(config.image.name()?, "tools:floki");
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_from_config_cycle() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let a = write_config(dir.path(), "a.yaml", "image:\n  config: b.yaml")?;
+        write_config(dir.path(), "b.yaml", "image:\n  config: a.yaml")?;
+
+        let config = crate::config::FlokiConfig::from_files(&[a], None)?;
+        let error = config.image.name().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::ImageReferenceCycle { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pull_command() {
+        let command = pull_command("quay.io/foo/bar:1.0");
+        assert_eq!(command.get_program(), "docker");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec!["pull", "quay.io/foo/bar:1.0"]
+        );
+        assert_eq!(
+            describe_command(&command),
+            "docker pull quay.io/foo/bar:1.0"
+        );
+    }
+
+    #[test]
+    fn test_first_repo_digest() {
+        let output =
+            "debian@sha256:0123456789abcdef\nmirror.internal/debian@sha256:0123456789abcdef\n";
+        assert_eq!(
+            first_repo_digest(output),
+            Some("debian@sha256:0123456789abcdef".to_string())
+        );
+
+        // Images which were built locally and never pushed have no digests
+        assert_eq!(first_repo_digest(""), None);
+        assert_eq!(first_repo_digest("\n"), None);
+    }
+
+    #[test]
+    fn test_build_input_hash() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        fs::write(root.path().join("Dockerfile"), "FROM debian:sid\n")?;
+        fs::create_dir(root.path().join("src"))?;
+        fs::write(root.path().join("src/main.c"), "int main() {}\n")?;
+
+        let image: Image = serde_yaml::from_str("build:\n  name: foo\n  cache_build: true")?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let hash = build.input_hash(root.path(), None, &[])?;
+        assert_eq!(hash, build.input_hash(root.path(), None, &[])?);
+
+        // Changes to the context change the hash
+        fs::write(root.path().join("src/main.c"), "int main() { return 1; }\n")?;
+        assert_ne!(hash, build.input_hash(root.path(), None, &[])?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_input_hash_skips_ignored_files() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        fs::write(root.path().join("Dockerfile"), "FROM debian:sid\n")?;
+        fs::write(root.path().join(".dockerignore"), "target\n*.log\n")?;
+        fs::create_dir(root.path().join("target"))?;
+        // A symlink loop is hashed as a link rather than followed
+        std::os::unix::fs::symlink(".", root.path().join("loop"))?;
+
+        let image: Image = serde_yaml::from_str("build:\n  name: foo\n  cache_build: true")?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+        let hash = build.input_hash(root.path(), None, &[])?;
+
+        fs::write(root.path().join("target/app"), "built")?;
+        fs::write(root.path().join("build.log"), "output")?;
+        assert_eq!(hash, build.input_hash(root.path(), None, &[])?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_input_hash_covers_settings() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        fs::write(root.path().join("Dockerfile"), "FROM debian:sid\n")?;
+
+        let hash = |yaml: &str| -> Result<String, Error> {
+            match serde_yaml::from_str(yaml)? {
+                Image::Build { build } => build.input_hash(root.path(), None, &[]),
+                _ => panic!("expected a build image"),
+            }
+        };
+        let base = hash("build:\n  name: foo")?;
+        assert_ne!(base, hash("build:\n  name: foo\n  ssh:\n    - default")?);
+        assert_ne!(
+            base,
+            hash("build:\n  name: foo\n  output: type=local,dest=out")?
+        );
+        assert_ne!(
+            base,
+            hash("build:\n  name: foo\n  build_host: ssh://builder")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_docker_ignore() {
+        let ignore = DockerIgnore::parse("# comment\n*.log\n/target\ndocs/**/*.png\n!keep.log\n");
+        assert!(ignore.excludes(Path::new("build.log")));
+        assert!(!ignore.excludes(Path::new("keep.log")));
+        assert!(!ignore.excludes(Path::new("src/build.log")));
+        assert!(ignore.excludes(Path::new("target/debug/app")));
+        assert!(ignore.excludes(Path::new("docs/a/b/logo.png")));
+        assert!(ignore.excludes(Path::new("docs/logo.png")));
+        assert!(!ignore.excludes(Path::new("docs/logo.svg")));
+        assert!(!ignore.excludes(Path::new("src/main.c")));
+
+        // Exceptions mean excluded directories still need looking in
+        assert!(!ignore.skips_directory(Path::new("target")));
+        assert!(DockerIgnore::parse("target").skips_directory(Path::new("target")));
+    }
+
+    #[test]
+    fn test_build_targets() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  target: dev\n  targets:\n    test: foo:test\n    release: foo:release",
+        )?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let commands = build.targets_commands(Path::new("/root"), &Downloads::default(), &[], true);
+        let built: Vec<(&str, Vec<_>)> = commands
+            .iter()
+            .map(|(tag, command)| {
+                (
+                    *tag,
+                    command.get_args().filter_map(|arg| arg.to_str()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            built,
+            vec![
+                (
+                    "foo:release",
+                    vec![
+                        "build",
+                        "-t",
+                        "foo:release",
+                        "-f",
+                        "/root/Dockerfile",
+                        "--target",
+                        "release",
+                        "/root/."
+                    ]
+                ),
+                (
+                    "foo:test",
+                    vec![
+                        "build",
+                        "-t",
+                        "foo:test",
+                        "-f",
+                        "/root/Dockerfile",
+                        "--target",
+                        "test",
+                        "/root/."
+                    ]
+                ),
+            ]
+        );
+
+        // The image to run is still built from the single target
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &[],
+            true,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "build",
+                "-t",
+                "foo:floki",
+                "-f",
+                "/root/Dockerfile",
+                "--target",
+                "dev",
+                "/root/."
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_git_build_args() -> Result<(), Error> {
+        let image: Image =
+            serde_yaml::from_str("build:\n  name: foo\n  auto_git_build_args: true")?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+        let git = |directory: &Path, args: &[&str]| {
+            assert_eq!(directory, Path::new("/root"));
+            match args {
+                ["rev-parse", "HEAD"] => Some("0123abc".to_string()),
+                ["symbolic-ref", "--short", "HEAD"] => Some("main".to_string()),
+                _ => None,
+            }
+        };
+
+        let build_args = build.git_build_args(Path::new("/root"), git);
+        assert_eq!(
+            build_args,
+            vec![
+                ("GIT_COMMIT".to_string(), "0123abc".to_string()),
+                ("GIT_BRANCH".to_string(), "main".to_string())
+            ]
+        );
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &build_args,
+            true,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "build",
+                "-t",
+                "foo:floki",
+                "-f",
+                "/root/Dockerfile",
+                "--build-arg",
+                "GIT_COMMIT=0123abc",
+                "--build-arg",
+                "GIT_BRANCH=main",
+                "/root/."
+            ]
+        );
+
+        // A detached HEAD has no branch
+        let detached = |_: &Path, args: &[&str]| match args {
+            ["rev-parse", "HEAD"] => Some("0123abc".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            build.git_build_args(Path::new("/root"), detached),
+            vec![("GIT_COMMIT".to_string(), "0123abc".to_string())]
+        );
+
+        assert_eq!(build.git_warning(Path::new("/root"), detached), None);
+
+        // Outside a repository, the build goes ahead without them, with a
+        // warning
+        assert!(build
+            .git_build_args(Path::new("/root"), |_, _| None)
+            .is_empty());
+        assert_eq!(
+            build.git_warning(Path::new("/root"), |_, _| None),
+            Some(Warning::NotAGitRepository {
+                directory: "/root".into()
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_should_skip_build() -> Result<(), Error> {
+        assert!(should_skip_build(Some("abc"), "abc", || Ok(true))?);
+        // The inputs changed
+        assert!(!should_skip_build(Some("abc"), "def", || Ok(true))?);
+        // The image was never built, or has since been removed
+        assert!(!should_skip_build(None, "abc", || Ok(true))?);
+        assert!(!should_skip_build(Some("abc"), "abc", || Ok(false))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_skipped_builds_need_every_target() -> Result<(), Error> {
+        let image: Image =
+            serde_yaml::from_str("build:\n  name: foo\n  targets:\n    builder: foo-builder")?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let mut images: Vec<_> = build.built_images("foo").collect();
+        images.sort_unstable();
+        assert_eq!(images, vec!["foo", "foo-builder"]);
+
+        let exists = |local: &'static [&'static str]| {
+            move |image: &str| -> Result<bool, Error> { Ok(local.contains(&image)) }
+        };
+        assert!(all_exist(
+            build.built_images("foo"),
+            exists(&["foo", "foo-builder"])
+        )?);
+        // The target's image has since been removed
+        assert!(!all_exist(build.built_images("foo"), exists(&["foo"]))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_with_git_context() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  context: https://github.com/me/tools.git#main:docker\n  dockerfile: Dockerfile.dev",
+        )?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+        assert_eq!(
+            build.remote_context().map(|url| url.to_string()),
+            Some("https://github.com/me/tools.git#main:docker".to_string())
+        );
+
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &[],
+            true,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "build",
+                "-t",
+                "foo:floki",
+                "-f",
+                "Dockerfile.dev",
+                "https://github.com/me/tools.git#main:docker"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_context_is_not_remote() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str("build:\n  name: foo\n  context: ./docker")?;
+        match &image {
+            Image::Build { build } => assert_eq!(build.remote_context(), None),
+            _ => panic!("expected a build image"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_log_spec() -> Result<(), Error> {
+        let image: Image =
+            serde_yaml::from_str("build:\n  name: foo\n  build_log: logs/build.log")?;
+        assert!(matches!(
+            image,
+            Image::Build {
+                build: BuildSpec {
+                    build_log: Some(ref log),
+                    ..
+                }
+            } if log == Path::new("logs/build.log")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_dockerfile() -> Result<(), Error> {
+        let remote = |yaml: &str| -> Result<Option<Url>, Error> {
+            match serde_yaml::from_str(yaml)? {
+                Image::Build { build } => Ok(build.remote_dockerfile()),
+                _ => panic!("expected a build image"),
+            }
+        };
+        assert_eq!(
+            remote("build:\n  name: foo\n  dockerfile: https://example.com/docker/Dockerfile")?,
+            Some("https://example.com/docker/Dockerfile".try_into()?)
+        );
+        assert_eq!(
+            remote("build:\n  name: foo\n  dockerfile: docker/Dockerfile")?,
+            None
+        );
+        assert_eq!(remote("build:\n  name: foo")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_with_downloaded_dockerfile() -> Result<(), Error> {
+        let workspace = tempfile::tempdir()?;
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  dockerfile: https://example.com/Dockerfile\n  dockerfile_headers:\n    PRIVATE-TOKEN: TOKEN",
+        )?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let downloads = Downloads {
+            dockerfile: Some(DownloadedFile::write(
+                workspace.path(),
+                "Dockerfile",
+                b"FROM debian:sid\n",
+            )?),
+            context: None,
+        };
+        let path = downloads.dockerfile().unwrap().to_path_buf();
+        assert_eq!(fs::read_to_string(&path)?, "FROM debian:sid\n");
+
+        let command = build.command("foo", Path::new("/root"), &downloads, &[], true);
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                std::ffi::OsStr::new("build"),
+                std::ffi::OsStr::new("-t"),
+                std::ffi::OsStr::new("foo"),
+                std::ffi::OsStr::new("-f"),
+                path.as_os_str(),
+                std::ffi::OsStr::new("/root/."),
+            ]
+        );
+
+        // The download is cleaned up once it's no longer needed
+        drop(downloads);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    fn hashed(contents: &[u8]) -> Sha256 {
+        let mut hasher = Sha256::new();
+        hasher.update(contents);
+        hasher
+    }
+
+    #[test]
+    fn test_verify_context_checksum() -> Result<(), Error> {
+        let url = Url::parse("https://example.com/context.tar.gz")?;
+        // The SHA-256 digest of "hello"
+        let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        verify_sha256(&url, hashed(b"hello"), digest)?;
+        verify_sha256(&url, hashed(b"hello"), &digest.to_uppercase())?;
+
+        let error = verify_sha256(&url, hashed(b"goodbye"), digest).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::ChecksumMismatch { expected, actual, .. })
+                if expected == digest && actual != digest
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_with_checked_context() -> Result<(), Error> {
+        let workspace = tempfile::tempdir()?;
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  context: https://example.com/context.tar.gz\n  context_sha256: 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        )?;
+        image.validate()?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        // The checked download is passed on stdin, rather than the url
+        let downloads = Downloads {
+            dockerfile: None,
+            context: Some(DownloadedFile::write(workspace.path(), "tar", b"hello")?),
+        };
+        let command = build.command("foo", Path::new("/root"), &downloads, &[], true);
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, vec!["build", "-t", "foo", "-f", "Dockerfile", "-"]);
+
+        // Only remote contexts are downloaded to be checked
+        let local: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  context_sha256: 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+        )?;
+        assert!(matches!(
+            local.validate().unwrap_err().downcast_ref::<FlokiError>(),
+            Some(FlokiError::ChecksumNeedsTarballContext { image }) if image == "foo"
+        ));
+
+        // Nor can git contexts be checked
+        for context in [
+            "https://github.com/me/tools.git#main",
+            "git://example.com/tools",
+        ] {
+            let git: Image = serde_yaml::from_str(&format!(
+                "build:\n  name: foo\n  context: {}\n  context_sha256: 2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                context
+            ))?;
+            assert!(matches!(
+                git.validate().unwrap_err().downcast_ref::<FlokiError>(),
+                Some(FlokiError::ChecksumNeedsTarballContext { .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_log_is_written() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let log = dir.path().join("logs/build.log");
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo step 1/2; echo step 2/2 >&2; exit 3"]);
+        let exit_status = spawn_logged(&mut command, &log)?;
+
+        assert_eq!(exit_status.code(), Some(3));
+        let contents = fs::read_to_string(&log)?;
+        assert!(contents.contains("step 1/2\n"));
+        assert!(contents.contains("step 2/2\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_host() -> Result<(), Error> {
+        let docker_host = |yaml: &str| -> Result<Option<String>, Error> {
+            let image: Image = serde_yaml::from_str(yaml)?;
+            let build = match &image {
+                Image::Build { build } => build,
+                _ => panic!("expected a build image"),
+            };
+            let command = build.command(
+                &image.name()?,
+                Path::new("/root"),
+                &Downloads::default(),
+                &[],
+                true,
+            );
+            let host = command
+                .get_envs()
+                .find(|(k, _)| *k == "DOCKER_HOST")
+                .and_then(|(_, v)| v.map(|v| v.to_string_lossy().to_string()));
+            Ok(host)
+        };
+
+        assert_eq!(
+            docker_host("build:\n  name: foo\n  build_host: unix:///var/run/docker.sock")?,
+            Some("unix:///var/run/docker.sock".to_string())
+        );
+        assert_eq!(docker_host("build:\n  name: foo")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_with_ssh() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  ssh:\n    - default\n    - id=/keys/deploy",
+        )?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &[],
+            true,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "build",
+                "-t",
+                "foo:floki",
+                "-f",
+                "/root/Dockerfile",
+                "--ssh",
+                "default",
+                "--ssh",
+                "id=/keys/deploy",
+                "/root/."
+            ]
+        );
+        assert!(command
+            .get_envs()
+            .any(|(k, v)| k == "DOCKER_BUILDKIT" && v == Some("1".as_ref())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_progress_serde() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str("build:\n  name: foo\n  progress: plain")?;
+        match &image {
+            Image::Build { build } => assert_eq!(build.progress, Some(BuildProgress::Plain)),
+            _ => panic!("expected a build image"),
+        }
+        assert!(serde_yaml::from_str::<Image>("build:\n  name: foo\n  progress: fancy").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_progress_plain_without_terminal() -> Result<(), Error> {
+        assert_eq!(progress_mode(None, false), Some(BuildProgress::Plain));
+        assert_eq!(progress_mode(None, true), None);
+        assert_eq!(
+            progress_mode(Some(BuildProgress::Tty), false),
+            Some(BuildProgress::Tty)
+        );
+
+        let image: Image = serde_yaml::from_str("build:\n  name: foo")?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &[],
+            false,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "build",
+                "-t",
+                "foo:floki",
+                "-f",
+                "/root/Dockerfile",
+                "--progress",
+                "plain",
+                "/root/."
+            ]
+        );
+        // --progress needs BuildKit
+        assert!(command
+            .get_envs()
+            .any(|(k, v)| k == "DOCKER_BUILDKIT" && v == Some("1".as_ref())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_contexts_serde() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  build_contexts:\n    base: docker-image://alpine:3\n    shared: ../shared",
+        )?;
+        match &image {
+            Image::Build { build } => assert_eq!(
+                build.build_contexts,
+                maplit::btreemap!(
+                    "base".into() => "docker-image://alpine:3".into(),
+                    "shared".into() => "../shared".into()
+                )
+            ),
+            _ => panic!("expected a build image"),
+        }
+
+        let image: Image = serde_yaml::from_str("build:\n  name: foo")?;
+        match &image {
+            Image::Build { build } => assert!(build.build_contexts.is_empty()),
+            _ => panic!("expected a build image"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_with_build_contexts() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  build_contexts:\n    base: docker-image://alpine:3\n    shared: ../shared",
+        )?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &[],
+            true,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "build",
+                "-t",
+                "foo:floki",
+                "-f",
+                "/root/Dockerfile",
+                "--build-context",
+                "base=docker-image://alpine:3",
+                "--build-context",
+                "shared=/root/../shared",
+                "/root/."
+            ]
+        );
+        assert!(command
+            .get_envs()
+            .any(|(k, v)| k == "DOCKER_BUILDKIT" && v == Some("1".as_ref())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotations_serde() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  buildx: true\n  annotations:\n    org.opencontainers.image.title: foo",
+        )?;
+        match &image {
+            Image::Build { build } => {
+                assert!(build.buildx);
+                assert_eq!(
+                    build.annotations,
+                    maplit::btreemap!("org.opencontainers.image.title".into() => "foo".into())
+                );
+            }
+            _ => panic!("expected a build image"),
+        }
+
+        let image: Image = serde_yaml::from_str("build:\n  name: foo")?;
+        match &image {
+            Image::Build { build } => {
+                assert!(!build.buildx);
+                assert!(build.annotations.is_empty());
+            }
+            _ => panic!("expected a build image"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_buildx_build_with_annotations() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  buildx: true\n  annotations:\n    org.opencontainers.image.title: foo\n    org.opencontainers.image.version: \"1.0\"",
+        )?;
+        image.validate()?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &[],
+            true,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "buildx",
+                "build",
+                "-t",
+                "foo:floki",
+                "--load",
+                "-f",
+                "/root/Dockerfile",
+                "--annotation",
+                "org.opencontainers.image.title=foo",
+                "--annotation",
+                "org.opencontainers.image.version=1.0",
+                "/root/."
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_quiet_build() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str("build:\n  name: foo\n  quiet_build: true")?;
+        image.validate()?;
+        let build = match &image {
+            Image::Build { build } => build,
+            _ => panic!("expected a build image"),
+        };
+
+        // Even without a terminal, no progress mode is asked for
+        let command = build.command(
+            &image.name()?,
+            Path::new("/root"),
+            &Downloads::default(),
+            &[],
+            false,
+        );
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec![
+                "build",
+                "-t",
+                "foo:floki",
+                "-f",
+                "/root/Dockerfile",
+                "-q",
+                "/root/."
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_quiet_build_conflicts() -> Result<(), Error> {
+        for (yaml, conflict) in [
+            ("progress: plain", "progress"),
+            ("build_log: build.log", "build_log"),
+        ] {
+            let image: Image = serde_yaml::from_str(&format!(
+                "build:\n  name: foo\n  quiet_build: true\n  {}",
+                yaml
+            ))?;
+            let error = image.validate().unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<FlokiError>(),
+                Some(FlokiError::QuietBuildConflict { image, option })
+                    if image == "foo" && option == conflict
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_annotations_need_buildx() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str(
+            "build:\n  name: foo\n  annotations:\n    org.opencontainers.image.title: foo",
+        )?;
+        let error = image.validate().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::AnnotationsNeedBuildx { image }) if image == "foo"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_ssh_agent_to_build() -> Result<(), Error> {
+        let mut image: Image = serde_yaml::from_str("build:\n  name: foo")?;
+        image.forward_ssh_agent_to_build();
+        match &image {
+            Image::Build { build } => assert_eq!(build.ssh, vec!["default"]),
+            _ => panic!("expected a build image"),
+        }
+
+        // Explicit ssh settings are left alone
+        let mut image: Image =
+            serde_yaml::from_str("build:\n  name: foo\n  ssh:\n    - id=/keys/deploy")?;
+        image.forward_ssh_agent_to_build();
+        match &image {
+            Image::Build { build } => assert_eq!(build.ssh, vec!["id=/keys/deploy"]),
+            _ => panic!("expected a build image"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_without_output_produces_image() -> Result<(), Error> {
+        let image: Image = serde_yaml::from_str("build:\n  name: foo")?;
+        assert!(image.produces_image());
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_templated_name() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let file = tmp_dir.path().join("versions.yaml");
+        fs::write(
+            &file,
+            "image:\n  registry: quay.io\n  repo: foo/bar\n  tag: \"1.2\"\n",
+        )?;
+
+        let yaml = format!(
+            r#"
+image:
+    yaml:
+        file: {}
+        template: "{{registry}}/{{repo}}:{{tag}}"
+        keys:
+            registry: image.registry
+            repo: image.repo
+            tag: image.tag
+"#,
+            file.display()
+        );
+        let actual: TestImage = serde_yaml::from_str(&yaml)?;
+        assert!(matches!(
+            actual.image,
+            Image::Yaml {
+                yaml: YamlSpec::File {
+                    lookup: YamlLookup::Template { .. },
+                    ..
+                }
+            }
+        ));
+        assert_eq!(actual.image.name()?, "quay.io/foo/bar:1.2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_template_missing_placeholder() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let file = tmp_dir.path().join("versions.yaml");
+        fs::write(&file, "repo: foo/bar\n")?;
+
+        let image = Image::Yaml {
+            yaml: YamlSpec::File {
+                file,
+                lookup: YamlLookup::Template {
+                    template: "{repo}:{tag}".into(),
+                    keys: maplit::btreemap!("repo".into() => "repo".into()),
+                },
+            },
+        };
+        assert!(image.name().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_single_key_name() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let file = tmp_dir.path().join("ci.yaml");
+        fs::write(&file, "variables:\n  RUST-IMAGE: rust:1.57\n")?;
+
+        let image = Image::Yaml {
+            yaml: YamlSpec::File {
+                file,
+                lookup: YamlLookup::Key {
+                    key: "variables.RUST-IMAGE".into(),
+                    join: None,
+                },
+            },
+        };
+        assert_eq!(image.name()?, "rust:1.57");
+        Ok(())
+    }
+
+    #[test]
+    fn test_memoized_resolves_once() -> Result<(), Error> {
+        let cache = Mutex::new(BTreeMap::new());
+        let fetches = std::cell::Cell::new(0);
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            Ok("rust:1.57".to_string())
+        };
+
+        assert_eq!(memoized(&cache, "spec", fetch)?, "rust:1.57");
+        assert_eq!(memoized(&cache, "spec", fetch)?, "rust:1.57");
+        assert_eq!(fetches.get(), 1);
+
+        // Failures are retried
+        assert!(memoized(&cache, "other", || Err(anyhow!("unreachable"))).is_err());
+        assert_eq!(memoized(&cache, "other", fetch)?, "rust:1.57");
+        assert_eq!(fetches.get(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_name_read_once() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let file = tmp_dir.path().join("ci.yaml");
+        fs::write(&file, "image: rust:1.57\n")?;
+
+        let image = Image::Yaml {
+            yaml: YamlSpec::File {
+                file: file.clone(),
+                lookup: YamlLookup::Key {
+                    key: "image".into(),
+                    join: None,
+                },
+            },
+        };
+        assert_eq!(image.name()?, "rust:1.57");
+        // The second lookup uses the name resolved by the first
+        fs::remove_file(&file)?;
+        assert_eq!(image.name()?, "rust:1.57");
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_joined_list_name() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let file = tmp_dir.path().join("manifest.yaml");
+        fs::write(&file, "image:\n  - registry.example.com/tools\n  - 2\n")?;
+
+        let image = |join: Option<&str>| Image::Yaml {
+            yaml: YamlSpec::File {
+                file: file.clone(),
+                lookup: YamlLookup::Key {
+                    key: "image".into(),
+                    join: join.map(Into::into),
+                },
+            },
+        };
+        assert_eq!(image(Some(":")).name()?, "registry.example.com/tools:2");
+        // Without a separator, a list isn't an image name
+        assert!(image(None).name().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_joined_key_spec() {
+        let yaml = "image:\n  yaml:\n    file: manifest.yaml\n    key: image\n    join: \":\"";
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            actual.image,
+            Image::Yaml {
+                yaml: YamlSpec::File {
+                    file: "manifest.yaml".into(),
+                    lookup: YamlLookup::Key {
+                        key: "image".into(),
+                        join: Some(":".into()),
+                    },
+                },
+            }
+        );
+    }
+
+    /// Serve the given HTTP responses, one per connection, returning the
+    /// url to fetch and a count of the requests served
+    fn serve(responses: Vec<&'static str>) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        (url.parse().unwrap(), served)
+    }
+
+    const UNAVAILABLE: &str =
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const NOT_FOUND: &str =
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    const OK: &str =
+        "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nimage: foo\n";
+
+    #[test]
+    fn test_fetch_url_retries_server_errors() -> Result<(), Error> {
+        let (url, served) = serve(vec![UNAVAILABLE, OK]);
+
+        let body = fetch_url(&url, &None, 2, Duration::from_millis(1), 1024)?;
+
+        assert_eq!(body, "image: foo\n");
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_url_does_not_retry_client_errors() {
+        let (url, served) = serve(vec![NOT_FOUND, OK]);
+
+        let result = fetch_url(&url, &None, 2, Duration::from_millis(1), 1024);
+
+        assert!(result.is_err());
+        assert_eq!(served.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_fetch_url_gives_up_after_retries() {
+        let (url, served) = serve(vec![UNAVAILABLE, UNAVAILABLE, OK]);
+
+        let result = fetch_url(&url, &None, 1, Duration::from_millis(1), 1024);
+
+        assert!(result.is_err());
+        assert_eq!(served.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_fetch_header() -> Result<(), Error> {
+        let (url, _) = serve(vec![
+            "HTTP/1.1 200 OK\r\nDocker-Content-Digest: sha256:abc123\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ]);
+
+        let digest = fetch_header(
+            &url,
+            &None,
+            0,
+            Duration::from_millis(1),
+            "docker-content-digest",
+        )?;
+
+        assert_eq!(digest, "sha256:abc123");
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_header_missing() {
+        let (url, _) = serve(vec![OK]);
+
+        let error = fetch_header(
+            &url,
+            &None,
+            0,
+            Duration::from_millis(1),
+            "Docker-Content-Digest",
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::MissingResponseHeader { header, .. }) if header == "Docker-Content-Digest"
+        ));
+    }
+
+    #[test]
+    fn test_header_lookup_spec() {
+        let yaml = "image:\n  yaml:\n    url: https://example.com/manifest\n    from_header: Docker-Content-Digest";
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        match actual.image {
+            Image::Yaml {
+                yaml: YamlSpec::Url { lookup, .. },
+            } => assert_eq!(
+                lookup,
+                YamlLookup::Header {
+                    from_header: "Docker-Content-Digest".into()
+                }
+            ),
+            image => panic!("unexpected image {:?}", image),
+        }
+    }
+
+    #[test]
+    fn test_fetch_url_rejects_oversized_body() {
+        let (url, _) = serve(vec![OK]);
+
+        let error = fetch_url(&url, &None, 0, Duration::from_millis(1), 5).unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::ResponseTooLarge { max_size: 5, .. })
+        ));
+    }
 }
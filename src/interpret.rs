@@ -1,51 +1,172 @@
 use crate::command;
-use crate::command::DockerCommandBuilder;
+use crate::command::{CommandSpec, DockerCommandBuilder};
+use crate::config::{PullPolicy, RegistryAuth, Runtime, Shell};
 use crate::dind::Dind;
+use crate::errors::FlokiError;
+use crate::events::{Emitter, Event};
 use crate::spec;
-use crate::volumes::resolve_volume_mounts;
+use crate::summary::{ImageSource, Summary};
+use crate::volumes::{
+    instantiate_volumes, resolve_tmpfs_mounts, resolve_volume_mounts, setup_commands,
+};
 
 use anyhow::Error;
 use std::path;
 
+/// Run the floki container, recording what was done in the summary (if
+/// one is wanted) and reporting progress to the events. The command is run in the inner
+/// shell, or without one, the inner shell is run interactively.
 pub(crate) fn run_floki_container(
     spec: &spec::FlokiSpec,
-    inner_command: &str,
+    command: Option<&[String]>,
+    mut summary: Option<&mut Summary>,
+    events: &mut dyn Emitter,
 ) -> Result<(), Error> {
-    spec.image.obtain_image(&spec.paths.root)?;
+    if spec.cleanup_orphans {
+        if let Err(e) = command::cleanup_orphans() {
+            warn!("Failed to clean up orphaned floki containers: {}", e);
+        }
+    }
 
-    let mut cmd = command::DockerCommandBuilder::new(&spec.image.name()?)
-        .add_volume((&spec.paths.root, &spec.mount));
+    spec.image.obtain_image(
+        &spec.paths.root,
+        spec.paths.config_dir(),
+        &spec.paths.workspace,
+        false,
+        events,
+    )?;
 
-    let volumes = resolve_volume_mounts(&spec.paths.config, &spec.paths.workspace, &spec.volumes);
-    instantiate_volumes(&volumes)?;
+    let image = spec.image.mirrored_name(spec.registry_mirror.as_deref())?;
+    // Finding where the image came from takes a docker call or two, so is
+    // only done for the summary
+    if let Some(summary) = summary.as_deref_mut() {
+        summary.image_source = Some(image_source(spec, &image)?);
+        summary.image = Some(image.clone());
+    }
 
-    cmd = configure_volumes(cmd, &volumes);
-    cmd = cmd.add_environment("FLOKI_HOST_MOUNTDIR", &spec.paths.root);
-    cmd = cmd.add_environment("FLOKI_HOST_UID", spec.user.uid.to_string());
-    cmd = cmd.add_environment("FLOKI_HOST_GID", spec.user.gid.to_string());
-    cmd = cmd.set_working_directory(&spec.paths.internal_working_directory);
+    // docker run would pull the image anyway - pulling it first lets the
+    // pull be reported, so is only done when events are wanted. Pull
+    // policies are left to docker.
+    if events.listening()
+        && spec.pull_policy.is_none()
+        && spec.image.is_remote()
+        && !crate::image::image_exists_locally(&image)?
+    {
+        pull_with_events(&image, spec.registry_auth.as_ref(), false, events)?;
+    }
+
+    if !spec.image.produces_image() {
+        info!("Build exported its output rather than an image; not running a container");
+        return Ok(());
+    }
 
-    if spec.user.forward {
+    let shell = resolve_shell(&spec.shell, || Ok(image.clone()))?;
+    let inner_command = match command {
+        Some(command) => command_in_shell(shell.inner_shell(), command),
+        None => interactive_shell(
+            shell.inner_shell(),
+            spec.shell_rc.as_ref().map(|_| SHELL_RC_PATH),
+        ),
+    };
+
+    let mut cmd = configure_project_mount(command::DockerCommandBuilder::new(&image), spec)
+        .set_keep_on_failure(spec.keep_on_failure)
+        .set_tty(spec.tty)
+        .set_timeout(spec.session_timeout);
+    if let (Some(rc), None) = (&spec.shell_rc, command) {
+        cmd = configure_shell_rc(cmd, rc);
+    }
+
+    // Pull policies only make sense for images which come from a registry
+    if let (Some(policy), true) = (spec.pull_policy, spec.image.is_remote()) {
+        check_pull_policy(policy, &image, || {
+            crate::image::image_exists_locally(&image)
+        })?;
         cmd = cmd
-            .add_docker_switch("--user")
-            .add_docker_switch(&format!("{}:{}", spec.user.uid, spec.user.gid));
+            .add_docker_switch("--pull")
+            .add_docker_switch(policy.value());
+    }
+    if let Some(summary) = summary.as_deref_mut() {
+        summary.add_volume(&spec.paths.mounted, &spec.mount);
+    }
+
+    let volume_key = spec.volume_key.as_deref();
+    let volumes = resolve_volume_mounts(
+        &spec.paths.config,
+        &spec.paths.workspace,
+        volume_key,
+        &spec.volumes,
+    );
+    instantiate_volumes(
+        &spec.paths.config,
+        &spec.paths.workspace,
+        volume_key,
+        &spec.volumes,
+    )?;
+    let mut init = spec.init.clone();
+    init.extend(setup_commands(
+        &spec.paths.config,
+        &spec.paths.workspace,
+        volume_key,
+        &spec.volumes,
+    ));
+    if let Some(summary) = summary {
+        for (src, dst, _) in volumes.iter() {
+            summary.add_volume(src, dst);
+        }
+    }
+
+    cmd = configure_volumes(cmd, &volumes);
+    cmd = configure_tmpfs_volumes(cmd, &resolve_tmpfs_mounts(&spec.volumes));
+    cmd = configure_environment(cmd, spec);
+    cmd = configure_forwarded_variables(
+        cmd,
+        &spec.forwarded_variables,
+        spec.forward_env_prefix.as_deref(),
+        |var| std::env::var_os(var),
+    );
+    cmd = configure_user(cmd, &spec.user);
+    if spec.user.forward && spec.user.map_names {
+        cmd = configure_user_names(cmd, &spec.user, &spec.paths.workspace)?;
     }
 
     if let Some(spec::SshAgent { path }) = &spec.ssh_agent {
         cmd = command::enable_forward_ssh_agent(cmd, path);
     }
 
-    if let Some(entrypoint) = &spec.entrypoint {
-        cmd = cmd.add_docker_switch(&format!("--entrypoint={}", entrypoint))
-    }
+    // The script is removed once the container has exited
+    let _script = if spec.entrypoint_script {
+        let script = EntrypointScript::write(&spec.paths.workspace, &init)?;
+        cmd = configure_entrypoint_script(cmd, spec.runtime, script.path());
+        Some(script)
+    } else {
+        if let Some(entrypoint) = &spec.entrypoint {
+            for switch in spec.runtime.entrypoint_switches(entrypoint) {
+                cmd = cmd.add_docker_switch(switch);
+            }
+        }
+        None
+    };
+
+    cmd = configure_container_options(cmd, spec);
+    let started = if spec.label_start_time {
+        Some(time::OffsetDateTime::now_utc())
+    } else {
+        None
+    };
+    cmd = configure_trace_labels(cmd, &spec.paths.config, started)?;
 
     for switch in &spec.docker_switches {
         cmd = cmd.add_docker_switch(switch);
     }
 
     // Finally configure dind, taking care to hold a handle for the linked dind container
-    let _handle = if let Some(spec::Dind { image }) = &spec.dind {
-        let dind = Dind::new(image, (&spec.paths.root, &spec.mount));
+    let _handle = if let Some(spec::Dind { image, healthcheck }) = &spec.dind {
+        let dind = Dind::new(
+            image,
+            (&spec.paths.mounted, &spec.mount),
+            healthcheck.as_deref(),
+        );
         cmd = command::enable_docker_in_docker(cmd, &dind)?;
         crate::dind::dind_preflight(image)?;
         Some(dind.launch()?)
@@ -53,8 +174,445 @@ pub(crate) fn run_floki_container(
         None
     };
 
-    let subshell_command = subshell_command(&spec.init, inner_command);
-    cmd.run(&[spec.shell.outer_shell(), "-c", &subshell_command])
+    // An entrypoint script has already run the init commands
+    let init: &[String] = if spec.entrypoint_script { &[] } else { &init };
+    let subshell_command = subshell_command(init, &user_command(&spec.user, &inner_command));
+    run_session(&image, events, || {
+        cmd.run(&[shell.outer_shell(), "-c", &subshell_command])
+    })
+}
+
+/// Run the container session, reporting when it starts and exits
+fn run_session(
+    image: &str,
+    events: &mut dyn Emitter,
+    session: impl FnOnce() -> Result<(), Error>,
+) -> Result<(), Error> {
+    events.emit(Event::ContainerStarted {
+        image: image.into(),
+    });
+    let result = session();
+    events.emit(Event::ContainerExited {
+        code: result.as_ref().err().map_or(0, crate::exit_code),
+    });
+    result
+}
+
+/// Pull the image unless the pull policy rules it out - never doesn't pull
+/// (and fails if the image isn't there), while missing only pulls images
+/// which aren't there
+pub(crate) fn pull_with_policy(
+    image: &str,
+    policy: Option<PullPolicy>,
+    auth: Option<&RegistryAuth>,
+    dry_run: bool,
+    events: &mut dyn Emitter,
+) -> Result<(), Error> {
+    if should_pull(policy, image, || crate::image::image_exists_locally(image))? {
+        pull_with_events(image, auth, dry_run, events)
+    } else {
+        info!("Not pulling {}, as its pull_policy is {:?}", image, policy);
+        Ok(())
+    }
+}
+
+/// Whether the pull policy lets an image be pulled. Docker is only asked
+/// whether the image is there if the policy depends on it.
+fn should_pull(
+    policy: Option<PullPolicy>,
+    image: &str,
+    image_exists: impl FnOnce() -> Result<bool, Error>,
+) -> Result<bool, Error> {
+    match policy {
+        None | Some(PullPolicy::Always) => Ok(true),
+        Some(PullPolicy::Missing) => Ok(!image_exists()?),
+        Some(PullPolicy::Never) => {
+            check_pull_policy(PullPolicy::Never, image, image_exists).map(|()| false)
+        }
+    }
+}
+
+/// Pull the image, logging in to its registry first if there are
+/// credentials, and reporting the pull to the events
+fn pull_with_events(
+    image: &str,
+    auth: Option<&RegistryAuth>,
+    dry_run: bool,
+    events: &mut dyn Emitter,
+) -> Result<(), Error> {
+    if let Some(auth) = auth {
+        crate::registry::login(auth, &crate::registry::OsKeychain, dry_run)?;
+    }
+    events.emit(Event::PullStarted {
+        image: image.into(),
+    });
+    crate::image::pull_image(image, dry_run)?;
+    events.emit(Event::PullFinished {
+        image: image.into(),
+    });
+    Ok(())
+}
+
+/// Where the entrypoint script is mounted in the container
+const ENTRYPOINT_SCRIPT_PATH: &str = "/floki-entrypoint.sh";
+
+/// A generated entrypoint script on the host, removed when dropped
+struct EntrypointScript {
+    path: path::PathBuf,
+}
+
+impl EntrypointScript {
+    fn write(workspace: &path::Path, init: &[String]) -> Result<Self, Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = workspace.join("entrypoints");
+        std::fs::create_dir_all(&directory)?;
+        let path = directory.join(format!("{}.sh", uuid::Uuid::new_v4()));
+        std::fs::write(&path, entrypoint_script(init))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(EntrypointScript { path })
+    }
+
+    fn path(&self) -> &path::Path {
+        &self.path
+    }
+}
+
+impl Drop for EntrypointScript {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            debug!("Couldn't remove entrypoint script {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// A script which runs the init commands, stopping if any fail, then
+/// replaces itself with the command it was given
+fn entrypoint_script(init: &[String]) -> String {
+    let mut script = String::from("#!/bin/sh\nset -e\n");
+    for command in init {
+        script.push_str(command);
+        script.push('\n');
+    }
+    script.push_str("exec \"$@\"\n");
+    script
+}
+
+/// Mount the entrypoint script into the container and use it
+fn configure_entrypoint_script(
+    cmd: DockerCommandBuilder,
+    runtime: Runtime,
+    script: &path::Path,
+) -> DockerCommandBuilder {
+    let mut cmd = cmd.add_docker_switch("-v").add_docker_switch(format!(
+        "{}:{}:ro",
+        script.display(),
+        ENTRYPOINT_SCRIPT_PATH
+    ));
+    for switch in runtime.entrypoint_switches(ENTRYPOINT_SCRIPT_PATH) {
+        cmd = cmd.add_docker_switch(switch);
+    }
+    cmd
+}
+
+/// Run the session, then the on exit commands from the given directory -
+/// whether or not the session succeeded. Failures of the on exit commands
+/// are only warned about.
+pub(crate) fn run_with_exit_hooks<T>(
+    hooks: &[CommandSpec],
+    directory: &path::Path,
+    session: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let result = session();
+    for hook in hooks {
+        if let Err(e) = hook.run_in(false, Some(directory)) {
+            warn!("on_exit command failed: {}", e);
+        }
+    }
+    result
+}
+
+/// The shell to use. With `shell: auto`, it's found by probing the image,
+/// so the image is only needed then.
+pub(crate) fn resolve_shell(
+    shell: &Shell,
+    image: impl FnOnce() -> Result<String, Error>,
+) -> Result<Shell, Error> {
+    match shell {
+        Shell::Shell(name) if name == "auto" => probe_shell(&image()?),
+        shell => Ok(shell.clone()),
+    }
+}
+
+/// Find a shell in the image, for configurations which ask for one
+fn probe_shell(image: &str) -> Result<Shell, Error> {
+    debug!("Probing image {} for a shell", image);
+    let output = command::docker()
+        .args(["run", "--rm", "--entrypoint", "which", image, "bash", "sh"])
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()?;
+    // which fails if any shell is missing, but still lists those it found
+    let shell = shell_from_probe(&String::from_utf8_lossy(&output.stdout));
+    debug!("Using shell {:?}", shell);
+    Ok(shell)
+}
+
+/// Prefer bash, falling back to sh - which is also used if the probe
+/// found nothing (e.g. because the image has no `which`)
+fn shell_from_probe(output: &str) -> Shell {
+    let found_bash = output
+        .lines()
+        .any(|line| path::Path::new(line.trim()).file_name() == Some("bash".as_ref()));
+    Shell::Shell(if found_bash { "bash" } else { "sh" }.into())
+}
+
+/// Fail early if the image can't be pulled and isn't present, rather than
+/// leaving docker to fail with "No such image"
+fn check_pull_policy(
+    policy: PullPolicy,
+    image: &str,
+    image_exists: impl FnOnce() -> Result<bool, Error>,
+) -> Result<(), Error> {
+    if policy == PullPolicy::Never && !image_exists()? {
+        Err(FlokiError::ImageNotPresentLocally {
+            image: image.into(),
+        }
+        .into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Work out where the image for the container comes from. Remote images
+/// which aren't present locally get pulled when the container starts.
+fn image_source(spec: &spec::FlokiSpec, image: &str) -> Result<ImageSource, Error> {
+    if !spec.image.is_remote() {
+        Ok(ImageSource::Built)
+    } else if crate::image::image_exists_locally(image)? {
+        if let Some(digest) = crate::image::local_image_digest(image)? {
+            info!("Using local image {} ({})", image, digest);
+        }
+        Ok(ImageSource::Cached)
+    } else {
+        Ok(ImageSource::Pulled)
+    }
+}
+
+/// Mount the project (or the current directory) and work in it
+fn configure_project_mount(
+    cmd: DockerCommandBuilder,
+    spec: &spec::FlokiSpec,
+) -> DockerCommandBuilder {
+    cmd.add_volume((&spec.paths.mounted, &spec.mount))
+        .set_working_directory(&spec.paths.internal_working_directory)
+}
+
+/// Add the switches for the configured container options
+fn configure_container_options(
+    cmd: DockerCommandBuilder,
+    spec: &spec::FlokiSpec,
+) -> DockerCommandBuilder {
+    let mut cmd = cmd; // Shadow as mutable
+
+    if let Some(memory_swap) = &spec.memory_swap {
+        cmd = cmd
+            .add_docker_switch("--memory-swap")
+            .add_docker_switch(memory_swap);
+    }
+
+    if spec.oom_kill_disable {
+        cmd = cmd.add_docker_switch("--oom-kill-disable");
+    }
+
+    if let Some(oom_score_adj) = spec.oom_score_adj {
+        cmd = cmd
+            .add_docker_switch("--oom-score-adj")
+            .add_docker_switch(oom_score_adj.to_string());
+    }
+
+    if let Some(pids_limit) = spec.pids_limit {
+        cmd = cmd
+            .add_docker_switch("--pids-limit")
+            .add_docker_switch(pids_limit.to_string());
+    }
+
+    if let Some(shm_size) = &spec.shm_size {
+        cmd = cmd
+            .add_docker_switch("--shm-size")
+            .add_docker_switch(shm_size);
+    }
+
+    if spec.read_only_rootfs {
+        cmd = cmd.add_docker_switch("--read-only");
+    }
+
+    if let Some(cgroup_parent) = &spec.cgroup_parent {
+        cmd = cmd
+            .add_docker_switch("--cgroup-parent")
+            .add_docker_switch(cgroup_parent);
+    }
+
+    if let Some(cgroupns) = spec.cgroupns {
+        cmd = cmd
+            .add_docker_switch("--cgroupns")
+            .add_docker_switch(cgroupns.value());
+    }
+
+    if let Some(isolation) = spec.isolation {
+        cmd = cmd
+            .add_docker_switch("--isolation")
+            .add_docker_switch(isolation.value());
+    }
+
+    if let Some(userns) = &spec.userns {
+        cmd = cmd.add_docker_switch("--userns").add_docker_switch(userns);
+    }
+
+    if let Some(stop_signal) = &spec.stop_signal {
+        cmd = cmd
+            .add_docker_switch("--stop-signal")
+            .add_docker_switch(stop_signal);
+    }
+
+    if let Some(mac_address) = &spec.mac_address {
+        cmd = cmd
+            .add_docker_switch("--mac-address")
+            .add_docker_switch(mac_address);
+    }
+
+    if let Some(alias) = &spec.host_gateway_alias {
+        if !adds_host(&spec.docker_switches, alias) {
+            cmd = cmd
+                .add_docker_switch("--add-host")
+                .add_docker_switch(format!("{}:host-gateway", alias));
+        }
+    }
+
+    for mount in &spec.mounts {
+        cmd = cmd.add_docker_switch("--mount").add_docker_switch(mount);
+    }
+
+    for device in &spec.devices {
+        cmd = cmd.add_docker_switch("--device").add_docker_switch(device);
+    }
+
+    for label_file in &spec.label_files {
+        cmd = cmd
+            .add_docker_switch("--label-file")
+            .add_docker_switch(label_file);
+    }
+
+    cmd
+}
+
+/// Label the container with the configuration it was run from, and the
+/// version of floki which ran it, so it can be traced back to its source.
+/// The time it was started is added as an RFC 3339 timestamp, if given.
+fn configure_trace_labels(
+    cmd: DockerCommandBuilder,
+    config: &path::Path,
+    started: Option<time::OffsetDateTime>,
+) -> Result<DockerCommandBuilder, Error> {
+    let mut cmd = cmd
+        .add_docker_switch("--label")
+        .add_docker_switch(format!("com.floki.config-path={}", config.display()))
+        .add_docker_switch("--label")
+        .add_docker_switch(format!("com.floki.version={}", env!("CARGO_PKG_VERSION")));
+    if let Some(started) = started {
+        let timestamp = started.format(&time::format_description::well_known::Rfc3339)?;
+        cmd = cmd
+            .add_docker_switch("--label")
+            .add_docker_switch(format!("com.floki.started={}", timestamp));
+    }
+    Ok(cmd)
+}
+
+/// Whether the docker switches already add an entry for the host name
+fn adds_host(switches: &[String], host: &str) -> bool {
+    let entry = format!("{}:", host);
+    switches.iter().enumerate().any(|(i, switch)| {
+        let value = match switch.strip_prefix("--add-host=") {
+            Some(value) => Some(value),
+            None if switch == "--add-host" => switches.get(i + 1).map(String::as_str),
+            None => None,
+        };
+        value.is_some_and(|value| value.starts_with(&entry))
+    })
+}
+
+/// Get the image ready to use - building or pulling it as needed - without
+/// running a container, and return its name. In a dry run, pulls are
+/// printed rather than run.
+pub(crate) fn prepare_image(
+    spec: &spec::FlokiSpec,
+    dry_run: bool,
+    events: &mut dyn Emitter,
+) -> Result<String, Error> {
+    spec.image.obtain_image(
+        &spec.paths.root,
+        spec.paths.config_dir(),
+        &spec.paths.workspace,
+        dry_run,
+        events,
+    )?;
+    let image = spec.image.mirrored_name(spec.registry_mirror.as_deref())?;
+    if spec.image.is_remote() {
+        pull_with_policy(
+            &image,
+            spec.pull_policy,
+            spec.registry_auth.as_ref(),
+            dry_run,
+            events,
+        )?;
+    }
+    Ok(image)
+}
+
+/// Render the image names and container environment as shell exports
+pub(crate) fn environment_exports(spec: &spec::FlokiSpec) -> Result<String, Error> {
+    let mut exports = vec![(
+        "FLOKI_IMAGE".to_string(),
+        spec.image.mirrored_name(spec.registry_mirror.as_deref())?,
+    )];
+    if let Some(dind) = &spec.dind {
+        exports.push(("FLOKI_DIND_IMAGE".to_string(), dind.image.clone()));
+    }
+    for (var, value) in spec.resolved_environment() {
+        exports.push((var, value.to_string_lossy().to_string()));
+    }
+    Ok(export_lines(&exports))
+}
+
+fn export_lines(exports: &[(String, String)]) -> String {
+    exports
+        .iter()
+        .map(|(var, value)| format!("export {}={}\n", var, shlex::quote(value)))
+        .collect()
+}
+
+/// Where the shell rc file is mounted in the container
+const SHELL_RC_PATH: &str = "/floki-shell-rc";
+
+/// Mount the shell rc file where the inner shell will look for it
+fn configure_shell_rc(cmd: DockerCommandBuilder, rc: &path::Path) -> DockerCommandBuilder {
+    cmd.add_docker_switch("-v")
+        .add_docker_switch(format!("{}:{}:ro", rc.display(), SHELL_RC_PATH))
+}
+
+/// The command which starts the inner shell interactively, sourcing the rc
+/// file if there is one. bash is told to use it in place of its usual
+/// ~/.bashrc, while POSIX shells (sh, dash, ash and ksh) source the file
+/// named by ENV when they start interactively. ENV is set with env so the
+/// command still works after a privilege drop tool.
+fn interactive_shell(shell: &str, rc: Option<&str>) -> String {
+    match rc {
+        Some(rc) if shell.rsplit('/').next() == Some("bash") => {
+            format!("{} --rcfile {}", shell, rc)
+        }
+        Some(rc) => format!("env ENV={} {} -i", rc, shell),
+        None => shell.to_string(),
+    }
 }
 
 pub(crate) fn command_in_shell(shell: &str, command: &[String]) -> String {
@@ -63,24 +621,136 @@ pub(crate) fn command_in_shell(shell: &str, command: &[String]) -> String {
     inner_shell + " -c \"" + &command.join(" ") + "\""
 }
 
+/// Set the variables floki provides in the container
+fn configure_environment(
+    mut cmd: DockerCommandBuilder,
+    spec: &spec::FlokiSpec,
+) -> DockerCommandBuilder {
+    for (var, value) in spec.resolved_environment() {
+        cmd = cmd.add_environment(var, value);
+    }
+    cmd
+}
+
+/// Forward host variables into the container. With a prefix, they're
+/// renamed, so the value is looked up and passed to docker under the new
+/// name - in docker's environment, so it isn't visible in the process list.
+fn configure_forwarded_variables(
+    cmd: DockerCommandBuilder,
+    variables: &[String],
+    prefix: Option<&str>,
+    value: impl Fn(&str) -> Option<std::ffi::OsString>,
+) -> DockerCommandBuilder {
+    let mut cmd = cmd; // Shadow as mutable
+    for var in variables {
+        cmd = match prefix {
+            Some(prefix) => match value(var) {
+                Some(value) => cmd.pass_environment(format!("{}{}", prefix, var), value),
+                None => cmd,
+            },
+            None => cmd.forward_environment(var),
+        };
+    }
+    cmd
+}
+
+/// Run as the host user if requested - unless privileges are dropped
+/// later, in which case the container starts as root
+fn configure_user(cmd: DockerCommandBuilder, user: &spec::User) -> DockerCommandBuilder {
+    if user.forward && user.drop_privileges.is_none() {
+        cmd.add_docker_switch("--user")
+            .add_docker_switch(format!("{}:{}", user.uid, user.gid))
+    } else {
+        cmd
+    }
+}
+
+/// Mount passwd and group files naming the host user and group, so tools
+/// which look the user up by id find them
+fn configure_user_names(
+    cmd: DockerCommandBuilder,
+    user: &spec::User,
+    workspace: &path::Path,
+) -> Result<DockerCommandBuilder, Error> {
+    let host_user = nix::unistd::User::from_uid(user.uid)?;
+    let host_group = nix::unistd::Group::from_gid(user.gid)?;
+    let user_name = host_user
+        .as_ref()
+        .map_or_else(|| user.uid.to_string(), |u| u.name.clone());
+    let group_name = host_group.map_or_else(|| user.gid.to_string(), |g| g.name);
+    let home = host_user.map_or_else(|| path::PathBuf::from("/"), |u| u.dir);
+    let (passwd, group) = user_database(user, &user_name, &group_name, &home);
+
+    let directory = workspace.join("users").join(user.uid.to_string());
+    std::fs::create_dir_all(&directory)?;
+    let mut cmd = cmd;
+    for (name, contents) in [("passwd", passwd), ("group", group)] {
+        let file = directory.join(name);
+        std::fs::write(&file, contents)?;
+        cmd = cmd.add_docker_switch("-v").add_docker_switch(format!(
+            "{}:/etc/{}:ro",
+            file.display(),
+            name
+        ));
+    }
+    Ok(cmd)
+}
+
+/// Generate passwd and group files for root and the host user
+fn user_database(
+    user: &spec::User,
+    user_name: &str,
+    group_name: &str,
+    home: &path::Path,
+) -> (String, String) {
+    let passwd = format!(
+        "root:x:0:0:root:/root:/bin/sh\n{name}:x:{uid}:{gid}:{name}:{home}:/bin/sh\n",
+        name = user_name,
+        uid = user.uid,
+        gid = user.gid,
+        home = home.display()
+    );
+    let group = format!("root:x:0:\n{}:x:{}:{}\n", group_name, user.gid, user_name);
+    (passwd, group)
+}
+
+/// Wrap the inner command to drop to the host user, if configured. Without
+/// forward_user the container keeps its own user throughout.
+fn user_command(user: &spec::User, inner_command: &str) -> String {
+    match user.drop_privileges {
+        Some(tool) if user.forward => format!(
+            "{} {}:{} {}",
+            tool.command(),
+            user.uid,
+            user.gid,
+            inner_command
+        ),
+        _ => inner_command.to_string(),
+    }
+}
+
 /// Add mounts for each of the passed in volumes
 fn configure_volumes(
     cmd: DockerCommandBuilder,
-    volumes: &[(path::PathBuf, &path::PathBuf)],
+    volumes: &[(path::PathBuf, &path::PathBuf, &[String])],
 ) -> DockerCommandBuilder {
     let mut cmd = cmd; // Shadow as mutable
-    for (src, dst) in volumes.iter() {
-        cmd = cmd.add_volume((src, dst));
+    for (src, dst, options) in volumes.iter() {
+        cmd = cmd.add_volume_with_options((src, dst), options);
     }
     cmd
 }
 
-/// Create the backing directories for floki volumes if needed
-fn instantiate_volumes(volumes: &[(path::PathBuf, &path::PathBuf)]) -> Result<(), Error> {
-    for (src, _) in volumes.iter() {
-        std::fs::create_dir_all(src)?;
+/// Add tmpfs mounts for each of the passed in mount points
+fn configure_tmpfs_volumes(
+    cmd: DockerCommandBuilder,
+    mounts: &[&path::PathBuf],
+) -> DockerCommandBuilder {
+    let mut cmd = cmd; // Shadow as mutable
+    for mount in mounts.iter() {
+        cmd = cmd.add_docker_switch("--tmpfs").add_docker_switch(mount);
     }
-    Ok(())
+    cmd
 }
 
 /// Turn the init section of a floki.yaml file into a command
@@ -94,6 +764,438 @@ fn subshell_command(init: &[String], command: &str) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::environment::Environment;
+
+    fn test_spec(yaml: &str) -> spec::FlokiSpec {
+        let config: crate::config::FlokiConfig = serde_yaml::from_str(yaml).unwrap();
+        spec::FlokiSpec::from(config, Environment::for_tests()).unwrap()
+    }
+
+    fn container_options(yaml: &str) -> Vec<String> {
+        configure_container_options(DockerCommandBuilder::new("foo"), &test_spec(yaml))
+            .build_docker_switches()
+            .iter()
+            .map(|s| s.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_configure_project_mount() {
+        let cmd =
+            configure_project_mount(DockerCommandBuilder::new("foo"), &test_spec("image: foo"));
+        assert_eq!(cmd.build_volume_switches(), vec!["-v", "/host/src:/src"]);
+        assert_eq!(cmd.build_docker_switches(), &vec!["-w", "/src/"]);
+    }
+
+    #[test]
+    fn test_configure_bound_current_directory() {
+        let mut config: crate::config::FlokiConfig = serde_yaml::from_str("image: foo").unwrap();
+        crate::config::RuntimeOverrides {
+            bind_current_dir_as: Some("/work".into()),
+            ..Default::default()
+        }
+        .apply(&mut config);
+        let environment = Environment {
+            current_directory: "/host/src/module".into(),
+            ..Environment::for_tests()
+        };
+        let spec = spec::FlokiSpec::from(config, environment).unwrap();
+
+        let cmd = configure_project_mount(DockerCommandBuilder::new("foo"), &spec);
+        assert_eq!(
+            cmd.build_volume_switches(),
+            vec!["-v", "/host/src/module:/work"]
+        );
+        assert_eq!(cmd.build_docker_switches(), &vec!["-w", "/work/"]);
+    }
+
+    #[test]
+    fn test_configure_trace_labels() -> Result<(), Error> {
+        let cmd = configure_trace_labels(
+            DockerCommandBuilder::new("foo"),
+            path::Path::new("/host/src/floki.yaml"),
+            None,
+        )?;
+        let version = format!("com.floki.version={}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            cmd.build_docker_switches(),
+            &vec![
+                "--label",
+                "com.floki.config-path=/host/src/floki.yaml",
+                "--label",
+                &version
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_configure_start_time_label() -> Result<(), Error> {
+        use time::format_description::well_known::Rfc3339;
+
+        let now = time::OffsetDateTime::now_utc();
+        let cmd = configure_trace_labels(
+            DockerCommandBuilder::new("foo"),
+            path::Path::new("/host/src/floki.yaml"),
+            Some(now),
+        )?;
+        let switches = cmd.build_docker_switches();
+        let started = switches
+            .iter()
+            .find_map(|switch| switch.to_str()?.strip_prefix("com.floki.started="))
+            .expect("no start time label");
+        assert_eq!(time::OffsetDateTime::parse(started, &Rfc3339)?, now);
+        Ok(())
+    }
+
+    #[test]
+    fn test_configure_cgroups() {
+        assert_eq!(
+            container_options("image: foo\ncgroup_parent: /ci/jobs\ncgroupns: private"),
+            vec!["--cgroup-parent", "/ci/jobs", "--cgroupns", "private"]
+        );
+    }
+
+    #[test]
+    fn test_configure_read_only_rootfs() {
+        assert_eq!(
+            container_options("image: foo\nread_only_rootfs: true"),
+            vec!["--read-only"]
+        );
+        assert!(container_options("image: foo").is_empty());
+    }
+
+    #[test]
+    fn test_configure_memory_options() {
+        assert_eq!(
+            container_options("image: foo\nmemory_swap: 2g\noom_kill_disable: true"),
+            vec!["--memory-swap", "2g", "--oom-kill-disable"]
+        );
+    }
+
+    #[test]
+    fn test_configure_process_controls() {
+        assert_eq!(
+            container_options("image: foo\noom_score_adj: -500\npids_limit: 256"),
+            vec!["--oom-score-adj", "-500", "--pids-limit", "256"]
+        );
+    }
+
+    #[test]
+    fn test_configure_shm_size() {
+        assert_eq!(
+            container_options("image: foo\nshm_size: 2g"),
+            vec!["--shm-size", "2g"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_shell() -> Result<(), Error> {
+        let unused = || -> Result<String, Error> { panic!("the image was probed") };
+        assert_eq!(
+            resolve_shell(&test_spec("image: foo").shell, unused)?,
+            Shell::Shell("sh".into())
+        );
+        assert_eq!(
+            resolve_shell(&Shell::Shell("zsh".into()), unused)?,
+            Shell::Shell("zsh".into())
+        );
+        assert!(
+            resolve_shell(&Shell::Shell("auto".into()), || Err(anyhow::anyhow!(
+                "no image"
+            )))
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_shell_from_probe() {
+        let bash = Shell::Shell("bash".into());
+        let sh = Shell::Shell("sh".into());
+        assert_eq!(shell_from_probe("/bin/bash\n/bin/sh\n"), bash);
+        assert_eq!(shell_from_probe("/usr/local/bin/bash\n"), bash);
+        assert_eq!(shell_from_probe("/bin/sh\n"), sh);
+        assert_eq!(shell_from_probe(""), sh);
+    }
+
+    #[test]
+    fn test_pull_policy_never_with_missing_image() {
+        let error = check_pull_policy(PullPolicy::Never, "debian:sid", || Ok(false)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<FlokiError>(),
+            Some(FlokiError::ImageNotPresentLocally { image }) if image == "debian:sid"
+        ));
+        assert!(error.to_string().contains("docker pull"));
+
+        assert!(check_pull_policy(PullPolicy::Never, "debian:sid", || Ok(true)).is_ok());
+        assert!(check_pull_policy(PullPolicy::Missing, "debian:sid", || Ok(false)).is_ok());
+    }
+
+    #[test]
+    fn test_pull_policy_never_skips_pull() {
+        assert!(!should_pull(Some(PullPolicy::Never), "debian:sid", || Ok(true)).unwrap());
+        assert!(should_pull(Some(PullPolicy::Never), "debian:sid", || Ok(false)).is_err());
+    }
+
+    #[test]
+    fn test_pull_policy_missing_skips_present_image() {
+        assert!(!should_pull(Some(PullPolicy::Missing), "debian:sid", || Ok(true)).unwrap());
+        assert!(should_pull(Some(PullPolicy::Missing), "debian:sid", || Ok(false)).unwrap());
+        // Without a policy, or with always, docker isn't asked
+        let unasked = || -> Result<bool, Error> { panic!("docker was asked") };
+        assert!(should_pull(None, "debian:sid", unasked).unwrap());
+        assert!(should_pull(Some(PullPolicy::Always), "debian:sid", unasked).unwrap());
+    }
+
+    #[test]
+    fn test_exit_hooks_run_after_failure() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let marker = dir.path().join("ran");
+        let hooks = vec![
+            CommandSpec {
+                command: "false".into(),
+                args: vec![],
+                env: Default::default(),
+            },
+            CommandSpec {
+                command: "touch".into(),
+                args: vec![marker.display().to_string()],
+                env: Default::default(),
+            },
+        ];
+
+        let result: Result<(), Error> = run_with_exit_hooks(&hooks, dir.path(), || {
+            Err(crate::errors::FlokiError::RunContainerFailed {
+                exit_status: crate::errors::FlokiSubprocessExitStatus {
+                    process_description: "docker run".into(),
+                    exit_status: std::os::unix::process::ExitStatusExt::from_raw(1 << 8),
+                },
+            }
+            .into())
+        });
+
+        // The container's failure is still reported, and every hook ran
+        assert!(result.is_err());
+        assert!(marker.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_hooks_run_in_directory() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let hooks = vec![CommandSpec {
+            command: "touch".into(),
+            args: vec!["ran".into()],
+            env: Default::default(),
+        }];
+
+        run_with_exit_hooks(&hooks, dir.path(), || Ok(()))?;
+
+        assert!(dir.path().join("ran").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_configure_host_gateway_alias() {
+        assert_eq!(
+            container_options("image: foo\nhost_gateway_alias: host.docker.internal"),
+            vec!["--add-host", "host.docker.internal:host-gateway"]
+        );
+
+        // Not added again if the docker switches already map the name
+        let yaml = "image: foo\nhost_gateway_alias: host.docker.internal\ndocker_switches:\n  - --add-host=host.docker.internal:10.0.0.1";
+        assert!(container_options(yaml).is_empty());
+        let yaml = "image: foo\nhost_gateway_alias: host.docker.internal\ndocker_switches:\n  - --add-host host.docker.internal:host-gateway";
+        assert!(container_options(yaml).is_empty());
+    }
+
+    #[test]
+    fn test_configure_mounts() {
+        assert_eq!(
+            container_options("image: foo\nmounts:\n  - type=volume,source=cache,target=/cache"),
+            vec!["--mount", "type=volume,source=cache,target=/cache"]
+        );
+    }
+
+    #[test]
+    fn test_configure_devices() {
+        assert_eq!(
+            container_options(
+                "image: foo\ndevices:\n  - /dev/ttyUSB0:/dev/ttyUSB0:rwm\n  - /dev/fuse"
+            ),
+            vec![
+                "--device",
+                "/dev/ttyUSB0:/dev/ttyUSB0:rwm",
+                "--device",
+                "/dev/fuse"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_configure_userns() {
+        assert_eq!(
+            container_options("image: foo\nuserns: host"),
+            vec!["--userns", "host"]
+        );
+        assert_eq!(
+            container_options("image: foo\nuserns: \"\""),
+            vec!["--userns", ""]
+        );
+    }
+
+    #[test]
+    fn test_configure_mac_address() {
+        assert_eq!(
+            container_options("image: foo\nmac_address: 02:42:ac:11:00:02"),
+            vec!["--mac-address", "02:42:ac:11:00:02"]
+        );
+    }
+
+    #[test]
+    fn test_configure_stop_signal() {
+        assert_eq!(
+            container_options("image: foo\nstop_signal: SIGINT"),
+            vec!["--stop-signal", "SIGINT"]
+        );
+    }
+
+    #[test]
+    fn test_prepare_image_only_obtains_image() -> Result<(), Error> {
+        // An exec image is produced by a host command, and no container is run
+        let spec =
+            test_spec("image:\n  exec:\n    command: \"true\"\n    args: []\n    image: foo:1.0");
+        let mut events = Vec::new();
+        assert_eq!(prepare_image(&spec, false, &mut events)?, "foo:1.0");
+        assert_eq!(
+            events,
+            vec![Event::BuildStarted {
+                image: "foo:1.0".into()
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_events() -> Result<(), Error> {
+        let mut events = Vec::new();
+        run_session("debian:sid", &mut events, || Ok(()))?;
+
+        let failed = run_session("debian:sid", &mut events, || {
+            Err(crate::errors::FlokiError::RunContainerFailed {
+                exit_status: crate::errors::FlokiSubprocessExitStatus {
+                    process_description: "docker run".into(),
+                    exit_status: std::os::unix::process::ExitStatusExt::from_raw(3 << 8),
+                },
+            }
+            .into())
+        });
+
+        assert!(failed.is_err());
+        let started = Event::ContainerStarted {
+            image: "debian:sid".into(),
+        };
+        assert_eq!(
+            events,
+            vec![
+                started.clone(),
+                Event::ContainerExited { code: 0 },
+                started,
+                Event::ContainerExited { code: 3 },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_env_prefixes() {
+        let config = serde_yaml::from_str("image: foo\nforward_env_prefixes:\n  - AWS_").unwrap();
+        let environment = Environment {
+            host_variables: vec!["AWS_PROFILE".into(), "PATH".into()],
+            ..Environment::for_tests()
+        };
+        let spec = spec::FlokiSpec::from(config, environment).unwrap();
+        assert_eq!(spec.forwarded_variables, vec!["AWS_PROFILE"]);
+    }
+
+    #[test]
+    fn test_configure_locale() {
+        let spec = test_spec("image: foo\nlocale: en_GB.UTF-8\nkeep_env_clean: true");
+        let cmd = configure_environment(DockerCommandBuilder::new("foo"), &spec);
+        assert_eq!(
+            cmd.build_environment_switches(),
+            &vec![
+                "-e",
+                "LANG=en_GB.UTF-8",
+                "-e",
+                "LANGUAGE=en_GB.UTF-8",
+                "-e",
+                "LC_ALL=en_GB.UTF-8"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_configure_forwarded_variables() {
+        let variables = vec!["AWS_PROFILE".to_string(), "AWS_UNSET".to_string()];
+        let value = |var: &str| match var {
+            "AWS_PROFILE" => Some("dev".into()),
+            _ => None,
+        };
+
+        let cmd = configure_forwarded_variables(
+            DockerCommandBuilder::new("foo"),
+            &variables,
+            Some("HOST_"),
+            value,
+        );
+        assert_eq!(
+            cmd.build_environment_switches(),
+            &vec!["-e", "HOST_AWS_PROFILE"]
+        );
+        assert_eq!(
+            cmd.build_passed_environment(),
+            &vec![("HOST_AWS_PROFILE".into(), "dev".into())]
+        );
+
+        let cmd = configure_forwarded_variables(
+            DockerCommandBuilder::new("foo"),
+            &variables,
+            None,
+            value,
+        );
+        assert_eq!(
+            cmd.build_environment_switches(),
+            &vec!["-e", "AWS_PROFILE", "-e", "AWS_UNSET"]
+        );
+    }
+
+    #[test]
+    fn test_interactive_shell_rc() {
+        assert_eq!(
+            interactive_shell("bash", Some(SHELL_RC_PATH)),
+            "bash --rcfile /floki-shell-rc"
+        );
+        assert_eq!(
+            interactive_shell("/bin/bash", Some(SHELL_RC_PATH)),
+            "/bin/bash --rcfile /floki-shell-rc"
+        );
+        assert_eq!(
+            interactive_shell("sh", Some(SHELL_RC_PATH)),
+            "env ENV=/floki-shell-rc sh -i"
+        );
+        assert_eq!(interactive_shell("bash", None), "bash");
+
+        let cmd = configure_shell_rc(
+            DockerCommandBuilder::new("foo"),
+            path::Path::new("/host/src/floki.rc"),
+        );
+        assert_eq!(
+            cmd.build_docker_switches(),
+            &vec!["-v", "/host/src/floki.rc:/floki-shell-rc:ro"]
+        );
+    }
 
     #[test]
     fn test_command_in_shell() {
@@ -104,4 +1206,171 @@ mod test {
 
         assert!(result == expected);
     }
+
+    #[test]
+    fn test_environment_exports_dind_image() -> Result<(), Error> {
+        let spec = test_spec("image: debian:sid\ndind: true\nregistry_mirror: mirror.internal");
+        let exports = environment_exports(&spec)?;
+        assert!(exports.starts_with(
+            "export FLOKI_IMAGE=mirror.internal/library/debian:sid\n\
+             export FLOKI_DIND_IMAGE=mirror.internal/library/docker:stable-dind\n"
+        ));
+
+        let exports = environment_exports(&test_spec("image: debian:sid"))?;
+        assert!(!exports.contains("FLOKI_DIND_IMAGE"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_lines_quotes_values() {
+        let exports = vec![
+            ("FLOKI_IMAGE".to_string(), "debian:sid".to_string()),
+            (
+                "FLOKI_HOST_MOUNTDIR".to_string(),
+                "/home/me/my src".to_string(),
+            ),
+        ];
+
+        let expected =
+            "export FLOKI_IMAGE=debian:sid\nexport FLOKI_HOST_MOUNTDIR=\"/home/me/my src\"\n";
+
+        assert_eq!(export_lines(&exports), expected);
+    }
+
+    fn test_user(drop_privileges: Option<crate::config::PrivilegeDrop>) -> spec::User {
+        spec::User {
+            forward: true,
+            uid: nix::unistd::Uid::from_raw(1000),
+            gid: nix::unistd::Gid::from_raw(100),
+            map_names: false,
+            drop_privileges,
+        }
+    }
+
+    #[test]
+    fn test_user_database() {
+        let (passwd, group) = user_database(
+            &test_user(None),
+            "alice",
+            "users",
+            path::Path::new("/home/alice"),
+        );
+        assert_eq!(
+            passwd,
+            "root:x:0:0:root:/root:/bin/sh\nalice:x:1000:100:alice:/home/alice:/bin/sh\n"
+        );
+        assert_eq!(group, "root:x:0:\nusers:x:100:alice\n");
+    }
+
+    #[test]
+    fn test_forward_user() {
+        let user = test_user(None);
+
+        let cmd = configure_user(DockerCommandBuilder::new("foo"), &user);
+        assert_eq!(cmd.build_docker_switches(), &vec!["--user", "1000:100"]);
+
+        let command = subshell_command(&["setup".into()], &user_command(&user, "bash"));
+        assert_eq!(command, "setup && bash");
+    }
+
+    #[test]
+    fn test_drop_privileges_after_init() {
+        let user = test_user(Some(crate::config::PrivilegeDrop::Gosu));
+
+        // Start as root, so init runs as root...
+        let cmd = configure_user(DockerCommandBuilder::new("foo"), &user);
+        assert!(cmd.build_docker_switches().is_empty());
+
+        // ...then only the inner shell runs as the host user
+        let command = subshell_command(&["setup".into()], &user_command(&user, "bash"));
+        assert_eq!(command, "setup && gosu 1000:100 bash");
+    }
+
+    #[test]
+    fn test_drop_privileges_needs_forward_user() {
+        let user = spec::User {
+            forward: false,
+            ..test_user(Some(crate::config::PrivilegeDrop::Gosu))
+        };
+
+        let cmd = configure_user(DockerCommandBuilder::new("foo"), &user);
+        assert!(cmd.build_docker_switches().is_empty());
+        let command = subshell_command(&["setup".into()], &user_command(&user, "bash"));
+        assert_eq!(command, "setup && bash");
+    }
+
+    #[test]
+    fn test_entrypoint_script() {
+        assert_eq!(
+            entrypoint_script(&["umask 002".into(), "echo hello".into()]),
+            "#!/bin/sh\nset -e\numask 002\necho hello\nexec \"$@\"\n"
+        );
+        assert_eq!(entrypoint_script(&[]), "#!/bin/sh\nset -e\nexec \"$@\"\n");
+    }
+
+    #[test]
+    fn test_configure_entrypoint_script() {
+        let cmd = configure_entrypoint_script(
+            DockerCommandBuilder::new("foo"),
+            Runtime::Docker,
+            path::Path::new("/host/.floki/entrypoints/abc.sh"),
+        );
+        assert_eq!(
+            cmd.build_docker_switches(),
+            &vec![
+                "-v",
+                "/host/.floki/entrypoints/abc.sh:/floki-entrypoint.sh:ro",
+                "--entrypoint",
+                "/floki-entrypoint.sh"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_entrypoint_script_removed_on_drop() -> Result<(), Error> {
+        let workspace = tempfile::tempdir()?;
+        let script = EntrypointScript::write(workspace.path(), &["echo hello".into()])?;
+        let path = script.path().to_path_buf();
+        assert!(std::fs::read_to_string(&path)?.contains("echo hello"));
+
+        drop(script);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_configure_volume_options() {
+        let src = path::PathBuf::from("/host/.floki/volumes/cache");
+        let dst = path::PathBuf::from("/cache");
+        let z = vec!["z".to_string()];
+        let both = vec!["z".to_string(), "rshared".to_string()];
+
+        let cmd = configure_volumes(
+            DockerCommandBuilder::new("foo"),
+            &[
+                (src.clone(), &dst, &z),
+                (src.clone(), &dst, &both),
+                (src, &dst, &[]),
+            ],
+        );
+        assert_eq!(
+            cmd.build_volume_switches(),
+            vec![
+                "-v",
+                "/host/.floki/volumes/cache:/cache:z",
+                "-v",
+                "/host/.floki/volumes/cache:/cache:z,rshared",
+                "-v",
+                "/host/.floki/volumes/cache:/cache"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_configure_tmpfs_volumes() {
+        let mount = path::PathBuf::from("/scratch");
+
+        let cmd = configure_tmpfs_volumes(DockerCommandBuilder::new("foo"), &[&mount]);
+        assert_eq!(cmd.build_docker_switches(), &vec!["--tmpfs", "/scratch"]);
+    }
 }
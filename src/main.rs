@@ -8,15 +8,22 @@ mod config;
 mod dind;
 mod environment;
 mod errors;
+mod events;
 mod image;
 mod interpret;
+mod prompt;
+mod registry;
 mod spec;
+mod summary;
+mod template;
 mod volumes;
+mod warning;
 
 use anyhow::Error;
 use cli::{Cli, Subcommand};
 use config::FlokiConfig;
 use environment::Environment;
+use std::os::unix::process::ExitStatusExt;
 use structopt::StructOpt;
 
 fn main() -> Result<(), Error> {
@@ -27,7 +34,7 @@ fn main() -> Result<(), Error> {
         Ok(()) => (),
         Err(e) => {
             error!("A problem occurred: {}", e);
-            std::process::exit(1);
+            std::process::exit(exit_code(&e));
         }
     }
     Ok(())
@@ -38,24 +45,138 @@ fn run_floki_from_args(args: &Cli) -> Result<(), Error> {
     debug!("Got command line arguments: {:?}", &args);
 
     if args.local {
-        warn!("-l/--local is deprecated and may be removed in a future release");
+        warning::render(&[warning::Warning::LocalFlagDeprecated]);
+    }
+
+    // Running a container can't be done halfway
+    if args.dry_run && !(args.pull_only || matches!(args.subcommand, Some(Subcommand::Pull {}))) {
+        return Err(errors::FlokiUserError::DryRunNeedsPull {}.into());
+    }
+
+    // For editor integrations - no docker is involved
+    if args.print_config_path {
+        println!(
+            "{}",
+            environment::resolve_config_path(&args.config_files.first().cloned())?.display()
+        );
+        return Ok(());
+    }
+
+    // Trace the configuration to where it was set
+    if args.explain {
+        let (_, files) = config_files(args)?;
+        let profile = selected_profile(args, &files)?;
+        let fields = FlokiConfig::explain(&files, profile.as_deref(), &args.runtime_overrides())?;
+        for (field, source) in fields {
+            println!("{}\t{}", field, source);
+        }
+        return Ok(());
+    }
+
+    // Find out why two configurations behave differently
+    if let Some(other) = &args.compare_config {
+        let (_, config) = load_config(args)?;
+        let others = std::slice::from_ref(other);
+        let mut other =
+            FlokiConfig::from_files(others, selected_profile(args, others)?.as_deref())?;
+        args.runtime_overrides().apply(&mut other);
+        for (field, (left, right)) in config::diff(&config, &other)? {
+            println!(
+                "{}\t{}\t{}",
+                field,
+                serde_json::to_string(&left)?,
+                serde_json::to_string(&right)?
+            );
+        }
+        return Ok(());
+    }
+
+    // Volume maintenance doesn't need a configuration
+    if args.list_volumes || args.prune_volumes {
+        let workspace = environment::current_floki_workspace();
+        let volumes = if args.prune_volumes {
+            let prunable = volumes::list_volumes(&workspace)?
+                .into_iter()
+                .filter(|volume| !volume.shared)
+                .count();
+            if prunable == 0 {
+                return Ok(());
+            }
+            let question = format!("Remove {} floki volume(s)?", prunable);
+            if !(args.yes || prompt::confirm(&question)) {
+                return Err(errors::FlokiError::NotConfirmed {}.into());
+            }
+            volumes::prune_volumes(&workspace)?
+        } else {
+            volumes::list_volumes(&workspace)?
+        };
+        for volume in volumes {
+            println!(
+                "{}\t{}\t{}",
+                volume.size,
+                if volume.shared { "shared" } else { "local" },
+                volume.path.display()
+            );
+        }
+        return Ok(());
+    }
+
+    // Reconnect to a container which is already running
+    if let Some(name) = &args.attach {
+        let (env, config) = load_config(args)?;
+        let spec = load_spec(args, config, env)?;
+        let shell = interpret::resolve_shell(&spec.shell, || {
+            spec.image.mirrored_name(spec.registry_mirror.as_deref())
+        })?;
+        return command::attach(name, shell.inner_shell(), || {
+            command::container_running(name)
+        });
+    }
+
+    // Only get the image ready, e.g. to warm a CI cache
+    if args.pull_only {
+        let (env, config) = load_config(args)?;
+        println!(
+            "{}",
+            interpret::prepare_image(
+                &load_spec(args, config, env)?,
+                args.dry_run,
+                event_emitter(args).as_mut()
+            )?
+        );
+        return Ok(());
     }
 
     // Dispatch appropriate subcommand
     match &args.subcommand {
         // Pull the image in the configuration file
         Some(Subcommand::Pull {}) => {
-            let env = Environment::gather(&args.config_file)?;
-            let config = FlokiConfig::from_file(&env.config_file)?;
-            image::pull_image(&config.image.name()?)
+            let (_, config) = load_config(args)?;
+            interpret::pull_with_policy(
+                &config
+                    .image
+                    .mirrored_name(config.registry_mirror.as_deref())?,
+                config.pull_policy,
+                config.registry_auth.as_ref(),
+                args.dry_run,
+                event_emitter(args).as_mut(),
+            )
         }
 
         // Run a command in the floki container
         Some(Subcommand::Run { command }) => {
-            let env = Environment::gather(&args.config_file)?;
-            let config = FlokiConfig::from_file(&env.config_file)?;
-            let inner_command = interpret::command_in_shell(config.shell.inner_shell(), command);
-            interpret::run_floki_container(&spec::FlokiSpec::from(config, env)?, &inner_command)
+            let (env, config) = load_config(args)?;
+            run_container(args, &load_spec(args, config, env)?, Some(command))
+        }
+
+        // Print the container environment as shell exports
+        Some(Subcommand::Env {}) => {
+            let (env, config) = load_config(args)?;
+            print!(
+                "{}",
+                interpret::environment_exports(&load_spec(args, config, env)?)?
+            );
+            Ok(())
         }
 
         Some(Subcommand::Completion { shell }) => {
@@ -65,14 +186,130 @@ fn run_floki_from_args(args: &Cli) -> Result<(), Error> {
 
         // Launch an interactive floki shell (the default)
         None => {
-            let env = Environment::gather(&args.config_file)?;
-            let config = FlokiConfig::from_file(&env.config_file)?;
-            let inner_command = config.shell.inner_shell().to_string();
-            interpret::run_floki_container(&spec::FlokiSpec::from(config, env)?, &inner_command)
+            let (env, config) = load_config(args)?;
+            run_container(args, &load_spec(args, config, env)?, None)
         }
     }
 }
 
+/// Run the floki container, emitting a summary of the run afterwards if
+/// one was requested
+fn run_container(
+    args: &Cli,
+    spec: &spec::FlokiSpec,
+    command: Option<&[String]>,
+) -> Result<(), Error> {
+    let mut summary = if args.format == summary::OutputFormat::Json || args.summary_file.is_some() {
+        Some(summary::Summary::default())
+    } else {
+        None
+    };
+    let mut events = event_emitter(args);
+    let result = interpret::run_with_exit_hooks(&spec.on_exit, &spec.paths.outer_workdir, || {
+        interpret::run_floki_container(spec, command, summary.as_mut(), events.as_mut())
+    });
+
+    if let Some(summary) = &mut summary {
+        summary.exit_code = Some(match &result {
+            Ok(()) => 0,
+            Err(e) => exit_code(e),
+        });
+        // The container's result matters more than the summary's
+        if let Err(e) = summary.emit(args.summary_file.as_deref()) {
+            warn!("Failed to emit the summary: {:?}", e);
+        }
+    }
+
+    result
+}
+
+/// Where to report progress - stderr, if events were requested
+fn event_emitter(args: &Cli) -> Box<dyn events::Emitter> {
+    if args.events {
+        Box::new(events::JsonLines(std::io::stderr()))
+    } else {
+        Box::new(events::Discard)
+    }
+}
+
+/// Gather the environment and load the configuration. The first
+/// configuration file (or the one found by searching) is used to locate
+/// floki's root, and any further files are merged over it.
+fn load_config(args: &Cli) -> Result<(Environment, FlokiConfig), Error> {
+    let (env, mut config) = match &args.image {
+        // A one-off run, made only from command line options
+        Some(image) => (
+            Environment::gather_without_config()?,
+            FlokiConfig::scratch(image, args.shell.as_deref())?,
+        ),
+        None => {
+            let (env, files) = config_files(args)?;
+            let profile = selected_profile(args, &files)?;
+            (env, FlokiConfig::from_files(&files, profile.as_deref())?)
+        }
+    };
+    args.runtime_overrides().apply(&mut config);
+    command::set_docker_context(config.docker_context.as_deref())?;
+    Ok((env, config))
+}
+
+/// The profile to merge over the configuration files. One given with
+/// --profile must be defined, but FLOKI_PROFILE is often set for every
+/// project on a host, so it only selects a profile the files define.
+fn selected_profile(args: &Cli, files: &[std::path::PathBuf]) -> Result<Option<String>, Error> {
+    if let Some(profile) = &args.profile {
+        return Ok(Some(profile.clone()));
+    }
+    match std::env::var("FLOKI_PROFILE") {
+        Ok(profile) if config::defines_profile(files, &profile)? => Ok(Some(profile)),
+        Ok(profile) => {
+            debug!(
+                "Profile '{}' from FLOKI_PROFILE isn't defined, so isn't used",
+                profile
+            );
+            Ok(None)
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Gather the environment, and the configuration files to merge in order
+fn config_files(args: &Cli) -> Result<(Environment, Vec<std::path::PathBuf>), Error> {
+    let env = Environment::gather(&args.config_files.first().cloned())?;
+    let mut files = vec![env.config_file.clone()];
+    files.extend(args.config_files.iter().skip(1).cloned());
+    Ok((env, files))
+}
+
+/// Resolve the floki spec, checking it against any environment allowlist
+fn load_spec(args: &Cli, config: FlokiConfig, env: Environment) -> Result<spec::FlokiSpec, Error> {
+    let spec = spec::FlokiSpec::from(config, env)?;
+    warning::render(&spec.warnings);
+    if let Some(allowlist) = &args.env_allowlist {
+        spec.enforce_env_allowlist(&spec::load_env_allowlist(allowlist)?)?;
+    }
+    Ok(spec)
+}
+
+/// Choose the exit code for floki when it fails with the given error. If
+/// the container failed, its exit status is passed on so scripts can
+/// act on it.
+pub(crate) fn exit_code(error: &Error) -> i32 {
+    match error.downcast_ref::<errors::FlokiError>() {
+        Some(errors::FlokiError::RunContainerFailed { exit_status }) => {
+            match exit_status.exit_status.code() {
+                Some(0) | None => exit_status
+                    .exit_status
+                    .signal()
+                    .map(|signal| 128 + signal)
+                    .unwrap_or(1),
+                Some(code) => code,
+            }
+        }
+        _ => 1,
+    }
+}
+
 /// Configure the logger
 fn configure_logging(verbosity: u8) -> Result<(), Error> {
     let level = match verbosity {
@@ -94,3 +331,37 @@ fn configure_logging(verbosity: u8) -> Result<(), Error> {
     )?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use errors::{FlokiError, FlokiSubprocessExitStatus};
+    use std::process::ExitStatus;
+
+    fn container_failure(raw_status: i32) -> Error {
+        FlokiError::RunContainerFailed {
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "docker run".into(),
+                exit_status: ExitStatus::from_raw(raw_status),
+            },
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_exit_code_passes_through_container_exit_code() {
+        // Raw wait statuses hold the exit code in the second byte
+        assert_eq!(exit_code(&container_failure(42 << 8)), 42);
+        assert_eq!(exit_code(&container_failure(1 << 8)), 1);
+    }
+
+    #[test]
+    fn test_exit_code_for_signalled_container() {
+        assert_eq!(exit_code(&container_failure(9)), 137);
+    }
+
+    #[test]
+    fn test_exit_code_for_other_errors() {
+        assert_eq!(exit_code(&FlokiError::NoSshAuthSock {}.into()), 1);
+    }
+}
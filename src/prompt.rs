@@ -0,0 +1,79 @@
+/// Asking the user before doing anything destructive, or to choose between
+/// options
+use std::io::{self, BufRead, Write};
+
+/// Ask the user to confirm an action, reading the answer from stdin. If
+/// stdin isn't a terminal there is nobody to ask, so the answer is no.
+pub(crate) fn confirm(question: &str) -> bool {
+    if !nix::unistd::isatty(0).unwrap_or(false) {
+        warn!("Not asking \"{}\" as stdin isn't a terminal", question);
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let _ = io::stderr().flush();
+    answered_yes(io::stdin().lock())
+}
+
+/// Ask the user to pick one of the options, reading its number from stdin.
+/// If stdin isn't a terminal there is nobody to ask, so nothing is picked.
+pub(crate) fn choose(question: &str, options: &[String]) -> Option<usize> {
+    if !nix::unistd::isatty(0).unwrap_or(false) {
+        return None;
+    }
+    eprintln!("{}", question);
+    for (number, option) in options.iter().enumerate() {
+        eprintln!("  {}) {}", number + 1, option);
+    }
+    eprint!("Choose 1-{}: ", options.len());
+    let _ = io::stderr().flush();
+    chosen(io::stdin().lock(), options.len())
+}
+
+/// Read the number of a choice, counting from 1, of so many options.
+/// Anything else is no choice.
+fn chosen(mut input: impl BufRead, options: usize) -> Option<usize> {
+    let mut answer = String::new();
+    input.read_line(&mut answer).ok()?;
+    match answer.trim().parse::<usize>() {
+        Ok(number) if (1..=options).contains(&number) => Some(number - 1),
+        _ => None,
+    }
+}
+
+/// Read an answer - only "y" or "yes" count, and no answer (EOF) is no
+fn answered_yes(mut input: impl BufRead) -> bool {
+    let mut answer = String::new();
+    match input.read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_answered_yes() {
+        assert!(answered_yes("y\n".as_bytes()));
+        assert!(answered_yes("Yes\n".as_bytes()));
+        assert!(!answered_yes("n\n".as_bytes()));
+        assert!(!answered_yes("\n".as_bytes()));
+        assert!(!answered_yes("sure\n".as_bytes()));
+    }
+
+    #[test]
+    fn test_no_answer_is_no() {
+        assert!(!answered_yes("".as_bytes()));
+    }
+
+    #[test]
+    fn test_chosen() {
+        assert_eq!(chosen("2\n".as_bytes(), 3), Some(1));
+        assert_eq!(chosen(" 1 \n".as_bytes(), 3), Some(0));
+        assert_eq!(chosen("0\n".as_bytes(), 3), None);
+        assert_eq!(chosen("4\n".as_bytes(), 3), None);
+        assert_eq!(chosen("first\n".as_bytes(), 3), None);
+        assert_eq!(chosen("".as_bytes(), 3), None);
+    }
+}
@@ -0,0 +1,210 @@
+/// Logging in to registries before pulling images, with passwords from the
+/// host environment or the OS keychain
+use crate::config::{KeychainEntry, RegistryAuth};
+use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
+use anyhow::{Context, Error};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Somewhere passwords are kept
+pub(crate) trait Keychain {
+    /// The password kept in an entry, if there is one
+    fn password(&self, entry: &KeychainEntry) -> Result<Option<String>, Error>;
+}
+
+/// The OS keychain - the login keychain on macOS, the Credential Manager
+/// on Windows, and the Secret Service (GNOME Keyring, KWallet) on Linux
+pub(crate) struct OsKeychain;
+
+impl Keychain for OsKeychain {
+    fn password(&self, entry: &KeychainEntry) -> Result<Option<String>, Error> {
+        let found = keyring::Entry::new(&entry.service, &entry.account)
+            .and_then(|keychain| keychain.get_password());
+        match found {
+            Ok(password) => Ok(Some(password)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e).with_context(|| {
+                format!(
+                    "Couldn't read keychain entry {}/{}",
+                    entry.service, entry.account
+                )
+            }),
+        }
+    }
+}
+
+/// Log in to the registry with docker, passing the password on stdin so it
+/// doesn't appear in the process list
+pub(crate) fn login(
+    auth: &RegistryAuth,
+    keychain: &dyn Keychain,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let mut command = login_command(auth);
+    if dry_run {
+        println!("{}", crate::image::describe_command(&command));
+        return Ok(());
+    }
+
+    let password = password(auth, keychain, |var| std::env::var(var).ok())?;
+    debug!("Logging in to {} as {}", registry_name(auth), auth.username);
+    let mut child = command.stdin(Stdio::piped()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(password.as_bytes())?;
+    }
+    let exit_status = child.wait()?;
+
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(FlokiError::FailedToLogIn {
+            registry: registry_name(auth).into(),
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "docker login".into(),
+                exit_status,
+            },
+        }
+        .into())
+    }
+}
+
+/// The password to log in with, from whichever of the host environment or
+/// the keychain is configured
+fn password(
+    auth: &RegistryAuth,
+    keychain: &dyn Keychain,
+    variable: impl Fn(&str) -> Option<String>,
+) -> Result<String, Error> {
+    let registry = registry_name(auth).to_string();
+    match (&auth.password_env, &auth.password_keychain) {
+        (Some(_), Some(_)) => Err(FlokiError::RegistryPasswordConflict { registry }.into()),
+        (Some(var), None) => variable(var).ok_or_else(|| {
+            FlokiError::RegistryPasswordNotSet {
+                registry,
+                variable: var.clone(),
+            }
+            .into()
+        }),
+        (None, Some(entry)) => keychain.password(entry)?.ok_or_else(|| {
+            FlokiError::RegistryPasswordNotInKeychain {
+                registry,
+                service: entry.service.clone(),
+                account: entry.account.clone(),
+            }
+            .into()
+        }),
+        (None, None) => Err(FlokiError::RegistryPasswordMissing { registry }.into()),
+    }
+}
+
+/// The registry as named in messages - docker logs in to Docker Hub when
+/// no registry is given
+fn registry_name(auth: &RegistryAuth) -> &str {
+    auth.registry.as_deref().unwrap_or("Docker Hub")
+}
+
+/// Assemble the command which logs in to the registry
+fn login_command(auth: &RegistryAuth) -> Command {
+    let mut command = crate::command::docker();
+    command.arg("login");
+    if let Some(registry) = &auth.registry {
+        command.arg(registry);
+    }
+    command
+        .arg("--username")
+        .arg(&auth.username)
+        .arg("--password-stdin");
+    command
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A keychain holding at most one password, for any entry
+    struct TestKeychain(Option<&'static str>);
+
+    impl Keychain for TestKeychain {
+        fn password(&self, _entry: &KeychainEntry) -> Result<Option<String>, Error> {
+            Ok(self.0.map(String::from))
+        }
+    }
+
+    fn auth(yaml: &str) -> RegistryAuth {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn error_of(result: Result<String, Error>) -> FlokiError {
+        match result.unwrap_err().downcast::<FlokiError>() {
+            Ok(error) => error,
+            Err(error) => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_password_from_keychain() -> Result<(), Error> {
+        let auth = auth(
+            "registry: registry.example.com\nusername: me\npassword_keychain:\n  service: registry.example.com\n  account: me",
+        );
+        assert_eq!(
+            password(&auth, &TestKeychain(Some("secret")), |_| None)?,
+            "secret"
+        );
+        assert!(matches!(
+            error_of(password(&auth, &TestKeychain(None), |_| None)),
+            FlokiError::RegistryPasswordNotInKeychain { service, account, .. }
+                if service == "registry.example.com" && account == "me"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_from_environment() -> Result<(), Error> {
+        let auth = auth("username: me\npassword_env: REGISTRY_PASSWORD");
+        let variable = |var: &str| match var {
+            "REGISTRY_PASSWORD" => Some("secret".to_string()),
+            _ => None,
+        };
+        assert_eq!(password(&auth, &TestKeychain(None), variable)?, "secret");
+        assert!(matches!(
+            error_of(password(&auth, &TestKeychain(None), |_| None)),
+            FlokiError::RegistryPasswordNotSet { variable, .. } if variable == "REGISTRY_PASSWORD"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_password_needs_exactly_one_source() {
+        assert!(matches!(
+            error_of(password(
+                &auth("username: me"),
+                &TestKeychain(Some("secret")),
+                |_| None
+            )),
+            FlokiError::RegistryPasswordMissing { .. }
+        ));
+        let both = auth(
+            "username: me\npassword_env: REGISTRY_PASSWORD\npassword_keychain:\n  service: docker\n  account: me",
+        );
+        assert!(matches!(
+            error_of(password(&both, &TestKeychain(Some("secret")), |_| None)),
+            FlokiError::RegistryPasswordConflict { .. }
+        ));
+    }
+
+    #[test]
+    fn test_login_command() {
+        let command = login_command(&auth(
+            "registry: quay.io\nusername: me\npassword_env: PASSWORD",
+        ));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(
+            args,
+            vec!["login", "quay.io", "--username", "me", "--password-stdin"]
+        );
+
+        let command = login_command(&auth("username: me\npassword_env: PASSWORD"));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, vec!["login", "--username", "me", "--password-stdin"]);
+    }
+}
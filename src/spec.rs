@@ -1,18 +1,26 @@
-use crate::config::{DindConfig, FlokiConfig};
+use crate::command::CommandSpec;
+use crate::config::{
+    CgroupNamespace, DindConfig, FlokiConfig, Isolation, PullPolicy, Runtime, Volume,
+};
 use crate::environment::Environment;
 use crate::errors;
+use crate::image;
+use crate::warning::Warning;
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 
 use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::path;
+use std::time::Duration;
 
 /// Information for running docker-in-docker
 #[derive(Debug)]
 pub(crate) struct Dind {
     /// The image to use
     pub(crate) image: String,
+    /// Command checking the docker daemon is up
+    pub(crate) healthcheck: Option<String>,
 }
 
 /// Information about the user
@@ -24,6 +32,10 @@ pub(crate) struct User {
     pub(crate) uid: nix::unistd::Uid,
     /// User host GID
     pub(crate) gid: nix::unistd::Gid,
+    /// Should the user and group names be resolvable in the container?
+    pub(crate) map_names: bool,
+    /// Start as root, and drop to the host user for the inner shell
+    pub(crate) drop_privileges: Option<crate::config::PrivilegeDrop>,
 }
 
 /// Information about the host SSH agent
@@ -41,10 +53,22 @@ pub(crate) struct Paths {
     /// The root directory for the project (location of floki.yaml or
     /// configuration file)
     pub(crate) root: path::PathBuf,
+    /// The host directory mounted into the container - the project root,
+    /// unless the current directory is bound instead
+    pub(crate) mounted: path::PathBuf,
     /// The path to the configuration file
     pub(crate) config: path::PathBuf,
     /// The base directory for storing volumes
     pub(crate) workspace: path::PathBuf,
+    /// The host directory the on exit commands are run from
+    pub(crate) outer_workdir: path::PathBuf,
+}
+
+impl Paths {
+    /// The directory of the configuration file
+    pub(crate) fn config_dir(&self) -> &path::Path {
+        self.config.parent().unwrap_or(&self.root)
+    }
 }
 
 /// FlokiSpec provides a fully resolved and preprocessed block of
@@ -57,10 +81,14 @@ pub(crate) struct FlokiSpec {
     pub(crate) init: Vec<String>,
     /// Shell to use in the environment
     pub(crate) shell: crate::config::Shell,
+    /// File sourced by the inner shell when it starts interactively
+    pub(crate) shell_rc: Option<path::PathBuf>,
     /// Where to mount the working directory
     pub(crate) mount: path::PathBuf,
     /// Entrypoint
     pub(crate) entrypoint: Option<String>,
+    /// Run the init commands from a generated entrypoint script
+    pub(crate) entrypoint_script: bool,
     /// Volumes to mount into the container
     pub(crate) volumes: BTreeMap<String, crate::config::Volume>,
     /// User details and forwarding
@@ -73,22 +101,97 @@ pub(crate) struct FlokiSpec {
     pub(crate) dind: Option<Dind>,
     /// Paths on the host which are relevant to running
     pub(crate) paths: Paths,
+    /// Registry mirror to pull images through
+    pub(crate) registry_mirror: Option<String>,
+    /// Credentials to log in with before pulling the image
+    pub(crate) registry_auth: Option<crate::config::RegistryAuth>,
+    /// Files of labels to apply to the container
+    pub(crate) label_files: Vec<path::PathBuf>,
+    /// Limit on memory plus swap for the container
+    pub(crate) memory_swap: Option<String>,
+    /// Disable the OOM killer for the container
+    pub(crate) oom_kill_disable: bool,
+    /// Adjustment to how likely the OOM killer is to pick the container
+    pub(crate) oom_score_adj: Option<i64>,
+    /// Limit on the number of processes in the container
+    pub(crate) pids_limit: Option<i64>,
+    /// Leave the container in place if it fails
+    pub(crate) keep_on_failure: bool,
+    /// Size of /dev/shm in the container
+    pub(crate) shm_size: Option<String>,
+    /// Mount the container's root filesystem read only
+    pub(crate) read_only_rootfs: bool,
+    /// Give the container a terminal
+    pub(crate) tty: bool,
+    /// Parent cgroup for the container
+    pub(crate) cgroup_parent: Option<String>,
+    /// Cgroup namespace mode for the container
+    pub(crate) cgroupns: Option<CgroupNamespace>,
+    /// Isolation technology for Windows containers
+    pub(crate) isolation: Option<Isolation>,
+    /// User namespace mode for the container
+    pub(crate) userns: Option<String>,
+    /// Whether the container is labelled with the time it was started
+    pub(crate) label_start_time: bool,
+    /// How long the session may run before the container is killed
+    pub(crate) session_timeout: Option<Duration>,
+    /// Names the volumes which aren't shared, instead of the configuration
+    /// file's path
+    pub(crate) volume_key: Option<String>,
+    /// Host environment variables to forward into the container
+    pub(crate) forwarded_variables: Vec<String>,
+    /// With keep_env_clean, the only names which can be passed into the
+    /// container
+    pub(crate) clean_env: Option<Vec<String>>,
+    /// Prefix added to the names of forwarded variables in the container
+    pub(crate) forward_env_prefix: Option<String>,
+    /// Locale set as LANG, LC_ALL and LANGUAGE in the container
+    pub(crate) locale: Option<String>,
+    /// Signal used to stop the container
+    pub(crate) stop_signal: Option<String>,
+    /// Fixed MAC address for the container
+    pub(crate) mac_address: Option<String>,
+    /// Mount specs passed verbatim to docker
+    pub(crate) mounts: Vec<String>,
+    /// Host devices to pass through to the container
+    pub(crate) devices: Vec<String>,
+    /// Host name the container can reach the host by
+    pub(crate) host_gateway_alias: Option<String>,
+    /// Commands to run on the host once the container exits
+    pub(crate) on_exit: Vec<CommandSpec>,
+    /// When to pull the image
+    pub(crate) pull_policy: Option<PullPolicy>,
+    /// The runtime behind the docker CLI
+    pub(crate) runtime: Runtime,
+    /// Remove stopped floki containers before starting
+    pub(crate) cleanup_orphans: bool,
+    /// Problems found with the configuration which don't stop floki running
+    pub(crate) warnings: Vec<Warning>,
 }
 
 impl FlokiSpec {
     pub(crate) fn from(config: FlokiConfig, environ: Environment) -> Result<Self, Error> {
+        let registry_mirror = config.registry_mirror;
+
         let dind = match config.dind {
-            DindConfig::Toggle(true) => Some(Dind {
-                image: "docker:stable-dind".to_string(),
-            }),
+            DindConfig::Toggle(true) => Some((environ.defaults.dind_image().to_string(), None)),
             DindConfig::Toggle(false) => None,
-            DindConfig::Image { image } => Some(Dind { image }),
-        };
+            DindConfig::Image { image, healthcheck } => Some((image, healthcheck)),
+        }
+        .map(|(image, healthcheck)| Dind {
+            image: match &registry_mirror {
+                Some(mirror) => image::apply_registry_mirror(&image, mirror),
+                None => image,
+            },
+            healthcheck,
+        });
 
         let user = User {
-            forward: config.forward_user,
+            forward: config.forward_user.forward(),
             uid: environ.user_details.uid,
             gid: environ.user_details.gid,
+            map_names: config.forward_user.map_names(),
+            drop_privileges: config.drop_privileges,
         };
 
         let entrypoint = config.entrypoint.value().map(|v| v.to_string());
@@ -103,33 +206,172 @@ impl FlokiSpec {
             None
         };
 
+        let mounted = if config.bind_current_directory {
+            environ.current_directory.clone()
+        } else {
+            environ.floki_root.clone()
+        };
         let internal_working_directory = get_working_directory(
             &environ.current_directory,
-            &environ.floki_root,
+            &mounted,
             &path::PathBuf::from(&config.mount),
         );
 
+        let shell_rc = match &config.shell_rc {
+            Some(rc) => Some(resolve_shell_rc(
+                environ.config_file.parent().unwrap_or(&environ.floki_root),
+                rc,
+            )?),
+            None => None,
+        };
+        let label_files = resolve_label_files(
+            environ.config_file.parent().unwrap_or(&environ.floki_root),
+            &config.label_files,
+        )?;
+
+        if let Some(memory_swap) = &config.memory_swap {
+            validate_memory_swap(memory_swap)?;
+        }
+
+        if let Some(shm_size) = &config.shm_size {
+            validate_size("shm_size", shm_size)?;
+        }
+
+        if let Some(stop_signal) = &config.stop_signal {
+            validate_stop_signal(stop_signal)?;
+        }
+
+        if let Some(mac_address) = &config.mac_address {
+            validate_mac_address(mac_address)?;
+        }
+
+        if let Some(userns) = &config.userns {
+            validate_userns(userns)?;
+        }
+
+        if let Some(oom_score_adj) = config.oom_score_adj {
+            validate_oom_score_adj(oom_score_adj)?;
+        }
+
+        let variables = path_variables(&environ.config_file, &environ.floki_root);
+
+        let mounts: Vec<String> = config
+            .mounts
+            .iter()
+            .map(|mount| interpolate(mount, &variables))
+            .collect();
+        for mount in &mounts {
+            validate_mount(mount)?;
+        }
+        for device in &config.devices {
+            validate_device(device)?;
+        }
+
+        let forwarded_variables = forwarded_variables(
+            &config.forward_env_prefixes,
+            &environ.host_variables,
+            config.keep_env_clean,
+        );
+        let clean_env = if config.keep_env_clean {
+            Some(config.forward_env_prefixes.clone())
+        } else {
+            None
+        };
+
+        // Relative to the configuration, which is also the default
+        let outer_workdir = environ
+            .floki_root
+            .join(config.outer_workdir.unwrap_or_default());
         let paths = Paths {
             internal_working_directory,
+            outer_workdir,
             root: environ.floki_root,
+            mounted,
             config: environ.config_file,
             workspace: environ.floki_workspace,
         };
 
-        let docker_switches = decompose_switches(&config.docker_switches)?;
+        let mut docker_switches = decompose_switches(
+            &config
+                .docker_switches
+                .iter()
+                .map(|switch| interpolate(switch, &variables))
+                .collect::<Vec<_>>(),
+        )?;
+        if config.dedupe_docker_switches {
+            docker_switches = dedupe_switches(docker_switches);
+        }
+
+        let init = init_commands(config.umask.as_deref(), config.init)?;
+
+        // Builds can use the forwarded agent too, e.g. to clone private repos
+        let mut image = config.image;
+        image.validate()?;
+        if ssh_agent.is_some() {
+            image.forward_ssh_agent_to_build();
+        }
+
+        let mut warnings = image.warnings(&paths.root);
+        if config.pull_policy.is_some() && !image.is_remote() {
+            warnings.push(Warning::PullPolicyNotPulled);
+        }
+        for (name, volume) in &config.volumes {
+            warnings.extend(validate_volume_options(name, &volume.options)?);
+            validate_tmpfs_volume(name, volume)?;
+        }
+        validate_mount_paths(&config.mount, &config.volumes)?;
+        let (isolation, isolation_warning) =
+            isolation_for_host(config.isolation, std::env::consts::OS);
+        warnings.extend(isolation_warning);
 
         let spec = FlokiSpec {
-            image: config.image,
-            init: config.init,
+            image,
+            init,
             mount: config.mount,
-            shell: config.shell,
+            shell: config
+                .shell
+                .unwrap_or_else(|| crate::config::Shell::Shell("sh".into())),
             entrypoint,
+            entrypoint_script: config.entrypoint.script(),
             volumes: config.volumes,
             user,
             ssh_agent,
             docker_switches,
             dind,
             paths,
+            registry_mirror,
+            registry_auth: config.registry_auth,
+            label_files,
+            shell_rc,
+            memory_swap: config.memory_swap,
+            oom_kill_disable: config.oom_kill_disable,
+            oom_score_adj: config.oom_score_adj,
+            pids_limit: config.pids_limit,
+            read_only_rootfs: config.read_only_rootfs,
+            tty: config.tty.unwrap_or(environ.interactive),
+            cgroup_parent: config.cgroup_parent,
+            cgroupns: config.cgroupns,
+            isolation,
+            userns: config.userns,
+            label_start_time: config.label_start_time,
+            session_timeout: config.session_timeout.map(Duration::from_secs),
+            volume_key: config.volume_key,
+            keep_on_failure: config.keep_on_failure,
+            shm_size: config.shm_size,
+            forwarded_variables,
+            clean_env,
+            forward_env_prefix: config.forward_env_prefix,
+            locale: config.locale,
+            stop_signal: config.stop_signal,
+            mac_address: config.mac_address,
+            mounts,
+            devices: config.devices,
+            host_gateway_alias: config.host_gateway_alias,
+            on_exit: config.on_exit,
+            pull_policy: config.pull_policy,
+            runtime: config.runtime,
+            cleanup_orphans: config.cleanup_orphans,
+            warnings,
         };
 
         debug!("built spec from config and environment: {:?}", spec);
@@ -138,6 +380,57 @@ impl FlokiSpec {
     }
 }
 
+impl FlokiSpec {
+    /// Check every forwarded host variable is in the allowlist - those
+    /// matching forward_env_prefixes, and those named by `-e` in the
+    /// docker_switches
+    pub(crate) fn enforce_env_allowlist(&self, allowlist: &[String]) -> Result<(), Error> {
+        match self
+            .forwarded_variables
+            .iter()
+            .chain(&switch_forwarded_variables(&self.docker_switches))
+            .find(|var| !allowlist.contains(var))
+        {
+            Some(var) => {
+                Err(errors::FlokiError::EnvVariableNotAllowed { name: var.clone() }.into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The environment variables floki sets inside the container
+    pub(crate) fn resolved_environment(&self) -> BTreeMap<String, OsString> {
+        let mut environment = BTreeMap::new();
+        environment.insert(
+            "FLOKI_HOST_MOUNTDIR".to_string(),
+            self.paths.mounted.clone().into_os_string(),
+        );
+        environment.insert(
+            "FLOKI_HOST_UID".to_string(),
+            self.user.uid.to_string().into(),
+        );
+        environment.insert(
+            "FLOKI_HOST_GID".to_string(),
+            self.user.gid.to_string().into(),
+        );
+        environment.insert(
+            "FLOKI_INTERACTIVE".to_string(),
+            if self.tty { "1" } else { "0" }.into(),
+        );
+        if let Some(listed) = &self.clean_env {
+            environment.retain(|var, _| listed.contains(var));
+        }
+        // The locale is configured explicitly, so it's set even in a clean
+        // environment
+        if let Some(locale) = &self.locale {
+            for var in ["LANG", "LC_ALL", "LANGUAGE"] {
+                environment.insert(var.to_string(), locale.into());
+            }
+        }
+        environment
+    }
+}
+
 fn decompose_switches(specs: &[String]) -> Result<Vec<String>, Error> {
     let mut flattened = Vec::new();
 
@@ -154,13 +447,416 @@ fn decompose_switches(specs: &[String]) -> Result<Vec<String>, Error> {
     Ok(flattened)
 }
 
+/// Docker switches which take a single value, so only their last
+/// occurrence has any effect
+const SINGLE_VALUE_SWITCHES: &[&str] =
+    &["-w", "--workdir", "--network", "--net", "--hostname", "-h"];
+
+/// Drop all but the last occurrence of each single value switch, e.g.
+/// when merged configurations each set a --network. Other switches, such
+/// as -v and -e, are kept as they are.
+fn dedupe_switches(switches: Vec<String>) -> Vec<String> {
+    // Group each switch with its value, whether given as a separate
+    // argument or after an =
+    let mut groups: Vec<(Option<&str>, Vec<String>)> = Vec::new();
+    let mut switches = switches.into_iter();
+    while let Some(switch) = switches.next() {
+        match SINGLE_VALUE_SWITCHES
+            .iter()
+            .find(|name| switch == **name || switch.starts_with(&format!("{}=", name)))
+        {
+            Some(name) if switch == *name => {
+                let group = std::iter::once(switch).chain(switches.next()).collect();
+                groups.push((Some(canonical_switch(name)), group));
+            }
+            Some(name) => groups.push((Some(canonical_switch(name)), vec![switch])),
+            None => groups.push((None, vec![switch])),
+        }
+    }
+
+    let last = |index: usize, name: &str| {
+        !groups[index + 1..]
+            .iter()
+            .any(|(other, _)| *other == Some(name))
+    };
+    groups
+        .iter()
+        .enumerate()
+        .filter(|(index, (name, _))| name.is_none_or(|name| last(*index, name)))
+        .flat_map(|(_, (_, group))| group.clone())
+        .collect()
+}
+
+/// Short and long forms of a switch are the same switch
+fn canonical_switch(name: &str) -> &'static str {
+    match name {
+        "-w" | "--workdir" => "--workdir",
+        "--net" | "--network" => "--network",
+        _ => "--hostname",
+    }
+}
+
+/// Build the init commands, setting the umask before anything else runs
+fn init_commands(umask: Option<&str>, init: Vec<String>) -> Result<Vec<String>, Error> {
+    match umask {
+        Some(umask) => {
+            validate_umask(umask)?;
+            Ok(std::iter::once(format!("umask {}", umask))
+                .chain(init)
+                .collect())
+        }
+        None => Ok(init),
+    }
+}
+
+fn validate_umask(umask: &str) -> Result<(), Error> {
+    if (1..=4).contains(&umask.len()) && umask.chars().all(|c| ('0'..='7').contains(&c)) {
+        Ok(())
+    } else {
+        Err(errors::FlokiError::InvalidUmask {
+            value: umask.into(),
+        }
+        .into())
+    }
+}
+
+/// Load an allowlist of environment variables which may be forwarded
+/// The host variables docker switches forward - those given to `-e` or
+/// `--env` by name alone, rather than with a value
+fn switch_forwarded_variables(switches: &[String]) -> Vec<String> {
+    let mut variables = Vec::new();
+    let mut switches = switches.iter();
+    while let Some(switch) = switches.next() {
+        let value = match switch.as_str() {
+            "-e" | "--env" => switches.next().map(String::as_str),
+            other => other.strip_prefix("--env="),
+        };
+        if let Some(name) = value.filter(|value| !value.contains('=')) {
+            variables.push(name.to_string());
+        }
+    }
+    variables
+}
+
+pub(crate) fn load_env_allowlist(path: &path::Path) -> Result<Vec<String>, Error> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read allowlist {}", path.display()))?;
+    Ok(parse_env_allowlist(&contents))
+}
+
+/// Allowlists have one variable name per line. Blank lines and lines
+/// starting with '#' are ignored.
+fn parse_env_allowlist(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether a variable is one of floki's own, such as its FLOKI_PROFILE
+/// setting on the host
+fn is_floki_variable(var: &str) -> bool {
+    var.starts_with("FLOKI_")
+}
+
+/// Find the host variables with names starting with any of the prefixes.
+/// floki's own variables only match prefixes which are explicitly for
+/// them. When strict, only variables named in full are found.
+fn forwarded_variables(
+    prefixes: &[String],
+    host_variables: &[String],
+    strict: bool,
+) -> Vec<String> {
+    let matches = |var: &str, prefix: &str| {
+        if strict {
+            var == prefix
+        } else {
+            var.starts_with(prefix) && (!is_floki_variable(var) || is_floki_variable(prefix))
+        }
+    };
+    let mut forwarded: Vec<String> = host_variables
+        .iter()
+        .filter(|var| prefixes.iter().any(|prefix| matches(var, prefix)))
+        .cloned()
+        .collect();
+    forwarded.sort();
+    forwarded
+}
+
+/// Check a value is a docker size, i.e. a number with an optional unit
+fn validate_size(field: &str, value: &str) -> Result<(), Error> {
+    let digits = value.trim_end_matches(|c: char| "bkmgBKMG".contains(c));
+    let unit_length = value.len() - digits.len();
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) && unit_length <= 1 {
+        Ok(())
+    } else {
+        Err(errors::FlokiError::InvalidSize {
+            field: field.into(),
+            value: value.into(),
+        }
+        .into())
+    }
+}
+
+/// The variables which can be used in docker switches and mounts
+fn path_variables(
+    config_file: &path::Path,
+    floki_root: &path::Path,
+) -> BTreeMap<&'static str, String> {
+    let config_dir = config_file.parent().unwrap_or(floki_root);
+    let mut variables = BTreeMap::new();
+    variables.insert("FLOKI_CONFIG_DIR", config_dir.display().to_string());
+    variables.insert("FLOKI_ROOT", floki_root.display().to_string());
+    variables
+}
+
+/// Expand ${NAME} references to the given variables. Other references are
+/// left as they are.
+fn interpolate(value: &str, variables: &BTreeMap<&'static str, String>) -> String {
+    variables
+        .iter()
+        .fold(value.to_string(), |value, (name, replacement)| {
+            value.replace(&format!("${{{}}}", name), replacement)
+        })
+}
+
+/// Volume options docker is known to accept on a bind mount
+const KNOWN_VOLUME_OPTIONS: &[&str] = &[
+    "ro",
+    "rw",
+    "z",
+    "Z",
+    "shared",
+    "rshared",
+    "slave",
+    "rslave",
+    "private",
+    "rprivate",
+    "nocopy",
+    "consistent",
+    "cached",
+    "delegated",
+];
+
+/// Check that a tmpfs volume doesn't ask for anything which needs a host
+/// directory - tmpfs mounts belong to a single container, so can't be
+/// shared, seeded or set up once
+fn validate_tmpfs_volume(name: &str, volume: &Volume) -> Result<(), Error> {
+    if !volume.tmpfs {
+        return Ok(());
+    }
+    let conflict = if volume.shared {
+        Some("shared")
+    } else if volume.share_scope.is_some() {
+        Some("share_scope")
+    } else if volume.init_from.is_some() {
+        Some("init_from")
+    } else if !volume.setup_once.is_empty() {
+        Some("setup_once")
+    } else {
+        None
+    };
+    match conflict {
+        Some(option) => Err(errors::FlokiError::TmpfsVolumeConflict {
+            volume: name.into(),
+            option: option.into(),
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Check the options of a volume can be appended to its mapping. Options
+/// which aren't known are passed on, but warned about.
+fn validate_volume_options(volume: &str, options: &[String]) -> Result<Vec<Warning>, Error> {
+    let mut warnings = Vec::new();
+    for option in options {
+        if option.is_empty() || option.contains(':') || option.contains(',') {
+            return Err(errors::FlokiError::InvalidVolumeOption {
+                volume: volume.into(),
+                option: option.clone(),
+            }
+            .into());
+        }
+        if !KNOWN_VOLUME_OPTIONS.contains(&option.as_str()) {
+            warnings.push(Warning::UnknownVolumeOption {
+                volume: volume.into(),
+                option: option.clone(),
+            });
+        }
+    }
+    Ok(warnings)
+}
+
+/// The isolation mode to use on a host - only Windows hosts run Windows
+/// containers, so it is dropped with a warning anywhere else
+fn isolation_for_host(
+    isolation: Option<Isolation>,
+    os: &str,
+) -> (Option<Isolation>, Option<Warning>) {
+    match isolation {
+        Some(_) if os != "windows" => (None, Some(Warning::IsolationIgnored { os: os.into() })),
+        isolation => (isolation, None),
+    }
+}
+
+/// Check nothing else is mounted where the project or a volume is, as
+/// docker doesn't say which mount wins
+fn validate_mount_paths(
+    project_mount: &path::Path,
+    volumes: &BTreeMap<String, Volume>,
+) -> Result<(), Error> {
+    let mut mounted: BTreeMap<&path::Path, String> = BTreeMap::new();
+    mounted.insert(project_mount, "the project".into());
+    for (name, volume) in volumes {
+        let this = format!("volume '{}'", name);
+        if let Some(first) = mounted.get(volume.mount.as_path()) {
+            return Err(errors::FlokiError::MountPathCollision {
+                mount: volume.mount.display().to_string(),
+                first: first.clone(),
+                second: this,
+            }
+            .into());
+        }
+        mounted.insert(&volume.mount, this);
+    }
+    Ok(())
+}
+
+/// Check a device starts with an absolute host device path, optionally
+/// followed by the container path and permissions
+fn validate_device(spec: &str) -> Result<(), Error> {
+    let host_path = spec.split(':').next().unwrap_or_default();
+    if path::Path::new(host_path).is_absolute() {
+        Ok(())
+    } else {
+        Err(errors::FlokiError::InvalidDevice { spec: spec.into() }.into())
+    }
+}
+
+/// Check a mount looks like a docker --mount spec
+fn validate_mount(spec: &str) -> Result<(), Error> {
+    if spec.starts_with("type=") {
+        Ok(())
+    } else {
+        Err(errors::FlokiError::InvalidMount { spec: spec.into() }.into())
+    }
+}
+
+/// Check a stop signal is a signal name (like SIGTERM) or number
+fn validate_stop_signal(value: &str) -> Result<(), Error> {
+    let valid = if value.starts_with("SIG") {
+        value.parse::<nix::sys::signal::Signal>().is_ok()
+    } else {
+        matches!(value.parse::<u8>(), Ok(1..=64))
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(errors::FlokiError::InvalidStopSignal {
+            value: value.into(),
+        }
+        .into())
+    }
+}
+
+/// Check an OOM score adjustment is in the range the kernel accepts
+fn validate_oom_score_adj(value: i64) -> Result<(), Error> {
+    if (-1000..=1000).contains(&value) {
+        Ok(())
+    } else {
+        Err(errors::FlokiError::InvalidOomScoreAdj { value }.into())
+    }
+}
+
+/// Check a MAC address is six colon separated pairs of hex digits
+fn validate_mac_address(value: &str) -> Result<(), Error> {
+    let octets: Vec<&str> = value.split(':').collect();
+    let valid = octets.len() == 6
+        && octets
+            .iter()
+            .all(|octet| octet.len() == 2 && octet.chars().all(|c| c.is_ascii_hexdigit()));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(errors::FlokiError::InvalidMacAddress {
+            value: value.into(),
+        }
+        .into())
+    }
+}
+
+/// Check a user namespace mode - docker only has host, to opt out of
+/// userns-remap, or empty for the daemon's default
+fn validate_userns(value: &str) -> Result<(), Error> {
+    match value {
+        "host" | "" => Ok(()),
+        _ => Err(errors::FlokiError::InvalidUserns {
+            value: value.into(),
+        }
+        .into()),
+    }
+}
+
+/// Check a memory swap limit - either a size, or -1 for unlimited swap
+fn validate_memory_swap(value: &str) -> Result<(), Error> {
+    if value == "-1" {
+        Ok(())
+    } else {
+        validate_size("memory_swap", value)
+    }
+}
+
+/// Resolve the shell rc file relative to the configuration directory,
+/// checking that it exists - docker would mount a new directory otherwise
+fn resolve_shell_rc(
+    config_directory: &path::Path,
+    rc: &path::Path,
+) -> Result<path::PathBuf, Error> {
+    let resolved = config_directory.join(rc);
+    if resolved.is_file() {
+        Ok(resolved)
+    } else {
+        Err(errors::FlokiError::ShellRcNotFound {
+            name: resolved.display().to_string(),
+        }
+        .into())
+    }
+}
+
+/// Resolve label files relative to the configuration directory, checking
+/// that they exist
+fn resolve_label_files(
+    config_directory: &path::Path,
+    files: &[path::PathBuf],
+) -> Result<Vec<path::PathBuf>, Error> {
+    files
+        .iter()
+        .map(|file| {
+            let resolved = config_directory.join(file);
+            if resolved.is_file() {
+                Ok(resolved)
+            } else {
+                Err(errors::FlokiError::LabelFileNotFound {
+                    name: resolved.display().to_string(),
+                }
+                .into())
+            }
+        })
+        .collect()
+}
+
 /// Determine what directory we are currently in
 fn get_working_directory(
     current_directory: &path::Path,
     floki_root: &path::Path,
     mount: &path::Path,
 ) -> path::PathBuf {
-    mount.join(current_directory.strip_prefix(&floki_root).expect(
+    mount.join(current_directory.strip_prefix(floki_root).expect(
         "failed to deduce working directory - \
          floki_root should always be an ancestor of current_directory",
     ))
@@ -169,6 +865,218 @@ fn get_working_directory(
 #[cfg(test)]
 mod test {
     use super::*;
+
+    #[test]
+    fn test_outer_workdir() -> Result<(), Error> {
+        let outer_workdir = |yaml: &str| -> Result<path::PathBuf, Error> {
+            let spec = FlokiSpec::from(serde_yaml::from_str(yaml)?, Environment::for_tests())?;
+            Ok(spec.paths.outer_workdir)
+        };
+        assert_eq!(
+            outer_workdir("image: foo")?,
+            path::PathBuf::from("/host/src/")
+        );
+        assert_eq!(
+            outer_workdir("image: foo\nouter_workdir: out")?,
+            path::PathBuf::from("/host/src/out")
+        );
+        assert_eq!(
+            outer_workdir("image: foo\nouter_workdir: /tmp")?,
+            path::PathBuf::from("/tmp")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_interactive_follows_tty() -> Result<(), Error> {
+        let interactive = |yaml: &str, terminal: bool| -> Result<OsString, Error> {
+            let environment = Environment {
+                interactive: terminal,
+                ..Environment::for_tests()
+            };
+            let spec = FlokiSpec::from(serde_yaml::from_str(yaml)?, environment)?;
+            Ok(spec.resolved_environment()["FLOKI_INTERACTIVE"].clone())
+        };
+
+        assert_eq!(interactive("image: foo", true)?, "1");
+        assert_eq!(interactive("image: foo", false)?, "0");
+        // The tty option wins over detection
+        assert_eq!(interactive("image: foo\ntty: false", true)?, "0");
+        assert_eq!(interactive("image: foo\ntty: true", false)?, "1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_override_moves_working_directory() -> Result<(), Error> {
+        let mut config: FlokiConfig = serde_yaml::from_str("image: foo\nmount: /src")?;
+        crate::config::RuntimeOverrides {
+            mount: Some("/work".into()),
+            ..Default::default()
+        }
+        .apply(&mut config);
+
+        let environment = Environment {
+            current_directory: "/host/src/module".into(),
+            ..Environment::for_tests()
+        };
+        let spec = FlokiSpec::from(config, environment)?;
+        assert_eq!(spec.mount, path::PathBuf::from("/work"));
+        assert_eq!(
+            spec.paths.internal_working_directory,
+            path::PathBuf::from("/work/module")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_current_directory() -> Result<(), Error> {
+        let mut config: FlokiConfig = serde_yaml::from_str("image: foo\nmount: /src")?;
+        crate::config::RuntimeOverrides {
+            bind_current_dir_as: Some("/work".into()),
+            ..Default::default()
+        }
+        .apply(&mut config);
+
+        let environment = Environment {
+            current_directory: "/host/src/module".into(),
+            ..Environment::for_tests()
+        };
+        let spec = FlokiSpec::from(config, environment)?;
+        assert_eq!(spec.mount, path::PathBuf::from("/work"));
+        assert_eq!(spec.paths.mounted, path::PathBuf::from("/host/src/module"));
+        assert_eq!(
+            spec.paths.internal_working_directory,
+            path::PathBuf::from("/work")
+        );
+        assert_eq!(
+            spec.resolved_environment()["FLOKI_HOST_MOUNTDIR"],
+            OsString::from("/host/src/module")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_warnings() -> Result<(), Error> {
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str(
+                "image:\n  build:\n    name: foo\n    context: https://github.com/me/tools.git\n    cache_build: true\npull_policy: never",
+            )?,
+            Environment::for_tests(),
+        )?;
+        assert_eq!(
+            spec.warnings,
+            vec![
+                Warning::CacheBuildWithRemoteContext,
+                Warning::PullPolicyNotPulled
+            ]
+        );
+
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str("image:\n  build:\n    name: foo\n    auto_git_build_args: true")?,
+            Environment::for_tests(),
+        )?;
+        assert_eq!(
+            spec.warnings,
+            vec![Warning::NotAGitRepository {
+                directory: "/host/src".into()
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_warnings() -> Result<(), Error> {
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str("image: foo\npull_policy: never")?,
+            Environment::for_tests(),
+        )?;
+        assert!(spec.warnings.is_empty());
+
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str("image:\n  build:\n    name: foo\n    cache_build: true")?,
+            Environment::for_tests(),
+        )?;
+        assert!(spec.warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dind_image_default() -> Result<(), Error> {
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str("image: foo\ndind: true")?,
+            Environment::for_tests(),
+        )?;
+        assert_eq!(spec.dind.unwrap().image, "docker:stable-dind");
+
+        // The host's defaults file can change the default...
+        let environment = Environment {
+            defaults: serde_yaml::from_str("dind_image: docker:20-dind")?,
+            ..Environment::for_tests()
+        };
+        let spec = FlokiSpec::from(serde_yaml::from_str("image: foo\ndind: true")?, environment)?;
+        assert_eq!(spec.dind.unwrap().image, "docker:20-dind");
+
+        // ...but an image in the configuration takes precedence
+        let environment = Environment {
+            defaults: serde_yaml::from_str("dind_image: docker:20-dind")?,
+            ..Environment::for_tests()
+        };
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str("image: foo\ndind:\n  image: docker:19-dind")?,
+            environment,
+        )?;
+        assert_eq!(spec.dind.unwrap().image, "docker:19-dind");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dind_image_mirrored() -> Result<(), Error> {
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str("image: foo\ndind: true\nregistry_mirror: mirror.internal")?,
+            Environment::for_tests(),
+        )?;
+        assert_eq!(
+            spec.dind.unwrap().image,
+            "mirror.internal/library/docker:stable-dind"
+        );
+
+        // Images on other registries are left alone
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str(
+                "image: foo\ndind:\n  image: quay.io/me/dind:1\nregistry_mirror: mirror.internal",
+            )?,
+            Environment::for_tests(),
+        )?;
+        assert_eq!(spec.dind.unwrap().image, "quay.io/me/dind:1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarded_ssh_agent_defaults_build_ssh() -> Result<(), Error> {
+        let yaml = "image:\n  build:\n    name: foo\nforward_ssh_agent: true";
+        let spec = FlokiSpec::from(
+            serde_yaml::from_str(yaml)?,
+            Environment {
+                ssh_agent_socket: Some("/tmp/agent.sock".into()),
+                ..Environment::for_tests()
+            },
+        )?;
+
+        let expected: image::Image =
+            serde_yaml::from_str("build:\n  name: foo\n  ssh:\n    - default")?;
+        assert_eq!(spec.image, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_ssh_not_defaulted_without_agent() -> Result<(), Error> {
+        let yaml = "image:\n  build:\n    name: foo";
+        let spec = FlokiSpec::from(serde_yaml::from_str(yaml)?, Environment::for_tests())?;
+
+        let expected: image::Image = serde_yaml::from_str("build:\n  name: foo")?;
+        assert_eq!(spec.image, expected);
+        Ok(())
+    }
     #[test]
     fn test_decompose_switches() -> Result<(), Error> {
         let switches = vec!["-e FOO='bar baz'".to_string()];
@@ -189,6 +1097,429 @@ mod test {
         assert!(got.is_err());
     }
 
+    #[test]
+    fn test_umask_precedes_init() -> Result<(), Error> {
+        let init = vec!["echo hello".to_string()];
+
+        let want: Vec<String> = vec!["umask 002".to_string(), "echo hello".to_string()];
+
+        let got = init_commands(Some("002"), init)?;
+
+        assert_eq!(want, got);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_umask_validation() {
+        assert!(validate_umask("022").is_ok());
+        assert!(validate_umask("0027").is_ok());
+        assert!(validate_umask("").is_err());
+        assert!(validate_umask("089").is_err());
+        assert!(validate_umask("u=rwx").is_err());
+        assert!(validate_umask("00022").is_err());
+    }
+
+    #[test]
+    fn test_resolve_shell_rc() -> Result<(), Error> {
+        let tmp_dir = tempfile::tempdir()?;
+        std::fs::write(tmp_dir.path().join("floki.rc"), "alias ll='ls -l'\n")?;
+        assert_eq!(
+            resolve_shell_rc(tmp_dir.path(), path::Path::new("floki.rc"))?,
+            tmp_dir.path().join("floki.rc")
+        );
+        assert!(resolve_shell_rc(tmp_dir.path(), path::Path::new("missing.rc")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_label_files() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        std::fs::write(tmp_dir.path().join("labels.env"), "a=b\n")?;
+
+        let got = resolve_label_files(tmp_dir.path(), &["labels.env".into()])?;
+
+        assert_eq!(got, vec![tmp_dir.path().join("labels.env")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_label_files_missing() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+
+        let got = resolve_label_files(tmp_dir.path(), &["missing.env".into()]);
+
+        assert!(got.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarded_variables() {
+        let host_variables: Vec<String> = vec![
+            "AWS_SECRET_ACCESS_KEY".into(),
+            "PATH".into(),
+            "AWS_ACCESS_KEY_ID".into(),
+            "MY_AWS_THING".into(),
+            "CARGO_HOME".into(),
+        ];
+
+        let got = forwarded_variables(&["AWS_".into()], &host_variables, false);
+
+        assert_eq!(got, vec!["AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY"]);
+        assert!(forwarded_variables(&[], &host_variables, false).is_empty());
+    }
+
+    #[test]
+    fn test_floki_variables_not_forwarded() {
+        let host_variables: Vec<String> = vec!["FLOKI_PROFILE".into(), "FOO_BAR".into()];
+
+        // Matching every variable doesn't pick up floki's own...
+        assert_eq!(
+            forwarded_variables(&["F".into()], &host_variables, false),
+            vec!["FOO_BAR"]
+        );
+        // ...unless they're asked for
+        assert_eq!(
+            forwarded_variables(&["FLOKI_".into()], &host_variables, false),
+            vec!["FLOKI_PROFILE"]
+        );
+    }
+
+    #[test]
+    fn test_strict_forwarded_variables() {
+        let host_variables: Vec<String> = vec!["AWS_PROFILE".into(), "AWS_REGION".into()];
+        assert!(forwarded_variables(&["AWS_".into()], &host_variables, true).is_empty());
+        assert_eq!(
+            forwarded_variables(&["AWS_PROFILE".into()], &host_variables, true),
+            vec!["AWS_PROFILE"]
+        );
+    }
+
+    #[test]
+    fn test_keep_env_clean() -> Result<(), Error> {
+        let environment = |yaml: &str| -> Result<BTreeMap<String, OsString>, Error> {
+            let spec = FlokiSpec::from(serde_yaml::from_str(yaml)?, Environment::for_tests())?;
+            Ok(spec.resolved_environment())
+        };
+
+        assert!(environment("image: foo")?.contains_key("FLOKI_HOST_UID"));
+
+        let clean = environment("image: foo\nkeep_env_clean: true")?;
+        assert!(!clean.contains_key("FLOKI_HOST_UID"));
+        assert!(clean.is_empty());
+
+        // Listing a variable exposes it again
+        let clean = environment(
+            "image: foo\nkeep_env_clean: true\nforward_env_prefixes:\n  - FLOKI_HOST_UID",
+        )?;
+        assert_eq!(clean.keys().collect::<Vec<_>>(), vec!["FLOKI_HOST_UID"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_size() {
+        assert!(validate_size("memory_swap", "512m").is_ok());
+        assert!(validate_size("memory_swap", "2G").is_ok());
+        assert!(validate_size("memory_swap", "1048576").is_ok());
+        assert!(validate_size("memory_swap", "").is_err());
+        assert!(validate_size("memory_swap", "g").is_err());
+        assert!(validate_size("memory_swap", "2gb").is_err());
+        assert!(validate_size("memory_swap", "two gigs").is_err());
+        assert!(validate_size("memory_swap", "-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_env_allowlist() {
+        let contents = "# Credentials\nAWS_PROFILE\n\n  AWS_REGION  \n";
+        assert_eq!(
+            parse_env_allowlist(contents),
+            vec!["AWS_PROFILE", "AWS_REGION"]
+        );
+    }
+
+    #[test]
+    fn test_enforce_env_allowlist() -> Result<(), Error> {
+        let environment = || Environment {
+            host_variables: vec!["AWS_PROFILE".into(), "AWS_SECRET".into()],
+            ..Environment::for_tests()
+        };
+        let allowlist = vec!["AWS_PROFILE".to_string()];
+
+        // An allowed variable is forwarded
+        let yaml = "image: foo\nforward_env_prefixes:\n  - AWS_PROFILE";
+        let spec = FlokiSpec::from(serde_yaml::from_str(yaml)?, environment())?;
+        assert!(spec.enforce_env_allowlist(&allowlist).is_ok());
+
+        // A variable outside the allowlist is refused
+        let yaml = "image: foo\nforward_env_prefixes:\n  - AWS_";
+        let spec = FlokiSpec::from(serde_yaml::from_str(yaml)?, environment())?;
+        let error = spec.enforce_env_allowlist(&allowlist).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<errors::FlokiError>(),
+            Some(errors::FlokiError::EnvVariableNotAllowed { name }) if name == "AWS_SECRET"
+        ));
+
+        // As is one forwarded by the docker switches, though variables
+        // given a value there aren't being forwarded
+        for switches in ["-e AWS_SECRET", "--env AWS_SECRET", "--env=AWS_SECRET"] {
+            let yaml = format!(
+                "image: foo\ndocker_switches:\n  - -e AWS_PROFILE\n  - -e TOKEN=abc\n  - {}",
+                switches
+            );
+            let spec = FlokiSpec::from(serde_yaml::from_str(&yaml)?, environment())?;
+            let error = spec.enforce_env_allowlist(&allowlist).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<errors::FlokiError>(),
+                Some(errors::FlokiError::EnvVariableNotAllowed { name }) if name == "AWS_SECRET"
+            ));
+        }
+        let yaml = "image: foo\ndocker_switches:\n  - -e AWS_PROFILE\n  - -e TOKEN=abc";
+        let spec = FlokiSpec::from(serde_yaml::from_str(yaml)?, environment())?;
+        assert!(spec.enforce_env_allowlist(&allowlist).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate_path_variables() {
+        let variables = path_variables(
+            path::Path::new("/host/src/config/floki.yaml"),
+            path::Path::new("/host/src"),
+        );
+        assert_eq!(
+            interpolate("${FLOKI_CONFIG_DIR}/cache:/cache", &variables),
+            "/host/src/config/cache:/cache"
+        );
+        assert_eq!(
+            interpolate(
+                "type=bind,source=${FLOKI_ROOT}/data,target=/data",
+                &variables
+            ),
+            "type=bind,source=/host/src/data,target=/data"
+        );
+        assert_eq!(interpolate("${HOME}/cache", &variables), "${HOME}/cache");
+    }
+
+    #[test]
+    fn test_dedupe_switches_keeps_last_workdir() {
+        let switches = [
+            "-w",
+            "/src",
+            "--network=host",
+            "--workdir=/build",
+            "-w",
+            "/work",
+        ];
+        assert_eq!(
+            dedupe_switches(switches.iter().map(|s| s.to_string()).collect()),
+            vec!["--network=host", "-w", "/work"]
+        );
+    }
+
+    #[test]
+    fn test_dedupe_switches_keeps_repeatable_switches() {
+        let switches = [
+            "-v",
+            "/a:/a",
+            "--network",
+            "none",
+            "-v",
+            "/b:/b",
+            "--net=host",
+        ];
+        assert_eq!(
+            dedupe_switches(switches.iter().map(|s| s.to_string()).collect()),
+            vec!["-v", "/a:/a", "-v", "/b:/b", "--net=host"]
+        );
+    }
+
+    #[test]
+    fn test_docker_switches_are_interpolated() -> Result<(), Error> {
+        let yaml = "image: foo\ndocker_switches:\n  - -v ${FLOKI_CONFIG_DIR}/cache:/cache";
+        let spec = FlokiSpec::from(serde_yaml::from_str(yaml)?, Environment::for_tests())?;
+        assert_eq!(spec.docker_switches, vec!["-v", "/host/src/cache:/cache"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_mount() {
+        assert!(
+            validate_mount("type=bind,source=/data,target=/data,bind-propagation=rslave").is_ok()
+        );
+        assert!(validate_mount("source=/data,target=/data").is_err());
+    }
+
+    #[test]
+    fn test_validate_volume_options() -> Result<(), Error> {
+        assert!(validate_volume_options("cache", &["z".into(), "ro".into()])?.is_empty());
+        assert_eq!(
+            validate_volume_options("cache", &["U".into()])?,
+            vec![Warning::UnknownVolumeOption {
+                volume: "cache".into(),
+                option: "U".into()
+            }]
+        );
+        assert!(validate_volume_options("cache", &["z,ro".into()]).is_err());
+        assert!(validate_volume_options("cache", &["".into()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_tmpfs_volume() -> Result<(), Error> {
+        let volume = |yaml: &str| -> Volume { serde_yaml::from_str(yaml).unwrap() };
+        validate_tmpfs_volume("scratch", &volume("tmpfs: true\nmount: /scratch"))?;
+        // Options for host directories are fine without tmpfs
+        validate_tmpfs_volume("cache", &volume("shared: true\nmount: /cache"))?;
+
+        for yaml in [
+            "tmpfs: true\nshared: true\nmount: /scratch",
+            "tmpfs: true\nshare_scope: team\nmount: /scratch",
+            "tmpfs: true\ninit_from: seed\nmount: /scratch",
+            "tmpfs: true\nsetup_once: [make]\nmount: /scratch",
+        ] {
+            let error = validate_tmpfs_volume("scratch", &volume(yaml)).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<errors::FlokiError>(),
+                Some(errors::FlokiError::TmpfsVolumeConflict { volume, .. }) if volume == "scratch"
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_isolation_for_host() {
+        assert_eq!(
+            isolation_for_host(Some(Isolation::Hyperv), "windows"),
+            (Some(Isolation::Hyperv), None)
+        );
+        assert_eq!(
+            isolation_for_host(Some(Isolation::Hyperv), "linux"),
+            (None, Some(Warning::IsolationIgnored { os: "linux".into() }))
+        );
+        assert_eq!(isolation_for_host(None, "linux"), (None, None));
+    }
+
+    #[test]
+    fn test_validate_mount_paths() -> Result<(), Error> {
+        let volumes =
+            |yaml: &str| -> BTreeMap<String, Volume> { serde_yaml::from_str(yaml).unwrap() };
+        let project = path::Path::new("/src");
+
+        assert!(validate_mount_paths(
+            project,
+            &volumes("cache:\n  mount: /cache\ntarget:\n  mount: /src/target")
+        )
+        .is_ok());
+
+        let error = validate_mount_paths(
+            project,
+            &volumes("cache:\n  mount: /cache\nother:\n  mount: /cache/"),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<errors::FlokiError>(),
+            Some(errors::FlokiError::MountPathCollision { mount, first, second })
+                if mount == "/cache/" && first == "volume 'cache'" && second == "volume 'other'"
+        ));
+
+        let error = validate_mount_paths(project, &volumes("cache:\n  mount: /src")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the project and volume 'cache' are both mounted at /src - give them different mount paths"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_device() {
+        assert!(validate_device("/dev/ttyUSB0").is_ok());
+        assert!(validate_device("/dev/ttyUSB0:/dev/ttyS0:rwm").is_ok());
+        assert!(validate_device("").is_err());
+        assert!(validate_device("ttyUSB0:/dev/ttyUSB0").is_err());
+    }
+
+    #[test]
+    fn test_validate_oom_score_adj() {
+        assert!(validate_oom_score_adj(-1000).is_ok());
+        assert!(validate_oom_score_adj(0).is_ok());
+        assert!(validate_oom_score_adj(1000).is_ok());
+        assert!(validate_oom_score_adj(-1001).is_err());
+
+        let error = FlokiSpec::from(
+            serde_yaml::from_str("image: foo\noom_score_adj: 1001").unwrap(),
+            Environment::for_tests(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<errors::FlokiError>(),
+            Some(errors::FlokiError::InvalidOomScoreAdj { value: 1001 })
+        ));
+    }
+
+    #[test]
+    fn test_validate_userns() {
+        assert!(validate_userns("host").is_ok());
+        assert!(validate_userns("").is_ok());
+        assert!(validate_userns("private").is_err());
+
+        let error = FlokiSpec::from(
+            serde_yaml::from_str("image: foo\nuserns: keep-id").unwrap(),
+            Environment::for_tests(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<errors::FlokiError>(),
+            Some(errors::FlokiError::InvalidUserns { value }) if value == "keep-id"
+        ));
+    }
+
+    #[test]
+    fn test_validate_mac_address() {
+        assert!(validate_mac_address("02:42:ac:11:00:02").is_ok());
+        assert!(validate_mac_address("02:42:AC:11:00:02").is_ok());
+        assert!(validate_mac_address("02:42:ac:11:00").is_err());
+        assert!(validate_mac_address("02-42-ac-11-00-02").is_err());
+        assert!(validate_mac_address("02:42:ac:11:00:2").is_err());
+        assert!(validate_mac_address("02:42:ac:11:00:zz").is_err());
+
+        let error = FlokiSpec::from(
+            serde_yaml::from_str("image: foo\nmac_address: 02:42:ac").unwrap(),
+            Environment::for_tests(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<errors::FlokiError>(),
+            Some(errors::FlokiError::InvalidMacAddress { value }) if value == "02:42:ac"
+        ));
+    }
+
+    #[test]
+    fn test_validate_stop_signal() {
+        assert!(validate_stop_signal("SIGTERM").is_ok());
+        assert!(validate_stop_signal("SIGINT").is_ok());
+        assert!(validate_stop_signal("9").is_ok());
+        assert!(validate_stop_signal("SIGBOGUS").is_err());
+        assert!(validate_stop_signal("TERM").is_err());
+        assert!(validate_stop_signal("0").is_err());
+    }
+
+    #[test]
+    fn test_validate_shm_size() {
+        assert!(validate_size("shm_size", "2g").is_ok());
+        assert!(validate_size("shm_size", "512m").is_ok());
+        assert!(validate_size("shm_size", "big").is_err());
+        assert!(validate_size("shm_size", "2 g").is_err());
+    }
+
+    #[test]
+    fn test_validate_memory_swap() {
+        assert!(validate_memory_swap("-1").is_ok());
+        assert!(validate_memory_swap("4g").is_ok());
+        assert!(validate_memory_swap("-2").is_err());
+        assert!(validate_memory_swap("4 gigabytes").is_err());
+    }
+
     #[test]
     fn test_get_working_directory() {
         let current_directory = path::PathBuf::from("/host/workingdir/");
@@ -197,7 +1528,7 @@ mod test {
 
         assert!(
             get_working_directory(&current_directory, &floki_root, &mount)
-                == path::PathBuf::from("/guest/workingdir/")
+                == path::Path::new("/guest/workingdir/")
         )
     }
 }
@@ -0,0 +1,124 @@
+/// A machine readable summary of what floki did
+use anyhow::{Context, Error};
+use serde::Serialize;
+use std::io::Write;
+use std::path;
+use std::str::FromStr;
+
+/// How floki reports what it did
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown format '{}' (expected text or json)", s)),
+        }
+    }
+}
+
+/// Where the image used for the container came from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ImageSource {
+    /// Produced by a build or exec command
+    Built,
+    /// Not present locally, so fetched when the container started
+    Pulled,
+    /// Already present locally
+    Cached,
+}
+
+/// A volume mounted into the container
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct MountedVolume {
+    pub(crate) host: path::PathBuf,
+    pub(crate) container: path::PathBuf,
+}
+
+/// What floki did during a run
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct Summary {
+    pub(crate) image: Option<String>,
+    pub(crate) image_source: Option<ImageSource>,
+    pub(crate) volumes: Vec<MountedVolume>,
+    pub(crate) exit_code: Option<i32>,
+}
+
+impl Summary {
+    pub(crate) fn add_volume(&mut self, host: &path::Path, container: &path::Path) {
+        self.volumes.push(MountedVolume {
+            host: host.to_path_buf(),
+            container: container.to_path_buf(),
+        });
+    }
+
+    pub(crate) fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string(self).context("Failed to serialize summary")
+    }
+
+    /// Write the summary to the given file, or to stderr if there is none
+    pub(crate) fn emit(&self, file: Option<&path::Path>) -> Result<(), Error> {
+        let json = self.to_json()?;
+        match file {
+            Some(file) => std::fs::write(file, json + "\n")
+                .with_context(|| format!("Failed to write summary to {}", file.display())),
+            None => Ok(writeln!(std::io::stderr(), "{}", json)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialize_summary() -> Result<(), Error> {
+        let mut summary = Summary {
+            image: Some("debian:sid".into()),
+            image_source: Some(ImageSource::Pulled),
+            exit_code: Some(3),
+            ..Default::default()
+        };
+        summary.add_volume(path::Path::new("/host/src"), path::Path::new("/src"));
+
+        let value: serde_json::Value = serde_json::from_str(&summary.to_json()?)?;
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "image": "debian:sid",
+                "image_source": "pulled",
+                "volumes": [{"host": "/host/src", "container": "/src"}],
+                "exit_code": 3,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_summary_to_file() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("summary.json");
+
+        Summary::default().emit(Some(&file))?;
+
+        let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&file)?)?;
+        assert_eq!(value["image"], serde_json::Value::Null);
+        assert_eq!(value["volumes"], serde_json::json!([]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_output_format() {
+        assert_eq!("json".parse(), Ok(OutputFormat::Json));
+        assert_eq!("text".parse(), Ok(OutputFormat::Text));
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -0,0 +1,271 @@
+/// Built in functions which can be called from configuration strings, as
+/// ${env:VAR}, ${file:path} and ${cmd:command}
+use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
+use anyhow::Error;
+use std::path;
+use std::process::{Command, Stdio};
+
+/// Call the functions in every string of a configuration document. Paths
+/// and commands are relative to the directory of its file. Documents
+/// without any calls are returned as they are, to keep the locations in
+/// any parse errors.
+pub(crate) fn expand_document(contents: &str, directory: &path::Path) -> Result<String, Error> {
+    // Documents which don't parse are left for the configuration parser
+    // to report on
+    let mut document: serde_yaml::Value = match serde_yaml::from_str(contents) {
+        Ok(document) => document,
+        Err(_) => return Ok(contents.into()),
+    };
+    if expand_value(&mut document, directory)? {
+        Ok(serde_yaml::to_string(&document)?)
+    } else {
+        Ok(contents.into())
+    }
+}
+
+/// Call the functions in every string in a value, returning whether any
+/// were called
+fn expand_value(value: &mut serde_yaml::Value, directory: &path::Path) -> Result<bool, Error> {
+    use serde_yaml::Value;
+
+    match value {
+        Value::String(string) => {
+            let expanded = expand(string, directory)?;
+            let changed = expanded != *string;
+            *string = expanded;
+            Ok(changed)
+        }
+        Value::Sequence(items) => items.iter_mut().try_fold(false, |changed, item| {
+            Ok(expand_value(item, directory)? || changed)
+        }),
+        Value::Mapping(fields) => fields.iter_mut().try_fold(false, |changed, (_, field)| {
+            Ok(expand_value(field, directory)? || changed)
+        }),
+        _ => Ok(false),
+    }
+}
+
+/// Replace the function calls in a string with their results. Other ${...}
+/// references, such as ${FLOKI_ROOT} or a shell's ${VAR:-default}, are
+/// left as they are.
+pub(crate) fn expand(value: &str, directory: &path::Path) -> Result<String, Error> {
+    let mut expanded = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let call = function_call(reference);
+
+        match (call, closing_brace(reference)) {
+            (Some((function, _)), Some(end)) => {
+                let argument = &reference[function.len() + 1..end];
+                expanded.push_str(&call_function(function, argument, directory)?);
+                rest = &reference[end + 1..];
+            }
+            (Some(_), None) => {
+                return Err(FlokiError::UnterminatedTemplate {
+                    value: value.into(),
+                }
+                .into())
+            }
+            (None, _) => {
+                expanded.push_str("${");
+                rest = reference;
+            }
+        }
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Where the brace closing a reference (the text after its "${") is. Braces
+/// inside it are matched, so ${cmd:awk '{print $1}' file} is one call.
+fn closing_brace(reference: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in reference.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The function called by a reference (the text after its "${"), if it is
+/// a call - a lowercase name followed by a colon. Shell default and error
+/// operators, like ${var:-default}, aren't calls.
+fn function_call(reference: &str) -> Option<(&str, &str)> {
+    let (function, argument) = reference.split_once(':')?;
+    let is_name = !function.is_empty() && function.chars().all(|c| c.is_ascii_lowercase());
+    let is_shell_operator = argument.starts_with(['-', '=', '?', '+']);
+    if is_name && !is_shell_operator {
+        Some((function, argument))
+    } else {
+        None
+    }
+}
+
+fn call_function(function: &str, argument: &str, directory: &path::Path) -> Result<String, Error> {
+    match function {
+        "env" => std::env::var(argument).map_err(|_| {
+            FlokiError::TemplateVariableNotSet {
+                name: argument.into(),
+            }
+            .into()
+        }),
+        "file" => {
+            let file = directory.join(argument);
+            std::fs::read_to_string(&file)
+                .map(|contents| contents.trim().to_string())
+                .map_err(|error| {
+                    FlokiError::TemplateFileUnreadable {
+                        file: file.display().to_string(),
+                        error,
+                    }
+                    .into()
+                })
+        }
+        "cmd" => command_output(argument, directory),
+        _ => Err(FlokiError::UnknownTemplateFunction {
+            function: function.into(),
+        }
+        .into()),
+    }
+}
+
+/// Run a command with sh, returning what it printed, less any trailing
+/// newlines
+fn command_output(command: &str, directory: &path::Path) -> Result<String, Error> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(directory)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|error| FlokiError::FailedToRunCommand {
+            command: command.into(),
+            error,
+        })?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .trim_end_matches('\n')
+            .to_string())
+    } else {
+        Err(FlokiError::TemplateCommandFailed {
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: command.into(),
+                exit_status: output.status,
+            },
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn error_of(result: Result<String, Error>) -> FlokiError {
+        match result.unwrap_err().downcast::<FlokiError>() {
+            Ok(error) => error,
+            Err(error) => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_env_function() -> Result<(), Error> {
+        std::env::set_var("FLOKI_TEMPLATE_TEST", "value");
+        assert_eq!(
+            expand("a-${env:FLOKI_TEMPLATE_TEST}-b", path::Path::new("/"))?,
+            "a-value-b"
+        );
+        assert!(matches!(
+            error_of(expand("${env:FLOKI_TEMPLATE_UNSET}", path::Path::new("/"))),
+            FlokiError::TemplateVariableNotSet { name } if name == "FLOKI_TEMPLATE_UNSET"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_function() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("version"), "1.2.3\n")?;
+        assert_eq!(expand("foo:${file:version}", dir.path())?, "foo:1.2.3");
+        assert!(matches!(
+            error_of(expand("${file:missing}", dir.path())),
+            FlokiError::TemplateFileUnreadable { .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cmd_function() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("tag"), "")?;
+        assert_eq!(expand("${cmd:ls}", dir.path())?, "tag");
+        assert_eq!(expand("${cmd:echo a b}", dir.path())?, "a b");
+        std::fs::write(dir.path().join("words"), "first second\n")?;
+        assert_eq!(
+            expand("${cmd:awk '{print $1}' words}-${cmd:echo {}}", dir.path())?,
+            "first-{}"
+        );
+        assert!(matches!(
+            error_of(expand("${cmd:exit 3}", dir.path())),
+            FlokiError::TemplateCommandFailed { exit_status }
+                if exit_status.exit_status.code() == Some(3)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_function() {
+        assert!(matches!(
+            error_of(expand("${nope:foo}", path::Path::new("/"))),
+            FlokiError::UnknownTemplateFunction { function } if function == "nope"
+        ));
+        assert!(matches!(
+            error_of(expand("${env:HOME", path::Path::new("/"))),
+            FlokiError::UnterminatedTemplate { .. }
+        ));
+        assert!(matches!(
+            error_of(expand("${cmd:echo {}", path::Path::new("/"))),
+            FlokiError::UnterminatedTemplate { .. }
+        ));
+    }
+
+    #[test]
+    fn test_other_references_are_left_alone() -> Result<(), Error> {
+        for value in [
+            "${FLOKI_CONFIG_DIR}/cache:/cache",
+            "echo ${name:-default}",
+            "${HOME",
+            "$HOME",
+        ] {
+            assert_eq!(expand(value, path::Path::new("/"))?, value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_document() -> Result<(), Error> {
+        std::env::set_var("FLOKI_TEMPLATE_IMAGE", "debian:sid");
+        let document = "image: ${env:FLOKI_TEMPLATE_IMAGE}\ninit:\n  - echo ${cmd:echo hi}\n";
+        let expanded: serde_yaml::Value =
+            serde_yaml::from_str(&expand_document(document, path::Path::new("/"))?)?;
+        assert_eq!(
+            expanded,
+            serde_yaml::from_str::<serde_yaml::Value>("image: debian:sid\ninit:\n  - echo hi\n")?
+        );
+
+        // Documents without calls are untouched
+        let plain = "image: foo # a comment\n";
+        assert_eq!(expand_document(plain, path::Path::new("/"))?, plain);
+        Ok(())
+    }
+}
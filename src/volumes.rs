@@ -1,5 +1,6 @@
+use anyhow::{Context, Error};
 use std::path;
-use std::{collections::BTreeMap, os::unix::prelude::OsStrExt};
+use std::{collections::BTreeMap, fs, io, os::unix::prelude::OsStrExt};
 
 use sha2::{Digest, Sha256};
 
@@ -7,43 +8,304 @@ use crate::config::Volume;
 
 static VOLUME_DIRECTORY: &str = "volumes/";
 
+/// The host directory, mount path and mount options of each volume backed
+/// by a host directory
 pub(crate) fn resolve_volume_mounts<'a>(
     config_filepath: &path::Path,
     work_path: &path::Path,
+    volume_key: Option<&str>,
     volumes: &'a BTreeMap<String, Volume>,
-) -> Vec<(path::PathBuf, &'a path::PathBuf)> {
+) -> Vec<(path::PathBuf, &'a path::PathBuf, &'a [String])> {
     volumes
         .iter()
+        .filter(|(_, volume)| !volume.tmpfs)
         .map(|(name, volume)| {
             (
-                cache_path(work_path, config_filepath, name, volume),
+                cache_path(work_path, config_filepath, volume_key, name, volume),
                 &volume.mount,
+                volume.options.as_slice(),
             )
         })
         .collect()
 }
 
+/// Create the directories backing the volumes, seeding new volumes from
+/// their init_from directories (relative to the configuration file)
+pub(crate) fn instantiate_volumes(
+    config_filepath: &path::Path,
+    work_path: &path::Path,
+    volume_key: Option<&str>,
+    volumes: &BTreeMap<String, Volume>,
+) -> Result<(), Error> {
+    let config_dir = config_filepath
+        .parent()
+        .unwrap_or_else(|| path::Path::new("/"));
+    for (name, volume) in volumes.iter().filter(|(_, volume)| !volume.tmpfs) {
+        let seed = volume.init_from.as_ref().map(|seed| config_dir.join(seed));
+        instantiate_volume(
+            &cache_path(work_path, config_filepath, volume_key, name, volume),
+            seed.as_deref(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Create the directory backing a volume if it doesn't exist yet, copying
+/// the seed directory into it. Returns whether the volume was created -
+/// existing volumes are left as they are.
+fn instantiate_volume(path: &path::Path, seed: Option<&path::Path>) -> Result<bool, Error> {
+    if path.exists() {
+        return Ok(false);
+    }
+    match seed {
+        Some(seed) => seed_volume(path, seed),
+        None => create_volume_directory(path),
+    }
+}
+
+/// Seed a new volume in a sibling directory, then move it into place, so
+/// the volume never appears half seeded, returning whether this call
+/// created it. If the copy fails, there's still no volume, and the next run
+/// tries again. Another floki seeding the same shared volume at the same
+/// time isn't an error - whichever moves its copy into place first wins,
+/// and the volume just isn't new for the other.
+fn seed_volume(path: &path::Path, seed: &path::Path) -> Result<bool, Error> {
+    debug!("Seeding volume {} from {}", path.display(), seed.display());
+    let partial = partial_path(path);
+    let seeded = fs::create_dir_all(&partial)
+        .map_err(Error::from)
+        .and_then(|()| copy_directory(seed, &partial))
+        .with_context(|| format!("Couldn't seed volume from {}", seed.display()))
+        .and_then(|()| {
+            // Renaming a directory fails if a seeded volume is already there
+            match fs::rename(&partial, path) {
+                Err(_) if path.is_dir() => Ok(false),
+                renamed => renamed
+                    .map(|()| true)
+                    .with_context(|| format!("Couldn't create volume {}", path.display())),
+            }
+        });
+    if !matches!(seeded, Ok(true)) {
+        // The partial copy is of no use to anyone
+        let _ = fs::remove_dir_all(&partial);
+    }
+    seeded
+}
+
+/// Where a volume is seeded before it's moved into place - hidden, so it's
+/// not taken for a volume itself
+fn partial_path(path: &path::Path) -> path::PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.partial", name, uuid::Uuid::new_v4()))
+}
+
+/// Create the directory backing a volume, returning whether this call
+/// created it. Another floki creating the same shared volume at the same
+/// time isn't an error - the volume just isn't new here. Other failures are
+/// retried once, as they can be transient (e.g. the volumes directory being
+/// pruned meanwhile).
+fn create_volume_directory(path: &path::Path) -> Result<bool, Error> {
+    let create = || -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::create_dir(path)
+    };
+    let result = match create() {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+            debug!("Retrying creating volume {}: {}", path.display(), e);
+            create()
+        }
+        result => result,
+    };
+    created_or_existing(result)
+        .with_context(|| format!("Couldn't create volume {}", path.display()))
+}
+
+/// Whether creating a directory created it, counting it already existing
+/// as success
+fn created_or_existing(result: io::Result<()>) -> io::Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Left in a volume once its setup_once commands have succeeded
+const SETUP_MARKER: &str = ".floki-setup-done";
+
+/// The setup_once commands of the volumes which haven't been set up yet.
+/// Each volume's commands are followed by leaving the setup marker in it,
+/// which only happens if they all succeed, as init commands stop at the
+/// first failure.
+pub(crate) fn setup_commands(
+    config_filepath: &path::Path,
+    work_path: &path::Path,
+    volume_key: Option<&str>,
+    volumes: &BTreeMap<String, Volume>,
+) -> Vec<String> {
+    let mut commands = Vec::new();
+    for (name, volume) in volumes.iter() {
+        if volume.tmpfs || volume.setup_once.is_empty() {
+            continue;
+        }
+        let path = cache_path(work_path, config_filepath, volume_key, name, volume);
+        if path.join(SETUP_MARKER).exists() {
+            continue;
+        }
+        commands.extend(volume.setup_once.iter().cloned());
+        let marker = volume.mount.join(SETUP_MARKER);
+        commands.push(format!("touch {}", shlex::quote(&marker.to_string_lossy())));
+    }
+    commands
+}
+
+/// Recursively copy the contents of one directory into another
+fn copy_directory(from: &path::Path, to: &path::Path) -> Result<(), Error> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            fs::create_dir_all(&target)?;
+            copy_directory(&entry.path(), &target)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// The mount points of the tmpfs backed volumes
+pub(crate) fn resolve_tmpfs_mounts(volumes: &BTreeMap<String, Volume>) -> Vec<&path::PathBuf> {
+    volumes
+        .values()
+        .filter(|volume| volume.tmpfs)
+        .map(|volume| &volume.mount)
+        .collect()
+}
+
+/// A directory backing a floki volume
+#[derive(Debug, PartialEq)]
+pub(crate) struct FlokiVolume {
+    pub(crate) name: String,
+    pub(crate) path: path::PathBuf,
+    /// Whether the volume is shared between all configurations
+    pub(crate) shared: bool,
+    /// Total size of the files in the volume, in bytes
+    pub(crate) size: u64,
+}
+
+/// List the volumes floki has created
+pub(crate) fn list_volumes(work_path: &path::Path) -> Result<Vec<FlokiVolume>, Error> {
+    let directory = work_path.join(VOLUME_DIRECTORY);
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut volumes = Vec::new();
+    for entry in fs::read_dir(&directory)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        // Hidden directories are volumes still being seeded
+        if !path.is_dir() || name.starts_with('.') {
+            continue;
+        }
+        volumes.push(FlokiVolume {
+            shared: is_shared_volume(&name),
+            size: directory_size(&path)?,
+            name,
+            path,
+        });
+    }
+    volumes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(volumes)
+}
+
+/// Remove the volumes which aren't shared between all configurations,
+/// returning those removed. These are recreated empty when next used.
+pub(crate) fn prune_volumes(work_path: &path::Path) -> Result<Vec<FlokiVolume>, Error> {
+    let mut pruned = Vec::new();
+    for volume in list_volumes(work_path)? {
+        if !volume.shared {
+            fs::remove_dir_all(&volume.path)?;
+            pruned.push(volume);
+        }
+    }
+    Ok(pruned)
+}
+
+/// Local volumes have their names prefixed by a hash of their config file
+/// path or volume key. Shared volumes, including those with a share scope,
+/// don't start with a hash.
+fn is_shared_volume(name: &str) -> bool {
+    match name.split_once('-') {
+        Some((prefix, _)) => !(prefix.len() == 64 && prefix.chars().all(|c| c.is_ascii_hexdigit())),
+        None => true,
+    }
+}
+
+fn directory_size(directory: &path::Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// The host directory backing a volume. Volumes which aren't shared are
+/// named for the configuration file, or the volume key if one is set.
 fn cache_path(
     work_path: &path::Path,
     config_filepath: &path::Path,
+    volume_key: Option<&str>,
     name: &str,
     config: &Volume,
 ) -> path::PathBuf {
-    let folder = prefix_cache(config.shared, config_filepath) + name;
+    let folder = prefix_cache(config, config_filepath, volume_key) + name;
     work_path.join(VOLUME_DIRECTORY).join::<String>(folder)
 }
 
-fn prefix_cache(shared: bool, config_filepath: &path::Path) -> String {
-    if shared {
-        "".into()
-    } else {
-        hash_path(config_filepath) + "-"
+fn prefix_cache(config: &Volume, config_filepath: &path::Path, volume_key: Option<&str>) -> String {
+    match (config.shared, &config.share_scope, volume_key) {
+        (true, None, _) => "".into(),
+        // Marked as shared, so they're not taken for local volumes
+        (true, Some(scope), _) => format!("scoped-{}-", hash_scope(scope)),
+        (false, _, Some(key)) => hash_key(key) + "-",
+        (false, _, None) => hash_path(config_filepath) + "-",
     }
 }
 
 fn hash_path(path: &path::Path) -> String {
+    hash_bytes(path.as_os_str().as_bytes())
+}
+
+fn hash_key(key: &str) -> String {
+    // Namespace the key so it can never hash the same as a config path
+    hash_bytes(format!("volume_key:{}", key).as_bytes())
+}
+
+fn hash_scope(scope: &str) -> String {
+    // Namespace the scope so it can never hash the same as a config path
+    hash_bytes(format!("share_scope:{}", scope).as_bytes())
+}
+
+fn hash_bytes(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
-    hasher.update(path.as_os_str().as_bytes());
+    hasher.update(bytes);
     format!("{:x}", hasher.finalize())
 }
 
@@ -52,24 +314,198 @@ mod test {
     use super::*;
     use std::path::Path;
 
+    #[test]
+    fn test_new_volume_is_seeded() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let seed = dir.path().join("seed");
+        fs::create_dir_all(seed.join("nested"))?;
+        fs::write(seed.join("nested/file"), "contents")?;
+
+        let volume = dir.path().join("volumes/cache");
+        assert!(instantiate_volume(&volume, Some(&seed))?);
+        assert_eq!(fs::read_to_string(volume.join("nested/file"))?, "contents");
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_seed_leaves_no_volume() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let volume = dir.path().join("volumes/cache");
+        assert!(instantiate_volume(&volume, Some(&dir.path().join("missing"))).is_err());
+        assert!(!volume.exists());
+        // Nor anything left over from the attempt
+        assert_eq!(fs::read_dir(dir.path().join("volumes"))?.count(), 0);
+
+        // So the next run seeds it
+        let seed = dir.path().join("seed");
+        fs::create_dir_all(&seed)?;
+        fs::write(seed.join("file"), "seed")?;
+        assert!(instantiate_volume(&volume, Some(&seed))?);
+        assert_eq!(fs::read_to_string(volume.join("file"))?, "seed");
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_losing_race_keeps_existing_volume() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let seed = dir.path().join("seed");
+        fs::create_dir_all(&seed)?;
+        fs::write(seed.join("file"), "seed")?;
+
+        // Another floki moved its seeded copy into place first
+        let volume = dir.path().join("volumes/cache");
+        fs::create_dir_all(&volume)?;
+        fs::write(volume.join("file"), "theirs")?;
+
+        assert!(!seed_volume(&volume, &seed)?);
+        assert_eq!(fs::read_to_string(volume.join("file"))?, "theirs");
+        assert_eq!(fs::read_dir(dir.path().join("volumes"))?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_setup_once_runs_until_done() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let config = dir.path().join("floki.yaml");
+        let volumes: BTreeMap<String, Volume> = maplit::btreemap! {
+            "cache".into() => Volume {
+                mount: "/cache".into(),
+                setup_once: vec!["cargo fetch".into()],
+                ..Default::default()
+            },
+            "other".into() => Volume {
+                mount: "/other".into(),
+                ..Default::default()
+            },
+        };
+
+        instantiate_volumes(&config, dir.path(), None, &volumes)?;
+        let expected = vec!["cargo fetch", "touch /cache/.floki-setup-done"];
+        assert_eq!(
+            setup_commands(&config, dir.path(), None, &volumes),
+            expected
+        );
+
+        // Setup which didn't finish, e.g. as a command failed, runs again
+        instantiate_volumes(&config, dir.path(), None, &volumes)?;
+        assert_eq!(
+            setup_commands(&config, dir.path(), None, &volumes),
+            expected
+        );
+
+        // Once the container has left the marker, there is nothing to do
+        let cache = cache_path(dir.path(), &config, None, "cache", &volumes["cache"]);
+        fs::write(cache.join(SETUP_MARKER), "")?;
+        assert!(setup_commands(&config, dir.path(), None, &volumes).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_volume_already_existing_is_success() -> Result<(), Error> {
+        assert!(created_or_existing(Ok(()))?);
+        assert!(!created_or_existing(Err(
+            io::ErrorKind::AlreadyExists.into()
+        ))?);
+        assert!(created_or_existing(Err(io::ErrorKind::PermissionDenied.into())).is_err());
+
+        // A volume created by someone else in the meantime isn't new
+        let dir = tempfile::tempdir()?;
+        let volume = dir.path().join("volumes/cache");
+        assert!(create_volume_directory(&volume)?);
+        assert!(!create_volume_directory(&volume)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_volume_is_not_seeded() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let seed = dir.path().join("seed");
+        fs::create_dir_all(&seed)?;
+        fs::write(seed.join("file"), "seed")?;
+
+        let volume = dir.path().join("volumes/cache");
+        fs::create_dir_all(&volume)?;
+        fs::write(volume.join("file"), "changed in the container")?;
+
+        assert!(!instantiate_volume(&volume, Some(&seed))?);
+        assert_eq!(
+            fs::read_to_string(volume.join("file"))?,
+            "changed in the container"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_volume_key_is_stable_across_paths() {
+        let volume = Volume {
+            mount: "/cache".into(),
+            ..Default::default()
+        };
+        let cache = |config: &str, key: Option<&str>| {
+            cache_path(
+                Path::new("work_path"),
+                Path::new(config),
+                key,
+                "cache",
+                &volume,
+            )
+        };
+
+        let checkout_1 = cache("/home/me/project/floki.yaml", Some("project"));
+        let checkout_2 = cache("/builds/ci/project/floki.yaml", Some("project"));
+        assert_eq!(checkout_1, checkout_2);
+
+        assert_ne!(
+            checkout_1,
+            cache("/home/me/project/floki.yaml", Some("other"))
+        );
+        assert_ne!(checkout_1, cache("/home/me/project/floki.yaml", None));
+    }
+
+    #[test]
+    fn test_volume_key_does_not_affect_shared_volumes() {
+        let volume = Volume {
+            shared: true,
+            mount: "/cache".into(),
+            ..Default::default()
+        };
+        let cache = |config: &str, key: Option<&str>| {
+            cache_path(
+                Path::new("work_path"),
+                Path::new(config),
+                key,
+                "cache",
+                &volume,
+            )
+        };
+        assert_eq!(
+            cache("/a/floki.yaml", Some("project")),
+            cache("/b/floki.yaml", None)
+        );
+    }
+
     #[test]
     fn test_shared_cache_path_is_shared_across_flokis() {
         let cache_1 = cache_path(
             Path::new("work_path"),
             Path::new("/floki/root/1/floki.yaml"),
+            None,
             "cache",
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
         let cache_2 = cache_path(
             Path::new("work_path"),
             Path::new("/floki/root/2/floki.yaml"),
+            None,
             "cache",
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
 
@@ -81,19 +517,23 @@ mod test {
         let cache_1 = cache_path(
             Path::new("work_path"),
             Path::new("/floki/root/1/floki.yaml"),
+            None,
             "cache",
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
         let cache_2 = cache_path(
             Path::new("work_path"),
             Path::new("/floki/root/2/floki.yaml"),
+            None,
             "cache",
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
 
@@ -105,19 +545,23 @@ mod test {
         let cache_shared = cache_path(
             Path::new("work_path"),
             Path::new("/floki/root/1/floki.yaml"),
+            None,
             "cache",
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
         let cache_local = cache_path(
             Path::new("work_path"),
             Path::new("/floki/root/1/floki.yaml"),
+            None,
             "cache",
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
 
@@ -129,25 +573,182 @@ mod test {
         let cache_shared = cache_path(
             Path::new("work_path"),
             Path::new("/floki/root/1/floki-alternate.yaml"),
+            None,
             "cache",
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
         let cache_local = cache_path(
             Path::new("work_path"),
             Path::new("/floki/root/1/floki.yaml"),
+            None,
             "cache",
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                ..Default::default()
             },
         );
 
         assert_ne!(cache_shared, cache_local);
     }
 
+    #[test]
+    fn test_is_shared_volume() {
+        let local = cache_path(
+            Path::new("work_path"),
+            Path::new("/floki/root/1/floki.yaml"),
+            None,
+            "cargo-cache",
+            &Volume {
+                mount: "/".into(),
+                ..Default::default()
+            },
+        );
+        let local_name = local.file_name().unwrap().to_str().unwrap();
+        let scoped = cache_path(
+            Path::new("work_path"),
+            Path::new("/floki/root/1/floki.yaml"),
+            None,
+            "cargo-cache",
+            &Volume {
+                shared: true,
+                share_scope: Some("team".into()),
+                mount: "/".into(),
+                ..Default::default()
+            },
+        );
+        let scoped_name = scoped.file_name().unwrap().to_str().unwrap();
+
+        assert!(!is_shared_volume(local_name));
+        assert!(is_shared_volume(scoped_name));
+        assert!(is_shared_volume("cargo-cache"));
+        assert!(is_shared_volume("registry"));
+    }
+
+    #[test]
+    fn test_list_and_prune_volumes() -> Result<(), Error> {
+        let work_path = tempfile::tempdir()?;
+        let volumes = work_path.path().join(VOLUME_DIRECTORY);
+        let local_name = format!("{}-cache", hash_path(Path::new("/floki.yaml")));
+        fs::create_dir_all(volumes.join(&local_name))?;
+        fs::create_dir_all(volumes.join("registry"))?;
+        fs::write(volumes.join("registry/index"), "12345")?;
+
+        let listed = list_volumes(work_path.path())?;
+        assert_eq!(
+            listed
+                .iter()
+                .map(|v| (v.name.as_str(), v.shared, v.size))
+                .collect::<Vec<_>>(),
+            vec![(local_name.as_str(), false, 0), ("registry", true, 5)]
+        );
+
+        let pruned = prune_volumes(work_path.path())?;
+        assert_eq!(pruned.len(), 1);
+        assert!(!volumes.join(&local_name).exists());
+        assert!(volumes.join("registry").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scoped_cache_path_is_shared_within_scope() {
+        let volume = Volume {
+            shared: true,
+            share_scope: Some("team".into()),
+            mount: "/".into(),
+            ..Default::default()
+        };
+        let cache_1 = cache_path(
+            Path::new("work_path"),
+            Path::new("/floki/root/1/floki.yaml"),
+            None,
+            "cache",
+            &volume,
+        );
+        let cache_2 = cache_path(
+            Path::new("work_path"),
+            Path::new("/floki/root/2/floki.yaml"),
+            None,
+            "cache",
+            &volume,
+        );
+
+        assert_eq!(cache_1, cache_2);
+    }
+
+    #[test]
+    fn test_scoped_and_unscoped_caches_dont_collide() {
+        let cache_scoped = cache_path(
+            Path::new("work_path"),
+            Path::new("/floki/root/1/floki.yaml"),
+            None,
+            "cache",
+            &Volume {
+                shared: true,
+                share_scope: Some("team".into()),
+                mount: "/".into(),
+                ..Default::default()
+            },
+        );
+        let cache_other_scope = cache_path(
+            Path::new("work_path"),
+            Path::new("/floki/root/1/floki.yaml"),
+            None,
+            "cache",
+            &Volume {
+                shared: true,
+                share_scope: Some("other-team".into()),
+                mount: "/".into(),
+                ..Default::default()
+            },
+        );
+        let cache_unscoped = cache_path(
+            Path::new("work_path"),
+            Path::new("/floki/root/1/floki.yaml"),
+            None,
+            "cache",
+            &Volume {
+                shared: true,
+                share_scope: None,
+                mount: "/".into(),
+                ..Default::default()
+            },
+        );
+
+        assert_ne!(cache_scoped, cache_unscoped);
+        assert_ne!(cache_scoped, cache_other_scope);
+    }
+
+    #[test]
+    fn test_tmpfs_volumes_have_no_host_directory() {
+        let volumes: BTreeMap<String, Volume> = maplit::btreemap! {
+            "cache".into() => Volume {
+                mount: "/cache".into(),
+                ..Default::default()
+            },
+            "scratch".into() => Volume {
+                tmpfs: true,
+                mount: "/scratch".into(),
+                ..Default::default()
+            },
+        };
+
+        let mounts = resolve_volume_mounts(
+            Path::new("/floki/root/1/floki.yaml"),
+            Path::new("work_path"),
+            None,
+            &volumes,
+        );
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].1, Path::new("/cache"));
+
+        assert_eq!(resolve_tmpfs_mounts(&volumes), vec![Path::new("/scratch")]);
+    }
+
     #[test]
     fn test_path_sha() {
         let path = Path::new("/floki/root/1/floki.yaml");
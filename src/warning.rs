@@ -0,0 +1,63 @@
+/// Problems with the configuration which don't stop floki from running
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Warning {
+    /// Builds with a remote context can't be hashed, so are always run
+    CacheBuildWithRemoteContext,
+    /// Only images which come from a registry are pulled
+    PullPolicyNotPulled,
+    /// The deprecated -l/--local flag was given
+    LocalFlagDeprecated,
+    /// A volume option which docker may not understand, passed anyway
+    UnknownVolumeOption { volume: String, option: String },
+    /// Isolation modes only apply to Windows containers
+    IsolationIgnored { os: String },
+    /// Git build args can only be found for builds in a git repository
+    NotAGitRepository { directory: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::CacheBuildWithRemoteContext => {
+                write!(
+                    f,
+                    "cache_build has no effect for builds with a remote context"
+                )
+            }
+            Warning::PullPolicyNotPulled => {
+                write!(
+                    f,
+                    "pull_policy has no effect for images which aren't pulled"
+                )
+            }
+            Warning::LocalFlagDeprecated => write!(
+                f,
+                "-l/--local is deprecated and may be removed in a future release"
+            ),
+            Warning::UnknownVolumeOption { volume, option } => write!(
+                f,
+                "volume '{}' has unrecognised option '{}', which is passed to docker as is",
+                volume, option
+            ),
+            Warning::IsolationIgnored { os } => write!(
+                f,
+                "isolation only applies to Windows containers, so is ignored on {}",
+                os
+            ),
+            Warning::NotAGitRepository { directory } => write!(
+                f,
+                "{} isn't in a git repository, so the build has no GIT_COMMIT or GIT_BRANCH build args",
+                directory
+            ),
+        }
+    }
+}
+
+/// Log the warnings
+pub(crate) fn render(warnings: &[Warning]) {
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+}